@@ -0,0 +1,74 @@
+use crate::frame::{self, Role};
+use crate::{Error, WebsocketServer};
+use embedded_websocket::WebSocketSendMessageType;
+use pipebuf::{PBufRdWr, PBufWr};
+
+/// Websocket message encoded once for sending to many connections
+///
+/// Server frames are not masked, so the encoded bytes of a message
+/// are identical for every connection.  This allows a chat or pubsub
+/// server to encode the frame just once, and then copy the same bytes
+/// into the outgoing pipe-buffer of each connection.
+pub struct Broadcaster {
+    frame: Vec<u8>,
+}
+
+impl Broadcaster {
+    /// Encode an unfragmented websocket text message
+    pub fn text(data: &str) -> Self {
+        Self::encode(WebSocketSendMessageType::Text, data.as_bytes())
+    }
+
+    /// Encode an unfragmented websocket binary message
+    pub fn binary(data: &[u8]) -> Self {
+        Self::encode(WebSocketSendMessageType::Binary, data)
+    }
+
+    /// Encode an unfragmented text or binary message, or a `Ping`.
+    /// Other message types fail with `Error::InvalidOpCode`, since a
+    /// `Pong` only makes sense as a reply, and a `Close` must go
+    /// through [`WebsocketServer::close`] on each connection so that
+    /// it stops sending afterwards.  A `Ping` with more than 125
+    /// bytes of data fails with `Error::InvalidFrameLength`.
+    pub fn new(msg: WebSocketSendMessageType, data: &[u8]) -> Result<Self, Error> {
+        match msg {
+            WebSocketSendMessageType::Text | WebSocketSendMessageType::Binary => (),
+            WebSocketSendMessageType::Ping if data.len() <= 125 => (),
+            WebSocketSendMessageType::Ping => return Err(Error::InvalidFrameLength),
+            _ => return Err(Error::InvalidOpCode),
+        }
+        Ok(Self::encode(msg, data))
+    }
+
+    fn encode(msg: WebSocketSendMessageType, data: &[u8]) -> Self {
+        let role = Role::Server;
+        let mut frame = vec![0; role.frame_reserve(data.len())];
+        let used = role
+            .write_frame(true, frame::opcode(msg, false), data, [0; 4], &mut frame)
+            .expect("Buffer should always be big enough");
+        frame.truncate(used);
+        Self { frame }
+    }
+
+    /// Get the encoded frame
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Send the encoded message on the given connection.  As for
+    /// [`WebsocketServer::send`], this fails with
+    /// `Error::WebSocketNotOpen` once the connection is closing.  A
+    /// text or binary message fails with `Error::InvalidOpCode` if
+    /// the connection is part-way through sending a fragmented
+    /// message, since it would corrupt that message.  Nothing is
+    /// written in that case, so it may be sent again once the
+    /// fragmented message is complete.
+    pub fn send(&self, ws: &mut WebsocketServer, pb: PBufRdWr) -> Result<(), Error> {
+        ws.send_frame(pb, &self.frame)
+    }
+
+    /// Write the encoded message without indicating a push
+    pub(crate) fn write(&self, ws: &mut WebsocketServer, wr: PBufWr) -> Result<(), Error> {
+        ws.write_frame(wr, &self.frame)
+    }
+}
@@ -0,0 +1,635 @@
+use crate::handshake::is_token;
+use crate::{ConnectionState, Error, WebsocketServer, WsClock, WsExtension};
+use embedded_websocket as ws;
+use httparse::Status;
+use pipebuf::{PBufRd, PBufRdWr, PBufWr};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{Duration, SystemTime};
+use ws::{WebSocketCloseStatusCode, WebSocketSendMessageType};
+
+/// Maximum amount of the body of a rejection to keep
+const MAX_REJECTED_BODY: usize = 4096;
+
+/// Details for the upgrade request sent by a websocket client
+///
+/// See [`write_client_handshake`].  Create with
+/// [`ClientConfig::new`], and then set any other fields required.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientConfig {
+    /// Value for the `Host` header, e.g. `example.com` or
+    /// `example.com:8080`
+    pub host: String,
+    /// Request path, e.g. `/chat?room=1`.  An empty path is sent as
+    /// `/`.
+    pub path: String,
+    /// Value for the `Origin` header, if any, e.g.
+    /// `https://example.com`.  Browsers always send this, and some
+    /// servers require it.
+    pub origin: Option<String>,
+    /// Subprotocols to offer in `Sec-WebSocket-Protocol`, in order
+    /// of preference.  The header is left out if this is empty.
+    pub subprotocols: Vec<String>,
+    /// Extensions to offer in `Sec-WebSocket-Extensions`, in order of
+    /// preference, each with its parameters, e.g.
+    /// `permessage-deflate; client_max_window_bits`.  The header is
+    /// left out if this is empty.  Note that this crate doesn't
+    /// implement any extensions, so the caller must handle whatever
+    /// the server accepts, by creating a [`WsExtension`] from the
+    /// accepted parameters and passing it to
+    /// [`WebsocketClient::set_extension`].
+    pub extensions: Vec<String>,
+    /// Additional headers to send, e.g. `Authorization`, as name and
+    /// value
+    pub extra_headers: Vec<(String, String)>,
+    /// Cookies to send in a `Cookie` header, as name and value, e.g.
+    /// those set by the server on a previous connection, so that a
+    /// load balancer routes a reconnection to the same backend.  See
+    /// [`read_client_handshake_cookies`].
+    pub cookies: Vec<(String, String)>,
+}
+
+impl ClientConfig {
+    /// Create a configuration with the given host and path, and no
+    /// origin, subprotocols, extensions, additional headers or
+    /// cookies
+    pub fn new(host: &str, path: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            path: path.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Add a cookie to send, replacing any existing cookie with the
+    /// same name
+    pub fn set_cookie(&mut self, name: &str, value: &str) {
+        match self.cookies.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.cookies.push((name.to_string(), value.to_string())),
+        }
+    }
+}
+
+/// `Sec-WebSocket-Key` sent in a client's upgrade request
+///
+/// This must be kept until the server's response arrives, to check
+/// the `Sec-WebSocket-Accept` header of the response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandshakeKey(String);
+
+impl HandshakeKey {
+    /// Get the key as sent in the request
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Get the `Sec-WebSocket-Accept` value which the server must
+    /// send back for this key
+    pub fn expected_accept(&self) -> String {
+        // `embedded_websocket` doesn't expose its SHA-1 or accept
+        // calculation, so build a server response and take the value
+        // from that.  The response always ends with the 28-byte
+        // accept value followed by "\r\n\r\n".
+        let mut buf = [0; 256];
+        let mut server = ws::WebSocketServer::new_server();
+        let key = ws::WebSocketKey::from(self.as_str());
+        let len = server
+            .server_accept(&key, None, &mut buf)
+            .expect("Response always fits in buffer");
+        String::from_utf8_lossy(&buf[len - 32..len - 4]).into_owned()
+    }
+
+    /// Check a `Sec-WebSocket-Accept` value from the server's
+    /// response against this key
+    pub fn check_accept(&self, accept: &[u8]) -> bool {
+        accept.trim_ascii() == self.expected_accept().as_bytes()
+    }
+}
+
+/// Write the HTTP upgrade request for a websocket client to `wr`,
+/// with a push, and return the key which was sent.  This is
+/// independent of any client connection state, so may be used for
+/// unusual setups, e.g. where the handshake happens over a different
+/// transport to the frames.  The key is generated from the random
+/// keys of the standard library's `HashMap` hasher.  To use some
+/// other source of randomness, see
+/// [`write_client_handshake_entropy`].
+///
+/// The configuration is checked first, so that values from an
+/// untrusted source can't end a header early to add others of their
+/// own, or split the request.  If the host, path, origin, extensions
+/// or an additional header value contain CR, LF or NUL, or the path
+/// contains other control characters or spaces, or a subprotocol or
+/// additional header name is not an HTTP token, then
+/// `Error::InvalidHeader` is returned and nothing is written.  The
+/// same applies to a cookie whose name is not a token, or whose value
+/// is not made of the characters allowed by RFC 6265, which excludes
+/// `;`, `,`, spaces and quotes, amongst others.
+pub fn write_client_handshake(wr: PBufWr, config: &ClientConfig) -> Result<HandshakeKey, Error> {
+    write_client_handshake_entropy(wr, config, |bytes| *bytes = default_entropy())
+}
+
+/// Write the HTTP upgrade request for a websocket client, as for
+/// [`write_client_handshake`], but taking the 16 random bytes of the
+/// key from `entropy`, which must fill the array it is passed.  This
+/// allows a hardware RNG or the application's own generator to be
+/// used.  RFC 6455 requires the key to be randomly selected for each
+/// connection.
+pub fn write_client_handshake_entropy(
+    mut wr: PBufWr,
+    config: &ClientConfig,
+    mut entropy: impl FnMut(&mut [u8; 16]),
+) -> Result<HandshakeKey, Error> {
+    check_config(config)?;
+    let mut bytes = [0; 16];
+    entropy(&mut bytes);
+    let key = HandshakeKey(encode_key(&bytes));
+    let path = match config.path.as_str() {
+        "" => "/",
+        path => path,
+    };
+    let mut request = format!(
+        "GET {path} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n",
+        config.host,
+        key.as_str()
+    );
+    if let Some(origin) = &config.origin {
+        request.push_str(&format!("Origin: {origin}\r\n"));
+    }
+    if !config.subprotocols.is_empty() {
+        let list = config.subprotocols.join(", ");
+        request.push_str(&format!("Sec-WebSocket-Protocol: {list}\r\n"));
+    }
+    if !config.extensions.is_empty() {
+        let list = config.extensions.join(", ");
+        request.push_str(&format!("Sec-WebSocket-Extensions: {list}\r\n"));
+    }
+    for (name, value) in &config.extra_headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !config.cookies.is_empty() {
+        let list: Vec<String> = config
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        request.push_str(&format!("Cookie: {}\r\n", list.join("; ")));
+    }
+    request.push_str("\r\n");
+    wr.append(request.as_bytes());
+    wr.push();
+    Ok(key)
+}
+
+/// Test whether a cookie may be sent as it is, with a name which is
+/// an HTTP token and a value made of cookie-octets, optionally in
+/// double quotes, as required by RFC 6265 section 4.1.1
+fn cookie_ok(name: &str, value: &str) -> bool {
+    let octet_ok =
+        |b: u8| matches!(b, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E);
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    !name.is_empty() && name.bytes().all(is_token) && value.bytes().all(octet_ok)
+}
+
+/// Check that the values in `config` may be written into the request
+/// as they are
+fn check_config(config: &ClientConfig) -> Result<(), Error> {
+    let text_ok = |v: &str| !v.bytes().any(|b| matches!(b, b'\r' | b'\n' | 0));
+    let token_ok = |v: &str| !v.is_empty() && v.bytes().all(is_token);
+    let path_ok = config
+        .path
+        .bytes()
+        .all(|b| !b.is_ascii_control() && b != b' ');
+    let ok = path_ok
+        && text_ok(&config.host)
+        && config.origin.as_deref().is_none_or(text_ok)
+        && config.subprotocols.iter().all(|p| token_ok(p))
+        && config.extensions.iter().all(|e| text_ok(e))
+        && config
+            .extra_headers
+            .iter()
+            .all(|(name, value)| token_ok(name) && text_ok(value))
+        && config
+            .cookies
+            .iter()
+            .all(|(name, value)| cookie_ok(name, value));
+    if ok {
+        Ok(())
+    } else {
+        Err(Error::InvalidHeader)
+    }
+}
+
+/// Details of the server's acceptance of a client's upgrade request
+///
+/// See [`read_client_handshake`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UpgradeResponse {
+    /// Subprotocol selected by the server, which is always one of
+    /// those offered, or `None` if the server didn't select one
+    pub subprotocol: Option<String>,
+    /// Extensions accepted by the server, in the order given, each
+    /// with the parameters the server chose.  Each is always one of
+    /// those offered.
+    pub extensions: Vec<String>,
+}
+
+/// Failure of a client's websocket handshake
+#[derive(Debug, PartialEq)]
+pub enum ClientHandshakeError {
+    /// The server answered with an HTTP status other than 101, e.g.
+    /// 401 if authentication is required, or 503 if overloaded
+    Rejected(RejectedResponse),
+    /// Invalid HTTP response, or a 101 response which is not a valid
+    /// websocket upgrade
+    Ws(Error),
+}
+
+impl From<Error> for ClientHandshakeError {
+    fn from(e: Error) -> Self {
+        Self::Ws(e)
+    }
+}
+
+/// HTTP response from a server which refused a websocket upgrade
+///
+/// See [`ClientHandshakeError::Rejected`].  This allows the client
+/// to react appropriately, e.g. to authenticate again after a 401,
+/// or to back off after a 503.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectedResponse {
+    /// HTTP status code
+    pub status: u16,
+    /// Reason phrase from the status line
+    pub reason: String,
+    /// Headers in order, as name and value
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Start of the body, up to 4096 bytes.  This is taken from
+    /// `Content-Length` if present, or else is the data up to EOF.
+    /// A body with `Transfer-Encoding` is not read.
+    pub body: Vec<u8>,
+}
+
+impl RejectedResponse {
+    /// Get the value of a header, matching the name without regard
+    /// to case.  If it was repeated, the first value is returned.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Get the delay requested by a `Retry-After` header given in
+    /// seconds.  Returns `None` if there is no such header, or if it
+    /// gives an HTTP date instead.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let value = std::str::from_utf8(self.header("Retry-After")?).ok()?;
+        Some(Duration::from_secs(value.trim().parse().ok()?))
+    }
+}
+
+/// Read the server's response to the upgrade request written by
+/// [`write_client_handshake`], once it has arrived in `rd`.
+/// `config` and `key` must be those used for the request.
+///
+/// Returns `Ok(None)` if the response is not yet complete, in which
+/// case it should be called again when more data arrives.  Once the
+/// response has been accepted, returns `Ok(Some(response))`, having
+/// consumed the HTTP response.  Any data after that is the start of
+/// the websocket frames, and is left in `rd`.
+///
+/// If the status is not 101, returns
+/// [`ClientHandshakeError::Rejected`] with the details of the
+/// response, once the part of the body to be kept has arrived.
+/// Otherwise errors are returned as `ClientHandshakeError::Ws(_)`:
+/// `Error::AcceptStringInvalid` if the `Sec-WebSocket-Accept`
+/// doesn't match the key, `Error::HttpHeader(_)` for invalid HTTP,
+/// `Error::SubprotocolInvalid` or `Error::ExtensionInvalid` if the
+/// server selected a subprotocol or accepted an extension which
+/// wasn't offered, or `Error::UpgradeInvalid` if the response is not
+/// otherwise a valid upgrade.  In all these cases the connection
+/// should be closed.
+pub fn read_client_handshake(
+    rd: PBufRd,
+    config: &ClientConfig,
+    key: &HandshakeKey,
+) -> Result<Option<UpgradeResponse>, ClientHandshakeError> {
+    read_client_handshake_scan(rd, config, key, |_, _| ())
+}
+
+/// Read the server's response to the upgrade request, as for
+/// [`read_client_handshake`], but also passing the name and value of
+/// each cookie set by the server with `Set-Cookie` to `cookie_cb`.
+/// The cookie attributes, such as `Path` and `Max-Age`, are not
+/// interpreted.  Cookies which couldn't be sent back, because their
+/// names or values have characters not allowed by RFC 6265, are
+/// skipped.  To send the cookies on a later reconnection, pass them
+/// to [`ClientConfig::set_cookie`].  `cookie_cb` is only called once
+/// the whole response has arrived and been accepted.
+pub fn read_client_handshake_cookies(
+    rd: PBufRd,
+    config: &ClientConfig,
+    key: &HandshakeKey,
+    mut cookie_cb: impl FnMut(&str, &str),
+) -> Result<Option<UpgradeResponse>, ClientHandshakeError> {
+    read_client_handshake_scan(rd, config, key, |name, value| {
+        if !name.eq_ignore_ascii_case("Set-Cookie") {
+            return;
+        }
+        let Ok(value) = std::str::from_utf8(value) else {
+            return;
+        };
+        let pair = value.split(';').next().unwrap_or("");
+        if let Some((name, value)) = pair.split_once('=') {
+            let (name, value) = (name.trim(), value.trim());
+            if cookie_ok(name, value) {
+                cookie_cb(name, value);
+            }
+        }
+    })
+}
+
+/// Read the server's response to the upgrade request, as for
+/// [`read_client_handshake`], but also passing each header of an
+/// accepted response to `header_cb`, e.g. to pick up cookies or
+/// routing information sent by the server.  `header_cb` is only
+/// called once the whole response has arrived and been accepted.
+pub fn read_client_handshake_scan(
+    mut rd: PBufRd,
+    config: &ClientConfig,
+    key: &HandshakeKey,
+    mut header_cb: impl FnMut(&str, &[u8]),
+) -> Result<Option<UpgradeResponse>, ClientHandshakeError> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut response = httparse::Response::new(&mut headers);
+    let count = match response.parse(rd.data()).map_err(Error::from)? {
+        Status::Partial => return Ok(None),
+        Status::Complete(count) => count,
+    };
+    let status = response.code.unwrap_or(0);
+    if status != 101 {
+        let mut rejected = RejectedResponse {
+            status,
+            reason: response.reason.unwrap_or("").to_string(),
+            headers: response
+                .headers
+                .iter()
+                .map(|h| (h.name.to_string(), h.value.to_vec()))
+                .collect(),
+            body: Vec::new(),
+        };
+        let avail = &rd.data()[count..];
+        let eof = rd.has_pending_eof();
+        if rejected.header("Transfer-Encoding").is_none() {
+            let len = match rejected.header("Content-Length") {
+                Some(v) => std::str::from_utf8(v)
+                    .ok()
+                    .and_then(|v| v.trim().parse().ok())
+                    .unwrap_or(0),
+                None => usize::MAX,
+            };
+            let len = len.min(MAX_REJECTED_BODY);
+            if avail.len() < len && !eof {
+                return Ok(None); // Wait for more of the body
+            }
+            rejected.body = avail[..len.min(avail.len())].to_vec();
+        }
+        rd.consume(count + rejected.body.len());
+        return Err(ClientHandshakeError::Rejected(rejected));
+    }
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    };
+    let upgrade = header("Upgrade").is_some_and(|v| v.eq_ignore_ascii_case(b"websocket"));
+    let connection = header("Connection").is_some_and(|v| {
+        v.split(|b| *b == b',')
+            .any(|t| t.trim_ascii().eq_ignore_ascii_case(b"upgrade"))
+    });
+    if !upgrade || !connection {
+        return Err(Error::UpgradeInvalid.into());
+    }
+    if !header("Sec-WebSocket-Accept").is_some_and(|v| key.check_accept(v)) {
+        return Err(Error::AcceptStringInvalid.into());
+    }
+    let subprotocol = match header("Sec-WebSocket-Protocol") {
+        None => None,
+        Some(value) => {
+            let value = std::str::from_utf8(value).map_err(Error::from)?.trim();
+            if !config.subprotocols.iter().any(|p| p == value) {
+                return Err(Error::SubprotocolInvalid.into());
+            }
+            Some(value.to_string())
+        }
+    };
+    let mut extensions = Vec::new();
+    for h in response.headers.iter() {
+        if !h.name.eq_ignore_ascii_case("Sec-WebSocket-Extensions") {
+            continue;
+        }
+        let value = std::str::from_utf8(h.value).map_err(Error::from)?;
+        for ext in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            if !config
+                .extensions
+                .iter()
+                .any(|offer| extension_name(offer) == extension_name(ext))
+            {
+                return Err(Error::ExtensionInvalid.into());
+            }
+            extensions.push(ext.to_string());
+        }
+    }
+    for h in response.headers.iter() {
+        header_cb(h.name, h.value);
+    }
+    rd.consume(count);
+    Ok(Some(UpgradeResponse {
+        subprotocol,
+        extensions,
+    }))
+}
+
+// Get the name of an extension from an offer or acceptance, i.e.
+// the part before any parameters
+fn extension_name(ext: &str) -> &str {
+    ext.split(';').next().unwrap_or("").trim()
+}
+
+// Each `RandomState` is seeded differently, so hashing the time with
+// two of them gives 16 unpredictable bytes
+/// Client end of a websocket connection
+///
+/// Create this once the server has accepted the upgrade request, as
+/// checked by [`read_client_handshake`].  It shares the receive and
+/// send code of [`WebsocketServer`], and its methods behave the same
+/// as the methods of the same names there, except for masking.
+/// Every outgoing frame is masked with a new key, as RFC 6455
+/// requires of clients.  A masked frame from the server is a
+/// protocol error, so the connection is closed with code 1002 and
+/// `Error::Masked` is returned.
+pub struct WebsocketClient {
+    ws: WebsocketServer,
+}
+
+impl WebsocketClient {
+    /// Create a client connection in the `Open` state.  See
+    /// [`WebsocketServer::from_wss`] for `max_msg_len` and
+    /// `max_aux_len`.  The masking keys are generated from a seed
+    /// taken from the random keys of the standard library's `HashMap`
+    /// hasher.  To use some other source of randomness, see
+    /// [`WebsocketClient::new_entropy`].
+    pub fn new(max_msg_len: usize, max_aux_len: usize) -> Self {
+        Self::new_entropy(max_msg_len, max_aux_len, |bytes| *bytes = default_entropy())
+    }
+
+    /// Create a client connection, as for [`WebsocketClient::new`],
+    /// but taking the 16 bytes of the seed for the masking keys from
+    /// `entropy`, which must fill the array it is passed.  RFC 6455
+    /// requires the keys to be unpredictable to scripts which supply
+    /// the data sent, so the seed must be random.
+    pub fn new_entropy(
+        max_msg_len: usize,
+        max_aux_len: usize,
+        mut entropy: impl FnMut(&mut [u8; 16]),
+    ) -> Self {
+        let mut seed = [0; 16];
+        entropy(&mut seed);
+        Self {
+            ws: WebsocketServer::new_client(max_msg_len, max_aux_len, seed),
+        }
+    }
+
+    /// Send an unfragmented websocket text message
+    pub fn send_text(&mut self, pb: PBufRdWr, data: &str) -> Result<(), Error> {
+        self.ws.send_text(pb, data)
+    }
+
+    /// Send an unfragmented websocket binary message
+    pub fn send_binary(&mut self, pb: PBufRdWr, data: &[u8]) -> Result<(), Error> {
+        self.ws.send_binary(pb, data)
+    }
+
+    /// Send an arbitrary websocket message.  See
+    /// [`WebsocketServer::send`].
+    pub fn send(
+        &mut self,
+        pb: PBufRdWr,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.ws.send(pb, msg, eom, data)
+    }
+
+    /// Send a `Ping` carrying the next sequence number.  See
+    /// [`WebsocketServer::send_ping_seq`].
+    pub fn send_ping_seq(&mut self, pb: PBufRdWr) -> Result<u64, Error> {
+        self.ws.send_ping_seq(pb)
+    }
+
+    /// Enable keepalive pings.  See
+    /// [`WebsocketServer::set_keepalive`].  Servers can't rely on
+    /// browsers to ping them, so a long-lived client connection
+    /// needs this to survive behind NAT.
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration) {
+        self.ws.set_keepalive(interval, timeout);
+    }
+
+    /// Send a keepalive `Ping` if one is due, and check for the
+    /// server having timed out.  See [`WebsocketServer::keepalive`].
+    pub fn keepalive(&mut self, pb: PBufRdWr, clock: &impl WsClock) -> Result<bool, Error> {
+        self.ws.keepalive(pb, clock)
+    }
+
+    /// Get the time at which [`WebsocketClient::keepalive`] should
+    /// next be called.  See [`WebsocketServer::next_ping_due`].
+    pub fn next_ping_due(&self) -> Option<Duration> {
+        self.ws.next_ping_due()
+    }
+
+    /// Get the time at which data was last received from the server.
+    /// See [`WebsocketServer::last_activity`].
+    pub fn last_activity(&self) -> Option<Duration> {
+        self.ws.last_activity()
+    }
+
+    /// Process as much data as possible from the stream, replying
+    /// to `Ping` and `Close` messages from the server.  See
+    /// [`WebsocketServer::receive`].
+    pub fn receive(
+        &mut self,
+        pb: PBufRdWr,
+        message: PBufWr,
+        is_text: &mut bool,
+    ) -> Result<bool, Error> {
+        self.ws.receive(pb, message, is_text)
+    }
+
+    /// Initiate the close handshake.  See [`WebsocketServer::close`].
+    pub fn close(
+        &mut self,
+        pb: PBufRdWr,
+        code: WebSocketCloseStatusCode,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        self.ws.close(pb, code, reason)
+    }
+
+    /// Set an extension to interpret the reserved bits of data frames
+    /// and transform their payloads, as agreed with the server in
+    /// `UpgradeResponse::extensions`.  See
+    /// [`WebsocketServer::set_extension`].
+    pub fn set_extension(&mut self, extension: Option<Box<dyn WsExtension>>) {
+        self.ws.set_extension(extension);
+    }
+
+    /// Get the current state of the connection
+    pub fn state(&self) -> ConnectionState {
+        self.ws.state()
+    }
+
+    /// Get the fatal error which left the connection unusable, if
+    /// any.  See [`WebsocketServer::failure`].
+    pub fn failure(&self) -> Option<&Error> {
+        self.ws.failure()
+    }
+}
+
+fn default_entropy() -> [u8; 16] {
+    let now = SystemTime::now();
+    let mut bytes = [0; 16];
+    for half in bytes.chunks_mut(8) {
+        half.copy_from_slice(&RandomState::new().hash_one(now).to_le_bytes());
+    }
+    bytes
+}
+
+// Base64-encode 16 bytes, giving 22 digits and 2 padding characters
+fn encode_key(bytes: &[u8; 16]) -> String {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(24);
+    for group in bytes.chunks(3) {
+        let mut n = 0;
+        for (i, b) in group.iter().enumerate() {
+            n |= (*b as u32) << (16 - 8 * i);
+        }
+        for i in 0..=group.len() {
+            out.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+        }
+    }
+    out.push_str("==");
+    out
+}
@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+
+/// Source of monotonic time for time-based features
+///
+/// Rate limiting, the close deadline and the handshake deadline all
+/// take the current time from a `WsClock` supplied by the caller,
+/// rather than reading a system clock themselves.  This keeps the
+/// crate independent of any particular timer or runtime.  The time
+/// is given as a `Duration` since some fixed origin, which may be
+/// anything convenient, e.g. the time the program started, or the
+/// count of a hardware timer on a microcontroller.  It must never go
+/// backwards, and the same clock must be used for all calls relating
+/// to a connection, since only times from the same clock can be
+/// compared.
+///
+/// [`StdClock`] gives the time according to `std::time::Instant`.
+/// Any closure returning a `Duration` may also be used as a clock.
+pub trait WsClock {
+    /// Get the current time, as the duration since the clock's origin
+    fn now(&self) -> Duration;
+}
+
+impl<F: Fn() -> Duration> WsClock for F {
+    fn now(&self) -> Duration {
+        self()
+    }
+}
+
+/// [`WsClock`] based on `std::time::Instant`, with its origin at the
+/// moment it was created
+#[derive(Copy, Clone, Debug)]
+pub struct StdClock {
+    origin: Instant,
+}
+
+impl StdClock {
+    /// Create a clock with its origin at the current time
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WsClock for StdClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
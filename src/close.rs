@@ -0,0 +1,106 @@
+use embedded_websocket::WebSocketCloseStatusCode;
+
+/// Details of the close handshake
+///
+/// See [`WebsocketServer::last_close`].  This remains available after
+/// the connection has shut down, so that code which reaps closed
+/// connections can log why each one went away.
+///
+/// [`WebsocketServer::last_close`]: crate::WebsocketServer::last_close
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseInfo {
+    /// `true` if the peer sent the first `Close`, `false` if we did
+    pub by_peer: bool,
+    /// Status code from the first `Close`, or `None` if it had no
+    /// payload.  If the input stream was aborted before any `Close`,
+    /// this is 1006 (abnormal closure), with `by_peer` set.
+    pub code: Option<u16>,
+    /// Reason from the first `Close`.  If the peer's reason is not
+    /// valid UTF-8, then this is empty, and the `Close` is answered
+    /// with code 1007 (invalid payload data) rather than being echoed.
+    pub reason: String,
+    /// `true` once the `Close` has been answered, i.e. the close
+    /// handshake completed cleanly
+    pub completed: bool,
+}
+
+impl CloseInfo {
+    /// Decode the payload of a `Close` frame
+    pub(crate) fn from_payload(by_peer: bool, payload: &[u8]) -> Self {
+        let (code, reason) = match payload {
+            [hi, lo, reason @ ..] => (Some(u16::from_be_bytes([*hi, *lo])), reason),
+            _ => (None, &[][..]),
+        };
+        Self {
+            by_peer,
+            code,
+            reason: std::str::from_utf8(reason).unwrap_or("").to_string(),
+            completed: false,
+        }
+    }
+}
+
+/// Maximum length of a close reason, leaving room for the status
+/// code within the 125-byte limit for control frames
+pub(crate) const MAX_REASON_LEN: usize = 123;
+
+/// Truncate a close reason to at most `MAX_REASON_LEN` bytes,
+/// without splitting a UTF-8 character
+pub(crate) fn truncate_reason(reason: &str) -> &str {
+    let mut len = reason.len().min(MAX_REASON_LEN);
+    while !reason.is_char_boundary(len) {
+        len -= 1;
+    }
+    &reason[..len]
+}
+
+/// Check that the reason in the payload of a `Close` frame, if any,
+/// is valid UTF-8
+pub(crate) fn reason_ok(payload: &[u8]) -> bool {
+    payload
+        .get(2..)
+        .is_none_or(|r| std::str::from_utf8(r).is_ok())
+}
+
+/// Get the numeric value of a close status code, which
+/// `embedded_websocket` keeps private
+pub(crate) fn close_code(code: WebSocketCloseStatusCode) -> u16 {
+    use WebSocketCloseStatusCode::*;
+    match code {
+        NormalClosure => 1000,
+        EndpointUnavailable => 1001,
+        ProtocolError => 1002,
+        InvalidMessageType => 1003,
+        Reserved => 1004,
+        Empty => 1005,
+        InvalidPayloadData => 1007,
+        PolicyViolation => 1008,
+        MessageTooBig => 1009,
+        MandatoryExtension => 1010,
+        InternalServerError => 1011,
+        TlsHandshake => 1015,
+        Custom(value) => value,
+    }
+}
+
+/// Get the close status code for a numeric value, the reverse of
+/// [`close_code`]
+#[cfg(feature = "serde")]
+pub(crate) fn close_status(code: u16) -> WebSocketCloseStatusCode {
+    use WebSocketCloseStatusCode::*;
+    match code {
+        1000 => NormalClosure,
+        1001 => EndpointUnavailable,
+        1002 => ProtocolError,
+        1003 => InvalidMessageType,
+        1004 => Reserved,
+        1005 => Empty,
+        1007 => InvalidPayloadData,
+        1008 => PolicyViolation,
+        1009 => MessageTooBig,
+        1010 => MandatoryExtension,
+        1011 => InternalServerError,
+        1015 => TlsHandshake,
+        value => Custom(value),
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{Broadcaster, Error, WebsocketServer, WsTrip};
+use pipebuf::{PBufState, PipeBuf, PipeBufPair};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A websocket connection owned by [`WsConnections`]
+///
+/// This brings together the [`WebsocketServer`] and the pipe-buffers
+/// that it works with.  The transport glue code should exchange data
+/// with the lower end of `transport` (using `transport.lower()`),
+/// and the websocket layer works on the upper end.
+pub struct WsConnection {
+    /// Websocket protocol handler
+    pub ws: WebsocketServer,
+    /// Bidirectional pipe between the transport and the websocket
+    /// layer
+    pub transport: PipeBufPair,
+    /// Incoming message data.  See [`WebsocketServer::receive`].
+    pub message: PipeBuf,
+    /// Type of the incoming message: `true` for text, `false` for
+    /// binary
+    pub is_text: bool,
+}
+
+impl WsConnection {
+    /// Create from a [`WebsocketServer`] and the pipe that it was
+    /// created from
+    pub fn new(ws: WebsocketServer, transport: PipeBufPair) -> Self {
+        Self {
+            ws,
+            transport,
+            message: PipeBuf::new(),
+            is_text: false,
+        }
+    }
+
+    /// Process as much incoming data as possible.  See
+    /// [`WebsocketServer::receive`].  If a complete message is
+    /// waiting in `message`, returns `Ok(false)` without doing
+    /// anything until the caller has processed it and reset the
+    /// buffer.
+    pub fn receive(&mut self) -> Result<bool, Error> {
+        if self.has_message() {
+            return Ok(false);
+        }
+        self.ws
+            .receive(self.transport.upper(), self.message.wr(), &mut self.is_text)
+    }
+
+    /// Test whether a complete message is waiting in `message`
+    pub fn has_message(&self) -> bool {
+        !matches!(self.message.state(), PBufState::Open | PBufState::Push)
+    }
+
+    /// Send an unfragmented websocket text message
+    pub fn send_text(&mut self, data: &str) -> Result<(), Error> {
+        self.ws.send_text(self.transport.upper(), data)
+    }
+
+    /// Send an unfragmented websocket binary message
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.ws.send_binary(self.transport.upper(), data)
+    }
+
+    /// Get a tripwire value covering the transport streams and the
+    /// `message` buffer.  See [`WsTrip`].
+    pub fn tripwire(&self) -> WsTrip {
+        WsTrip {
+            rd: self.transport.up.tripwire(),
+            wr: self.transport.down.tripwire(),
+            message: Some(self.message.tripwire()),
+        }
+    }
+
+    /// Test whether this connection has finished, i.e. either the
+    /// outgoing stream has been closed and fully consumed by the
+    /// transport, or the incoming stream has been aborted
+    pub fn is_closed(&self) -> bool {
+        self.transport.down.is_done()
+            || matches!(
+                self.transport.up.state(),
+                PBufState::Aborting | PBufState::Aborted
+            )
+    }
+}
+
+/// Registry of websocket connections for a multi-client server
+///
+/// Connections are keyed by an ID chosen by the caller, for example
+/// a `mio::Token`.  This takes care of the bookkeeping that a server
+/// handling many connections would otherwise need to write by hand:
+/// looking up connections, sending to a particular connection,
+/// broadcasting to all of them and reaping those that have closed.
+pub struct WsConnections<K> {
+    map: HashMap<K, WsConnection>,
+}
+
+impl<K: Eq + Hash> WsConnections<K> {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    /// Add a connection whose handshake has completed.  `transport`
+    /// is the pipe that the [`WebsocketServer`] was created from.
+    /// Returns any connection previously registered with the same
+    /// key.
+    pub fn insert(
+        &mut self,
+        key: K,
+        ws: WebsocketServer,
+        transport: PipeBufPair,
+    ) -> Option<WsConnection> {
+        self.map.insert(key, WsConnection::new(ws, transport))
+    }
+
+    /// Remove a connection
+    pub fn remove(&mut self, key: &K) -> Option<WsConnection> {
+        self.map.remove(key)
+    }
+
+    /// Get a reference to a connection
+    pub fn get(&self, key: &K) -> Option<&WsConnection> {
+        self.map.get(key)
+    }
+
+    /// Get a mutable reference to a connection
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut WsConnection> {
+        self.map.get_mut(key)
+    }
+
+    /// Get the number of registered connections
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Test whether there are no registered connections
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Iterate over all connections
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &WsConnection)> + '_ {
+        self.map.iter()
+    }
+
+    /// Iterate mutably over all connections
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut WsConnection)> + '_ {
+        self.map.iter_mut()
+    }
+
+    /// Send an unfragmented websocket text message to one
+    /// connection.  Returns `Error::WebSocketNotOpen` if there is no
+    /// connection with that key.
+    pub fn send_text(&mut self, key: &K, data: &str) -> Result<(), Error> {
+        match self.map.get_mut(key) {
+            Some(conn) => conn.send_text(data),
+            None => Err(Error::WebSocketNotOpen),
+        }
+    }
+
+    /// Send an unfragmented websocket binary message to one
+    /// connection.  Returns `Error::WebSocketNotOpen` if there is no
+    /// connection with that key.
+    pub fn send_binary(&mut self, key: &K, data: &[u8]) -> Result<(), Error> {
+        match self.map.get_mut(key) {
+            Some(conn) => conn.send_binary(data),
+            None => Err(Error::WebSocketNotOpen),
+        }
+    }
+
+    /// Send a message to all connections.  Connections that can no
+    /// longer be sent to are skipped, as are those part-way through
+    /// sending a fragmented message, see [`Broadcaster::send`].
+    /// Returns the number of
+    /// connections that the message was sent to.
+    pub fn broadcast(&mut self, msg: &Broadcaster) -> usize {
+        let mut count = 0;
+        for conn in self.map.values_mut() {
+            if msg.send(&mut conn.ws, conn.transport.upper()).is_ok() {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+impl<K: Eq + Hash + Clone> WsConnections<K> {
+    /// Remove all connections which have closed (see
+    /// [`WsConnection::is_closed`]), passing each one to `cb` along
+    /// with its key
+    pub fn reap(&mut self, mut cb: impl FnMut(K, WsConnection)) {
+        let closed: Vec<K> = self
+            .map
+            .iter()
+            .filter(|(_, conn)| conn.is_closed())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in closed {
+            if let Some(conn) = self.map.remove(&key) {
+                cb(key, conn);
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash> Default for WsConnections<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{ConnectionState, Error, WebsocketClient, WebsocketServer};
+use embedded_websocket as ws;
+use pipebuf::{PBufRdWr, PBufWr};
+use ws::{WebSocketCloseStatusCode, WebSocketSendMessageType};
+
+/// Role-agnostic interface to a websocket connection
+///
+/// This trait is object-safe, so that frameworks may hold
+/// connections in a collection as `Box<dyn WsEndpoint>` without
+/// having to be generic over the connection type.  It is implemented
+/// by both [`WebsocketServer`] and [`WebsocketClient`], and the
+/// methods correspond to their inherent methods of the same names.
+pub trait WsEndpoint {
+    /// Send an arbitrary websocket message.  See
+    /// [`WebsocketServer::send`].
+    fn send(
+        &mut self,
+        pb: PBufRdWr,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error>;
+
+    /// Process as much data as possible from the stream.  See
+    /// [`WebsocketServer::receive`].
+    fn receive(&mut self, pb: PBufRdWr, message: PBufWr, is_text: &mut bool)
+        -> Result<bool, Error>;
+
+    /// Initiate the close handshake.  See [`WebsocketServer::close`].
+    fn close(
+        &mut self,
+        pb: PBufRdWr,
+        code: WebSocketCloseStatusCode,
+        reason: Option<&str>,
+    ) -> Result<(), Error>;
+
+    /// Get the current state of the connection
+    fn state(&self) -> ConnectionState;
+}
+
+impl WsEndpoint for WebsocketServer {
+    fn send(
+        &mut self,
+        pb: PBufRdWr,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        WebsocketServer::send(self, pb, msg, eom, data)
+    }
+
+    fn receive(
+        &mut self,
+        pb: PBufRdWr,
+        message: PBufWr,
+        is_text: &mut bool,
+    ) -> Result<bool, Error> {
+        WebsocketServer::receive(self, pb, message, is_text)
+    }
+
+    fn close(
+        &mut self,
+        pb: PBufRdWr,
+        code: WebSocketCloseStatusCode,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        WebsocketServer::close(self, pb, code, reason)
+    }
+
+    fn state(&self) -> ConnectionState {
+        WebsocketServer::state(self)
+    }
+}
+
+impl WsEndpoint for WebsocketClient {
+    fn send(
+        &mut self,
+        pb: PBufRdWr,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        WebsocketClient::send(self, pb, msg, eom, data)
+    }
+
+    fn receive(
+        &mut self,
+        pb: PBufRdWr,
+        message: PBufWr,
+        is_text: &mut bool,
+    ) -> Result<bool, Error> {
+        WebsocketClient::receive(self, pb, message, is_text)
+    }
+
+    fn close(
+        &mut self,
+        pb: PBufRdWr,
+        code: WebSocketCloseStatusCode,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        WebsocketClient::close(self, pb, code, reason)
+    }
+
+    fn state(&self) -> ConnectionState {
+        WebsocketClient::state(self)
+    }
+}
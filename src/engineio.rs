@@ -0,0 +1,106 @@
+use crate::{Error, WebsocketServer};
+use pipebuf::PBufRdWr;
+
+/// Packet of the Engine.IO protocol, version 4
+///
+/// Engine.IO is the transport layer underneath Socket.IO.  On a
+/// websocket transport, each text message carries one packet,
+/// starting with a single digit giving the packet type, followed by
+/// its data.  Binary data is carried as a binary message with no type
+/// prefix.  Socket.IO packets are carried as the data of
+/// [`EioPacket::Message`] packets, which the application must decode
+/// itself.
+///
+/// Engine.IO doesn't use a websocket subprotocol.  Instead the
+/// client connects to a path such as
+/// `/engine.io/?EIO=4&transport=websocket`, which the caller may
+/// check from the request headers.  After accepting the connection,
+/// the server must first send an [`EioPacket::Open`] packet
+/// containing the JSON handshake details, and then send
+/// [`EioPacket::Ping`] packets at the interval given there, which
+/// the client answers with [`EioPacket::Pong`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EioPacket {
+    /// Type 0: handshake details as JSON, sent by the server on
+    /// connecting
+    Open(String),
+    /// Type 1: request to close the transport
+    Close,
+    /// Type 2: heartbeat check, with optional data such as `probe`
+    Ping(String),
+    /// Type 3: answer to a `Ping`, echoing its data
+    Pong(String),
+    /// Type 4: text data for the application
+    Message(String),
+    /// Binary data for the application
+    Binary(Vec<u8>),
+    /// Type 5: completes an upgrade from the polling transport
+    Upgrade,
+    /// Type 6: no operation, used during an upgrade
+    Noop,
+}
+
+impl EioPacket {
+    /// Decode a complete incoming message.  `is_text` is as set by
+    /// [`WebsocketServer::receive`].  Returns `Error::InvalidPayload`
+    /// for a text message which is empty or has an unknown packet
+    /// type, or `Error::Utf8Error` if a text message is not valid
+    /// UTF-8.
+    pub fn decode(message: &[u8], is_text: bool) -> Result<Self, Error> {
+        if !is_text {
+            return Ok(Self::Binary(message.to_vec()));
+        }
+        let text = std::str::from_utf8(message)?;
+        let mut chars = text.chars();
+        let kind = chars.next().ok_or(Error::InvalidPayload)?;
+        let data = chars.as_str().to_string();
+        Ok(match kind {
+            '0' => Self::Open(data),
+            '1' => Self::Close,
+            '2' => Self::Ping(data),
+            '3' => Self::Pong(data),
+            '4' => Self::Message(data),
+            '5' => Self::Upgrade,
+            '6' => Self::Noop,
+            _ => return Err(Error::InvalidPayload),
+        })
+    }
+
+    /// Encode a text packet as it would appear in a text message.
+    /// Returns `None` for [`EioPacket::Binary`], which has no text
+    /// form.
+    pub fn encode(&self) -> Option<String> {
+        let (kind, data) = self.parts().ok()?;
+        Some(text_packet(kind, data))
+    }
+
+    /// Send the packet as a single websocket message
+    pub fn send(&self, ws: &mut WebsocketServer, pb: PBufRdWr) -> Result<(), Error> {
+        match self.parts() {
+            Ok((kind, data)) => ws.send_text(pb, &text_packet(kind, data)),
+            Err(data) => ws.send_binary(pb, data),
+        }
+    }
+
+    // Get the packet type and data of a text packet, or the data of a
+    // binary packet as the error
+    fn parts(&self) -> Result<(char, &str), &[u8]> {
+        Ok(match self {
+            Self::Open(data) => ('0', data.as_str()),
+            Self::Close => ('1', ""),
+            Self::Ping(data) => ('2', data.as_str()),
+            Self::Pong(data) => ('3', data.as_str()),
+            Self::Message(data) => ('4', data.as_str()),
+            Self::Binary(data) => return Err(data),
+            Self::Upgrade => ('5', ""),
+            Self::Noop => ('6', ""),
+        })
+    }
+}
+
+fn text_packet(kind: char, data: &str) -> String {
+    let mut out = String::with_capacity(1 + data.len());
+    out.push(kind);
+    out.push_str(data);
+    out
+}
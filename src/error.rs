@@ -0,0 +1,166 @@
+use embedded_websocket as ws;
+
+/// Websocket errors
+///
+/// This has the same variants as [`embedded_websocket::Error`], so
+/// that errors from `embedded_websocket` are passed on unchanged,
+/// plus those which only arise in this wrapper.  Unlike the
+/// `embedded_websocket` type, it may be copied, so that the first
+/// fatal error on a connection can be kept and reported later.
+///
+/// Each variant is documented as fatal or recoverable when returned
+/// by a receive call or a handshake, as also reported by
+/// [`Error::is_fatal`].  After a fatal error, the connection or
+/// handshake can't continue and should be shut down.  A recoverable
+/// error leaves everything as it was, so the caller may carry on,
+/// e.g. by waiting for more data or for the close handshake to
+/// finish.  Errors from sending are always recoverable, since
+/// nothing is written when a send fails.
+///
+/// [`embedded_websocket::Error`]:
+/// https://docs.rs/embedded-websocket/0.8.0/embedded_websocket/enum.Error.html
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Websocket frame has an invalid opcode.  Fatal.
+    InvalidOpCode,
+    /// Frame or payload has an invalid length.  Fatal.
+    InvalidFrameLength,
+    /// `Close` frame has an invalid status code.  Fatal.
+    InvalidCloseStatusCode,
+    /// The connection is not open for this operation, e.g. a send
+    /// after our `Close`.  Recoverable, since nothing was sent.
+    WebSocketNotOpen,
+    /// The connection is already open.  Recoverable.
+    WebsocketAlreadyOpen,
+    /// Text is not valid UTF-8.  Fatal.
+    Utf8Error,
+    /// Other invalid data.  Fatal.
+    Unknown,
+    /// Invalid HTTP request or response.  Fatal.
+    HttpHeader(httparse::Error),
+    /// HTTP request has no path.  Fatal.
+    HttpHeaderNoPath,
+    /// HTTP request or response is incomplete.  Recoverable, by
+    /// waiting for more data.
+    HttpHeaderIncomplete,
+    /// Buffer too small for the data, e.g. a message longer than the
+    /// limit.  Fatal.
+    WriteToBufferTooSmall,
+    /// Frame is incomplete.  Recoverable, by waiting for more data.
+    ReadFrameIncomplete,
+    /// HTTP response has an unexpected status code.  Fatal.
+    HttpResponseCodeInvalid(Option<u16>),
+    /// `Sec-WebSocket-Accept` doesn't match the key.  Fatal.
+    AcceptStringInvalid,
+    /// Conversion error within `embedded_websocket`.  Fatal.
+    ConvertInfallible,
+    /// Random number generator error.  Fatal.
+    RandCore,
+    /// Continuation frame without a message to continue.  Fatal.
+    UnexpectedContinuationFrame,
+    /// An earlier call failed with a fatal error, after which the
+    /// connection can't be used any further.  The original error is
+    /// available from [`WebsocketServer::failure`].  Fatal.
+    ///
+    /// [`WebsocketServer::failure`]: crate::WebsocketServer::failure
+    ConnectionFailed,
+    /// A frame from the client was not masked, as RFC 6455 requires.
+    /// Fatal.
+    Unmasked,
+    /// A frame from the server was masked, which RFC 6455 forbids.
+    /// Fatal.
+    Masked,
+    /// A fragmented message was given where only whole messages can
+    /// be sent.  Recoverable, since nothing was sent.
+    FragmentUnsupported,
+    /// A message is not valid for the framing of the subprotocol or
+    /// packet format it was parsed as.  Recoverable, since the
+    /// websocket connection itself is unaffected, although the
+    /// subprotocol may require it to be closed.
+    InvalidPayload,
+    /// A multiplexed channel doesn't exist, or is in the wrong state
+    /// for the operation.  Recoverable.
+    InvalidChannel,
+    /// The peer exceeded a limit of a multiplexed connection, on the
+    /// number of channels or the amount of data buffered.
+    /// Recoverable, since the websocket connection itself is
+    /// unaffected, although the peer should usually be disconnected.
+    LimitExceeded,
+    /// The server's handshake response is not a websocket upgrade.
+    /// Fatal.
+    UpgradeInvalid,
+    /// The server selected a subprotocol which wasn't offered.
+    /// Fatal.
+    SubprotocolInvalid,
+    /// The server accepted an extension which wasn't offered.  Fatal.
+    ExtensionInvalid,
+    /// A value given for the upgrade request contains characters
+    /// which aren't allowed there, such as CR or LF in a header
+    /// value.  Recoverable, since nothing was written.
+    InvalidHeader,
+    /// A snapshot has an unknown connection state or a subprotocol
+    /// which is too long.  Fatal, since the snapshot can't be used.
+    InvalidSnapshot,
+}
+
+impl Error {
+    /// Test whether the error is fatal when returned by a receive
+    /// call or a handshake, meaning that the connection or handshake
+    /// can't continue.  Fatal errors from
+    /// [`WebsocketServer::receive`] and the other receive calls are
+    /// kept as the failure of the connection, after which every call
+    /// fails with `Error::ConnectionFailed`.
+    ///
+    /// [`WebsocketServer::receive`]: crate::WebsocketServer::receive
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            Self::WebSocketNotOpen
+                | Self::WebsocketAlreadyOpen
+                | Self::HttpHeaderIncomplete
+                | Self::ReadFrameIncomplete
+                | Self::FragmentUnsupported
+                | Self::InvalidPayload
+                | Self::InvalidChannel
+                | Self::LimitExceeded
+                | Self::InvalidHeader
+        )
+    }
+}
+
+impl From<ws::Error> for Error {
+    fn from(e: ws::Error) -> Self {
+        use ws::Error as E;
+        match e {
+            E::InvalidOpCode => Self::InvalidOpCode,
+            E::InvalidFrameLength => Self::InvalidFrameLength,
+            E::InvalidCloseStatusCode => Self::InvalidCloseStatusCode,
+            E::WebSocketNotOpen => Self::WebSocketNotOpen,
+            E::WebsocketAlreadyOpen => Self::WebsocketAlreadyOpen,
+            E::Utf8Error => Self::Utf8Error,
+            E::Unknown => Self::Unknown,
+            E::HttpHeader(e) => Self::HttpHeader(e),
+            E::HttpHeaderNoPath => Self::HttpHeaderNoPath,
+            E::HttpHeaderIncomplete => Self::HttpHeaderIncomplete,
+            E::WriteToBufferTooSmall => Self::WriteToBufferTooSmall,
+            E::ReadFrameIncomplete => Self::ReadFrameIncomplete,
+            E::HttpResponseCodeInvalid(code) => Self::HttpResponseCodeInvalid(code),
+            E::AcceptStringInvalid => Self::AcceptStringInvalid,
+            E::ConvertInfallible => Self::ConvertInfallible,
+            E::RandCore => Self::RandCore,
+            E::UnexpectedContinuationFrame => Self::UnexpectedContinuationFrame,
+        }
+    }
+}
+
+impl From<httparse::Error> for Error {
+    fn from(e: httparse::Error) -> Self {
+        Self::HttpHeader(e)
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(_: std::str::Utf8Error) -> Self {
+        Self::Utf8Error
+    }
+}
@@ -0,0 +1,72 @@
+use crate::Violation;
+use embedded_websocket::WebSocketState;
+use std::collections::VecDeque;
+
+/// Protocol event recorded for debugging
+///
+/// See [`WebsocketServer::set_event_log`].
+///
+/// [`WebsocketServer::set_event_log`]: crate::WebsocketServer::set_event_log
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WsEvent {
+    /// Header of an incoming frame was consumed
+    FrameIn {
+        /// Frame opcode
+        opcode: u8,
+        /// FIN bit
+        fin: bool,
+        /// Payload length
+        len: u64,
+    },
+    /// An outgoing frame was written
+    FrameOut {
+        /// Frame opcode
+        opcode: u8,
+        /// FIN bit
+        fin: bool,
+        /// Length of the whole frame, including the header
+        len: usize,
+    },
+    /// The protocol state changed
+    State(WebSocketState),
+    /// EOF was reached on the input stream
+    InputEnded,
+    /// The input stream was aborted, i.e. the transport failed
+    InputAborted,
+    /// The output stream was found closed by something other than
+    /// the close handshake, so replies can no longer be sent
+    OutputEnded,
+    /// The peer committed a minor conformance violation
+    Violation(Violation),
+}
+
+/// Ring buffer holding the most recent events
+pub(crate) struct EventLog {
+    events: VecDeque<WsEvent>,
+    capacity: usize,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn push(&mut self, ev: WsEvent) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(ev);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &WsEvent> {
+        self.events.iter()
+    }
+}
@@ -0,0 +1,222 @@
+use crate::Error;
+use embedded_websocket::WebSocketSendMessageType;
+
+/// Websocket frame header
+///
+/// Incoming frames are parsed and unmasked by this crate rather than
+/// by `embedded_websocket`, because it does not handle frames which
+/// arrive in several parts correctly.  Headers are passed to the
+/// caller by [`WebsocketServer::receive_frame`].
+///
+/// [`WebsocketServer::receive_frame`]: crate::WebsocketServer::receive_frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// FIN bit, set on the final fragment of a message
+    pub fin: bool,
+    /// RSV1, RSV2 and RSV3 bits, in the low 3 bits.  These are
+    /// reserved for use by extensions.
+    pub rsv: u8,
+    /// Frame opcode (low 4 bits of the first byte)
+    pub opcode: u8,
+    /// Masking key, if the payload is masked
+    pub mask: Option<[u8; 4]>,
+    /// Payload length
+    pub len: u64,
+    /// Length of the header, including the mask key if present
+    pub header_len: usize,
+}
+
+impl FrameHeader {
+    /// Parse a frame header from the start of `data`.  Returns `None`
+    /// if more data is required to complete the header.
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 2 {
+            return None;
+        }
+        let fin = (data[0] & 0x80) != 0;
+        let rsv = (data[0] >> 4) & 7;
+        let opcode = data[0] & 0x0F;
+        let masked = (data[1] & 0x80) != 0;
+        let (len, mut header_len) = match data[1] & 0x7F {
+            126 => {
+                let b = data.get(2..4)?;
+                (u16::from_be_bytes([b[0], b[1]]) as u64, 4)
+            }
+            127 => {
+                let mut b = [0; 8];
+                b.copy_from_slice(data.get(2..10)?);
+                (u64::from_be_bytes(b), 10)
+            }
+            len => (len as u64, 2),
+        };
+        let mask = if masked {
+            let mut key = [0; 4];
+            key.copy_from_slice(data.get(header_len..header_len + 4)?);
+            header_len += 4;
+            Some(key)
+        } else {
+            None
+        };
+        Some(Self {
+            fin,
+            rsv,
+            opcode,
+            mask,
+            len,
+            header_len,
+        })
+    }
+}
+
+/// Get the length of the frame header at the start of `data`, as far
+/// as can be told from the bytes which have arrived so far.  This is
+/// 2 until the second byte has arrived.
+pub(crate) fn header_len(data: &[u8]) -> usize {
+    let Some(b) = data.get(1) else {
+        return 2;
+    };
+    let ext = match b & 0x7F {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask = if (b & 0x80) != 0 { 4 } else { 0 };
+    2 + ext + mask
+}
+
+/// Copy payload data from `from` to `to`, removing the mask if there
+/// is one.  Since masking is an XOR, this also applies the mask to
+/// outgoing data.  `pos` is the offset of `from[0]` within the frame
+/// payload, which is required to continue unmasking a frame which
+/// arrives in several parts.
+pub(crate) fn unmask(mask: Option<[u8; 4]>, pos: u64, from: &[u8], to: &mut [u8]) {
+    let to = &mut to[..from.len()];
+    to.copy_from_slice(from);
+    if let Some(key) = mask {
+        let phase = (pos % 4) as usize;
+        for (i, b) in to.iter_mut().enumerate() {
+            *b ^= key[(phase + i) % 4];
+        }
+    }
+}
+
+/// Which end of the connection this is
+///
+/// The receive and send code is shared, and consults the role for
+/// the only things which differ between the two ends: the masking
+/// required of incoming frames, and the masking and size of outgoing
+/// frame headers.  Frames from a [`Broadcaster`] are encoded once
+/// unmasked, so they are for servers only.
+///
+/// [`Broadcaster`]: crate::Broadcaster
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Role {
+    Server,
+    Client,
+}
+
+impl Role {
+    /// Check whether a frame from the peer has the masking required
+    /// by RFC 6455: clients must mask every frame, and servers must
+    /// not mask any.  `accept_unmasked` allows a server to accept
+    /// unmasked frames from non-conforming clients.
+    pub(crate) fn mask_ok(self, h: &FrameHeader, accept_unmasked: bool) -> bool {
+        match self {
+            Self::Server => h.mask.is_some() || accept_unmasked,
+            Self::Client => h.mask.is_none(),
+        }
+    }
+
+    /// Get the exact length of the header of an outgoing frame with a
+    /// payload of `len` bytes
+    pub(crate) fn header_len(self, len: usize) -> usize {
+        let key_len = match self {
+            Self::Server => 0,
+            Self::Client => 4,
+        };
+        key_len
+            + match len {
+                0..=125 => 2,
+                126..=0xFFFF => 4,
+                _ => 10,
+            }
+    }
+
+    /// Get the space to reserve for sending a frame with a payload of
+    /// `len` bytes.  The header is at most 10 bytes for a server, and
+    /// 14 bytes for a client, which adds the masking key.
+    pub(crate) fn frame_reserve(self, len: usize) -> usize {
+        match self {
+            Self::Server => 12 + len,
+            Self::Client => 16 + len,
+        }
+    }
+
+    /// Encode an outgoing frame into `to`, returning the number of
+    /// bytes used.  The length is always encoded in the shortest
+    /// form, as RFC 6455 requires.  A client masks the payload with
+    /// `key`, which a server ignores.  Fails with
+    /// `Error::WriteToBufferTooSmall` if the frame doesn't fit.
+    pub(crate) fn write_frame(
+        self,
+        fin: bool,
+        opcode: u8,
+        payload: &[u8],
+        key: [u8; 4],
+        to: &mut [u8],
+    ) -> Result<usize, Error> {
+        let len = payload.len();
+        let header_len = self.header_len(len);
+        let to = to
+            .get_mut(..header_len + len)
+            .ok_or(Error::WriteToBufferTooSmall)?;
+        to[0] = if fin { 0x80 } else { 0 } | opcode;
+        let key_at = match len {
+            0..=125 => {
+                to[1] = len as u8;
+                2
+            }
+            126..=0xFFFF => {
+                to[1] = 126;
+                to[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+                4
+            }
+            _ => {
+                to[1] = 127;
+                to[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+                10
+            }
+        };
+        let mask = match self {
+            Self::Server => None,
+            Self::Client => {
+                to[1] |= 0x80;
+                to[key_at..key_at + 4].copy_from_slice(&key);
+                Some(key)
+            }
+        };
+        unmask(mask, 0, payload, &mut to[header_len..]);
+        Ok(header_len + len)
+    }
+}
+
+/// Get the opcode of an outgoing frame of the given message type.
+/// Text and binary frames which follow the first frame of a message
+/// are sent as continuation frames.  Control frames always have
+/// their own opcode, even in the middle of a fragmented message.
+///
+/// Frames are encoded by this crate rather than by
+/// `embedded_websocket`, since that sends a control frame in the
+/// middle of a fragmented message as a continuation frame, and
+/// restarts the message after it.
+pub(crate) fn opcode(msg: WebSocketSendMessageType, continuation: bool) -> u8 {
+    use WebSocketSendMessageType as M;
+    match msg {
+        M::Text | M::Binary if continuation => 0,
+        M::Text => 1,
+        M::Binary => 2,
+        M::CloseReply => 8,
+        M::Ping => 9,
+        M::Pong => 10,
+    }
+}
@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Subprotocol name for GraphQL over websocket, as used by the
+/// `graphql-ws` library and most current GraphQL servers
+pub const GRAPHQL_TRANSPORT_WS_SUBPROTOCOL: &str = "graphql-transport-ws";
+
+/// Close code for an invalid or unexpected message
+pub const GRAPHQL_CLOSE_INVALID_MESSAGE: u16 = 4400;
+/// Close code for a `Subscribe` before the connection was acknowledged
+pub const GRAPHQL_CLOSE_UNAUTHORIZED: u16 = 4401;
+/// Close code for a `ConnectionInit` not arriving in time
+pub const GRAPHQL_CLOSE_INIT_TIMEOUT: u16 = 4408;
+/// Close code for a `Subscribe` reusing an ID which is still active
+pub const GRAPHQL_CLOSE_SUBSCRIBER_EXISTS: u16 = 4409;
+/// Close code for more than one `ConnectionInit`
+pub const GRAPHQL_CLOSE_TOO_MANY_INIT: u16 = 4429;
+
+/// Message of the `graphql-transport-ws` protocol
+///
+/// Each message is carried as JSON in a text message, tagged by its
+/// `type` field.  Send with [`WebsocketServer::send_json`], and
+/// decode complete incoming messages with [`parse_json`].  A parse
+/// failure should be answered by closing with
+/// [`GRAPHQL_CLOSE_INVALID_MESSAGE`].
+///
+/// The client starts with `ConnectionInit`, which the server answers
+/// with `ConnectionAck`.  Then the client may `Subscribe` to
+/// operations, each with its own ID.  The server sends results with
+/// `Next`, and ends each operation with `Complete` or `Error`.  The
+/// client may also `Complete` an operation early.  Either side may
+/// `Ping` at any time, which must be answered with `Pong`.
+///
+/// [`WebsocketServer::send_json`]: crate::WebsocketServer::send_json
+/// [`parse_json`]: crate::parse_json
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GraphqlMessage {
+    /// Client requests to start, e.g. with authentication details
+    ConnectionInit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Map<String, Value>>,
+    },
+    /// Server accepts the connection
+    ConnectionAck {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Map<String, Value>>,
+    },
+    /// Check that the peer is still responding
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Map<String, Value>>,
+    },
+    /// Answer to `Ping`
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<Map<String, Value>>,
+    },
+    /// Client requests an operation to be executed
+    Subscribe { id: String, payload: GraphqlRequest },
+    /// Server sends a result of an operation
+    Next { id: String, payload: Value },
+    /// Server reports that an operation failed.  The payload is a
+    /// list of GraphQL errors.
+    Error { id: String, payload: Vec<Value> },
+    /// Operation finished, sent by the server when no more results
+    /// follow, or by the client to stop an operation
+    Complete { id: String },
+}
+
+/// Operation to execute, as the payload of a `Subscribe` message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphqlRequest {
+    /// Name of the operation to execute within the query document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation_name: Option<String>,
+    /// GraphQL query document
+    pub query: String,
+    /// Values of variables used in the query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Map<String, Value>>,
+    /// Protocol extensions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Map<String, Value>>,
+}
@@ -0,0 +1,675 @@
+use crate::{Error, Quirks, WebsocketServer, WsClock, WsObserver};
+use embedded_websocket as ws;
+use pipebuf::{PBufRdWr, PBufWr};
+use std::sync::Arc;
+use std::time::Duration;
+use ws::{WebSocketSendMessageType, WebSocketSubProtocol};
+
+/// Reason for rejecting a websocket upgrade request
+///
+/// The variants other than `Ws` are for requests which are clearly
+/// attempting a websocket upgrade, but which are not acceptable.  In
+/// that case, [`HandshakeError::status`] gives the HTTP status code
+/// that the server should answer with before closing the
+/// connection.  `Ws(_)` covers everything else, including data which
+/// is not a websocket upgrade request at all, which may be passed on
+/// to another protocol handler.
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    /// The method of an upgrade request is not `GET`
+    BadMethod,
+    /// The HTTP version of an upgrade request is not 1.1
+    BadHttpVersion,
+    /// The `Sec-WebSocket-Key` of an upgrade request is missing,
+    /// repeated or malformed
+    BadKey,
+    /// The upgrade request announces a body, with `Transfer-Encoding`
+    /// or a non-zero `Content-Length`.  The body data would otherwise
+    /// be misinterpreted as websocket frames.  A short body may be
+    /// skipped instead, see `ServerHandshake::set_skip_body`.
+    RequestBody,
+    /// The upgrade request repeats a header which may only appear
+    /// once, such as `Host`.  This is only checked if enabled with
+    /// [`ServerHandshake::set_strict_headers`].
+    DuplicateHeader,
+    /// The request has more header lines than the limit set with
+    /// [`ServerHandshake::set_max_headers`], 32 by default.  This is
+    /// returned whether or not the request is an upgrade, since the
+    /// headers can't be examined.
+    TooManyHeaders,
+    /// The upgrade request did not complete in time.  See
+    /// [`ServerHandshake::handshake_deadline`].
+    Timeout,
+    /// The caller refused the connection with the given HTTP status,
+    /// and the response has already been sent.  See
+    /// [`WebsocketServer::from_http_check`].
+    Refused(u16),
+    /// Invalid or incomplete HTTP request, or the request is not a
+    /// websocket upgrade, or the reply could not be generated
+    Ws(Error),
+}
+
+impl HandshakeError {
+    /// Get the HTTP status code to reject the request with: 405 for a
+    /// bad method, 505 for a bad HTTP version, 431 for too many
+    /// headers, 408 for a timeout, the caller's status for a refusal,
+    /// otherwise 400
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::BadMethod => 405,
+            Self::BadHttpVersion => 505,
+            Self::TooManyHeaders => 431,
+            Self::Timeout => 408,
+            Self::Refused(status) => *status,
+            Self::BadKey | Self::RequestBody | Self::DuplicateHeader | Self::Ws(_) => 400,
+        }
+    }
+
+    /// Write a minimal HTTP response rejecting the request with the
+    /// status from [`HandshakeError::status`], with a push.  The
+    /// response asks for the connection to be closed, so the caller
+    /// should then close `wr`.
+    pub fn write_response(&self, mut wr: PBufWr) {
+        let status = self.status();
+        let response = format!(
+            "HTTP/1.1 {status} {}\r\n\
+             Connection: close\r\n\
+             Content-Length: 0\r\n\
+             \r\n",
+            reason_phrase(status)
+        );
+        wr.append(response.as_bytes());
+        wr.push();
+    }
+}
+
+impl From<Error> for HandshakeError {
+    fn from(e: Error) -> Self {
+        Self::Ws(e)
+    }
+}
+
+impl From<ws::Error> for HandshakeError {
+    fn from(e: ws::Error) -> Self {
+        Self::Ws(e.into())
+    }
+}
+
+/// Server connection which has not yet completed the websocket
+/// handshake
+///
+/// This holds the arguments for [`WebsocketServer::from_http_scan`]
+/// whilst waiting for the HTTP headers to arrive, and allows messages
+/// to be queued up before the connection has been accepted.  For
+/// example a server that pushes a greeting as its first action can
+/// queue it here straight away.  Queued messages are written to
+/// `pb.wr` immediately after the protocol reply.  If one of them
+/// can't be sent, the connection is still returned, since the client
+/// has already been told that it was accepted, but it is returned in
+/// the failed state with the error given by
+/// [`WebsocketServer::failure`], so that the caller can close it.
+pub struct ServerHandshake {
+    subprotocol: Option<WebSocketSubProtocol>,
+    max_msg_len: usize,
+    max_aux_len: usize,
+    checks: Checks,
+    queue: Vec<(WebSocketSendMessageType, Vec<u8>)>,
+    started_at: Option<Duration>,
+    clock: Option<Arc<dyn WsClock>>,
+    observer: Option<Box<dyn WsObserver>>,
+    // Length of input already searched for the end of the request
+    scanned: usize,
+}
+
+impl ServerHandshake {
+    /// Prepare to accept a websocket connection.  See
+    /// [`WebsocketServer::from_http_scan`] for details of the
+    /// arguments.
+    pub fn new(
+        subprotocol: Option<WebSocketSubProtocol>,
+        max_msg_len: usize,
+        max_aux_len: usize,
+    ) -> Self {
+        Self {
+            subprotocol,
+            max_msg_len,
+            max_aux_len,
+            checks: Checks::default(),
+            queue: Vec::new(),
+            started_at: None,
+            clock: None,
+            observer: None,
+            scanned: 0,
+        }
+    }
+
+    /// Accept a `Sec-WebSocket-Key` which is not the base64 encoding
+    /// of 16 bytes, as required by RFC 6455, for compatibility with
+    /// broken clients.  The key must still be present and at most 24
+    /// bytes long.  Off by default.  The strict check makes it harder
+    /// for a request from some other protocol to be mistaken for a
+    /// websocket upgrade.
+    pub fn set_lenient_key(&mut self, lenient: bool) {
+        self.checks.quirks.lenient_key = lenient;
+    }
+
+    /// Set which faults of broken clients to tolerate.
+    /// `Quirks::lenient_key` applies to the upgrade request, and all
+    /// of them are passed on to the accepted connection, as if set
+    /// with [`WebsocketServer::set_quirks`].  This replaces any
+    /// earlier call to [`ServerHandshake::set_lenient_key`].  See
+    /// [`Quirks`].
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.checks.quirks = quirks;
+    }
+
+    /// Skip over a body of up to `limit` bytes sent with the upgrade
+    /// request, instead of rejecting the request.  This is for
+    /// misbehaving clients that send a short body with the upgrade.
+    /// The body must be announced with `Content-Length`, and the
+    /// handshake waits for the whole body to arrive before accepting
+    /// the connection.  A longer body, or a body sent with
+    /// `Transfer-Encoding`, still gives `HandshakeError::RequestBody`.
+    /// The default limit is 0, i.e. any body is rejected.
+    pub fn set_skip_body(&mut self, limit: usize) {
+        self.checks.skip_body = limit;
+    }
+
+    /// Reject upgrade requests which repeat a header that may only
+    /// appear once, giving `HandshakeError::DuplicateHeader`.  The
+    /// headers checked are `Host`, `Origin`, `Content-Length`,
+    /// `Sec-WebSocket-Key` and `Sec-WebSocket-Version`.  Off by
+    /// default.  Duplicates of these are a sign of request smuggling,
+    /// where a proxy and the server may each act on a different copy.
+    /// A repeated `Sec-WebSocket-Key` is always rejected.
+    pub fn set_strict_headers(&mut self, strict: bool) {
+        self.checks.strict_headers = strict;
+    }
+
+    /// Set the maximum number of header lines accepted in the
+    /// request, 32 by default.  A request with more is rejected with
+    /// `HandshakeError::TooManyHeaders`.  Up to 32 headers are parsed
+    /// using space on the stack, and only a request with more header
+    /// lines than that needs a heap allocation.  A lower limit
+    /// doesn't reduce the stack space used, which is 32 slots of four
+    /// words each.
+    pub fn set_max_headers(&mut self, max: usize) {
+        self.checks.max_headers = max;
+    }
+
+    /// Enforce a time limit on receiving the upgrade request, to
+    /// protect against slow-loris clients which trickle in the HTTP
+    /// headers to hold connections open.  If the limit is exceeded,
+    /// the output stream `pb.wr` is closed, so that the transport can
+    /// be shut down, and `HandshakeError::Timeout` is returned.  This
+    /// should be called regularly from a timer until the handshake
+    /// completes, taking the current time from `clock`.
+    ///
+    /// The time is measured from when the clock was registered with
+    /// [`ServerHandshake::set_clock`], which must be the same clock as
+    /// passed here.  Without a registered clock, it is measured from
+    /// the first call to this method, so in that case this should
+    /// also be called as soon as the connection is accepted.
+    pub fn handshake_deadline(
+        &mut self,
+        mut pb: PBufRdWr,
+        clock: &impl WsClock,
+        limit: Duration,
+    ) -> Result<(), HandshakeError> {
+        let now = clock.now();
+        let started_at = *self.started_at.get_or_insert(now);
+        if now.saturating_sub(started_at) < limit {
+            return Ok(());
+        }
+        if !pb.wr.is_eof() {
+            pb.wr.close();
+        }
+        Err(HandshakeError::Timeout)
+    }
+
+    /// Register a [`WsClock`], and record the current time from it as
+    /// the start of the handshake for
+    /// [`ServerHandshake::handshake_deadline`].  So this should be
+    /// called as soon as the transport connection is accepted.  The
+    /// clock is passed on to the connection once it is accepted, see
+    /// [`WebsocketServer::set_clock`].
+    pub fn set_clock(&mut self, clock: Arc<dyn WsClock>) {
+        self.started_at = Some(clock.now());
+        self.clock = Some(clock);
+    }
+
+    /// Register a [`WsObserver`] to be passed on to the connection
+    /// once it is accepted, at which point
+    /// [`WsObserver::handshake_complete`] is called.  See
+    /// [`WebsocketServer::set_observer`].
+    pub fn set_observer(&mut self, observer: Box<dyn WsObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Queue an unfragmented websocket text message to be sent once
+    /// the connection is accepted
+    pub fn send_text(&mut self, data: &str) {
+        self.queue_message(WebSocketSendMessageType::Text, data.as_bytes());
+    }
+
+    /// Queue an unfragmented websocket binary message to be sent
+    /// once the connection is accepted
+    pub fn send_binary(&mut self, data: &[u8]) {
+        self.queue_message(WebSocketSendMessageType::Binary, data);
+    }
+
+    fn queue_message(&mut self, msg: WebSocketSendMessageType, data: &[u8]) {
+        self.queue.push((msg, data.to_vec()));
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers, and if successful, send any
+    /// queued messages.  See [`WebsocketServer::from_http_scan`] for
+    /// details of arguments and returns.
+    pub fn accept_scan(
+        &mut self,
+        pb: PBufRdWr,
+        header_cb: impl FnMut(&str, &[u8]),
+    ) -> Result<Option<WebsocketServer>, HandshakeError> {
+        let subprotocol = self.subprotocol.clone();
+        self.accept_http(pb, |_| subprotocol, |_, _, _| (), no_veto(header_cb))
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers, and if successful, send any
+    /// queued messages.  See [`WebsocketServer::from_http_scan_request`]
+    /// for details of `request_cb` and `header_cb`.
+    pub fn accept_scan_request(
+        &mut self,
+        pb: PBufRdWr,
+        request_cb: impl FnOnce(&str, &str, u8),
+        header_cb: impl FnMut(&str, &[u8]),
+    ) -> Result<Option<WebsocketServer>, HandshakeError> {
+        let subprotocol = self.subprotocol.clone();
+        self.accept_http(pb, |_| subprotocol, request_cb, no_veto(header_cb))
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers, and if successful, send any
+    /// queued messages.  See [`WebsocketServer::from_http_scan_utf8`]
+    /// for details of `header_cb`.
+    pub fn accept_scan_utf8(
+        &mut self,
+        pb: PBufRdWr,
+        header_cb: impl FnMut(&str, Result<&str, &[u8]>),
+    ) -> Result<Option<WebsocketServer>, HandshakeError> {
+        self.accept_scan(pb, utf8_values(header_cb))
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers, allowing the caller to refuse
+    /// the connection based on the headers, and if successful, send
+    /// any queued messages.  See [`WebsocketServer::from_http_check`]
+    /// for details of `check`.
+    pub fn accept_check(
+        &mut self,
+        pb: PBufRdWr,
+        check: impl FnMut(&str, &[u8]) -> Result<(), u16>,
+    ) -> Result<Option<WebsocketServer>, HandshakeError> {
+        let subprotocol = self.subprotocol.clone();
+        self.accept_http(pb, |_| subprotocol, |_, _, _| (), check)
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers, choosing the subprotocol
+    /// according to the request, and if successful, send any queued
+    /// messages.  The subprotocol given to [`ServerHandshake::new`]
+    /// is ignored.  See [`WebsocketServer::from_http_select`] for
+    /// details of `select`.
+    pub fn accept_select(
+        &mut self,
+        pb: PBufRdWr,
+        select: impl FnOnce(&UpgradeRequest) -> Option<String>,
+    ) -> Result<Option<WebsocketServer>, HandshakeError> {
+        let select = |req: &UpgradeRequest| to_subprotocol(&select(req)?);
+        self.accept_http(pb, select, |_, _, _| (), |_, _| Ok(()))
+    }
+
+    fn accept_http(
+        &mut self,
+        mut pb: PBufRdWr,
+        select: impl FnOnce(&UpgradeRequest) -> Option<WebSocketSubProtocol>,
+        request_cb: impl FnOnce(&str, &str, u8),
+        check: impl FnMut(&str, &[u8]) -> Result<(), u16>,
+    ) -> Result<Option<WebsocketServer>, HandshakeError> {
+        if !ready_to_parse(pb.rd.data(), &mut self.scanned) {
+            return Ok(None);
+        }
+        let ws = WebsocketServer::accept_http(
+            pb.reborrow(),
+            select,
+            self.max_msg_len,
+            self.max_aux_len,
+            self.checks,
+            request_cb,
+            check,
+        )?;
+        if let Some(mut ws) = ws {
+            ws.set_clock(self.clock.take());
+            if let Some(mut observer) = self.observer.take() {
+                observer.handshake_complete(ws.subprotocol());
+                ws.set_observer(Some(observer));
+            }
+            for (msg, data) in self.queue.drain(..) {
+                if let Err(e) = ws.send(pb.reborrow(), msg, true, &data) {
+                    // The peer has already been told that the upgrade
+                    // was accepted, so the connection must be passed
+                    // to the caller to be shut down properly
+                    ws.failure = Some(e);
+                    break;
+                }
+            }
+            Ok(Some(ws))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers, and if successful, send any
+    /// queued messages.  See [`WebsocketServer::from_http_scan`] for
+    /// details of arguments and returns.
+    pub fn accept(&mut self, pb: PBufRdWr) -> Result<Option<WebsocketServer>, HandshakeError> {
+        self.accept_scan(pb, |_, _| ())
+    }
+}
+
+// Test for an HTTP token character (RFC 7230 section 3.2.6)
+pub(crate) fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Test whether it is worth parsing the upgrade request in `data`
+/// yet, i.e. whether the blank line which ends it has arrived.  The
+/// search continues from `*scanned`, which is updated if the end is
+/// not found, so that a request which trickles in a few bytes at a
+/// time is not searched from the start again on every call.  Data
+/// which can't be the start of a request line is passed on to the
+/// parser straight away, so that it is rejected without waiting.
+pub(crate) fn ready_to_parse(data: &[u8], scanned: &mut usize) -> bool {
+    if data.first().is_some_and(|b| !b.is_ascii_alphabetic()) {
+        return true;
+    }
+    // Back up in case the blank line straddles the previous end
+    let rest = &data[(*scanned).min(data.len()).saturating_sub(2)..];
+    let found = rest
+        .iter()
+        .enumerate()
+        .any(|(i, b)| *b == b'\n' && matches!(&rest[i + 1..], [b'\n', ..] | [b'\r', b'\n', ..]));
+    if !found {
+        *scanned = data.len();
+    }
+    found
+}
+
+/// Test whether a request is a websocket upgrade request, by the same
+/// rule as `embedded_websocket::read_http_header`, after
+/// [`normalise`]
+pub(crate) fn is_upgrade(headers: &[httparse::Header]) -> bool {
+    headers
+        .iter()
+        .map(|h| normalise(h.name, h.value))
+        .any(|(name, value)| name == "Upgrade" && value == b"websocket")
+}
+
+/// Give a header the exact spelling that `embedded_websocket`
+/// expects.  It only recognises the usual spelling of the header
+/// names and of the `websocket` token, but both are case-insensitive.
+pub(crate) fn normalise<'a>(name: &'a str, value: &'a [u8]) -> (&'a str, &'a [u8]) {
+    if name.eq_ignore_ascii_case("Upgrade") {
+        match value.eq_ignore_ascii_case(b"websocket") {
+            true => ("Upgrade", b"websocket"),
+            false => ("Upgrade", value),
+        }
+    } else if name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+        ("Sec-WebSocket-Key", value)
+    } else {
+        (name, value)
+    }
+}
+
+/// Optional checks on the upgrade request
+#[derive(Copy, Clone)]
+pub(crate) struct Checks {
+    /// Tolerated faults, including those of the handshake
+    pub quirks: Quirks,
+    /// Reject repeated singleton headers
+    pub strict_headers: bool,
+    /// Length of request body which may be skipped
+    pub skip_body: usize,
+    /// Maximum number of header lines
+    pub max_headers: usize,
+}
+
+impl Default for Checks {
+    fn default() -> Self {
+        Self {
+            quirks: Quirks::default(),
+            strict_headers: false,
+            skip_body: 0,
+            max_headers: 32,
+        }
+    }
+}
+
+/// Test whether a header which may only appear once is repeated
+pub(crate) fn has_duplicates(headers: &[httparse::Header]) -> bool {
+    const SINGLETONS: [&str; 5] = [
+        "Host",
+        "Origin",
+        "Content-Length",
+        "Sec-WebSocket-Key",
+        "Sec-WebSocket-Version",
+    ];
+    SINGLETONS.iter().any(|name| {
+        headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case(name))
+            .count()
+            > 1
+    })
+}
+
+/// Check that there is exactly one `Sec-WebSocket-Key`, and that it
+/// is the base64 encoding of 16 bytes.  With `Quirks::lenient_key`,
+/// any value will do which fits in the 24-byte buffer of
+/// `embedded_websocket`.
+pub(crate) fn key_ok(headers: &[httparse::Header], quirks: Quirks) -> bool {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut keys = headers
+        .iter()
+        .map(|h| normalise(h.name, h.value))
+        .filter(|(name, _)| *name == "Sec-WebSocket-Key");
+    let (Some((_, key)), None) = (keys.next(), keys.next()) else {
+        return false;
+    };
+    if quirks.lenient_key {
+        return key.len() <= 24;
+    }
+    // 16 bytes is 22 base64 digits, the last of which only carries 2
+    // bits, and then 2 padding characters
+    match key {
+        [body @ .., last, b'=', b'='] if body.len() == 21 => {
+            body.iter().all(|b| BASE64.contains(b))
+                && BASE64
+                    .iter()
+                    .position(|b| b == last)
+                    .is_some_and(|i| i % 16 == 0)
+        }
+        _ => false,
+    }
+}
+
+/// Adapt a header callback which can't refuse the connection
+pub(crate) fn no_veto(
+    mut header_cb: impl FnMut(&str, &[u8]),
+) -> impl FnMut(&str, &[u8]) -> Result<(), u16> {
+    move |name, value| {
+        header_cb(name, value);
+        Ok(())
+    }
+}
+
+/// Adapt a header callback which takes values as UTF-8 where
+/// possible
+pub(crate) fn utf8_values(
+    mut header_cb: impl FnMut(&str, Result<&str, &[u8]>),
+) -> impl FnMut(&str, &[u8]) {
+    move |name, value| header_cb(name, std::str::from_utf8(value).map_err(|_| value))
+}
+
+/// Get the standard reason phrase for an HTTP status code which might
+/// be used to reject a request
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Rejected",
+    }
+}
+
+/// Convert a subprotocol name chosen by a `select` callback.  Names
+/// longer than the 24 bytes that `embedded_websocket` allows can't
+/// be selected, so give `None`.
+pub(crate) fn to_subprotocol(name: &str) -> Option<WebSocketSubProtocol> {
+    let mut sp = WebSocketSubProtocol::new();
+    sp.push_str(name).ok()?;
+    Some(sp)
+}
+
+/// Get the length of the body announced by a request, which is 0 if
+/// there is no `Content-Length`.  Returns `None` if the length can't
+/// be known in advance, i.e. with `Transfer-Encoding`, or if a
+/// `Content-Length` can't be parsed or disagrees with another one.
+/// Leading zeros are allowed, as the grammar of RFC 7230 permits.
+pub(crate) fn body_len(headers: &[httparse::Header]) -> Option<u64> {
+    let mut len = None;
+    for h in headers {
+        if h.name.eq_ignore_ascii_case("Transfer-Encoding") {
+            return None;
+        }
+        if h.name.eq_ignore_ascii_case("Content-Length") {
+            let value = h.value.trim_ascii();
+            if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+            let value = std::str::from_utf8(value).ok()?.parse().ok()?;
+            if len.is_some_and(|len| len != value) {
+                return None;
+            }
+            len = Some(value);
+        }
+    }
+    Some(len.unwrap_or(0))
+}
+
+/// Summary of the upgrade request which opened a connection
+///
+/// See [`WebsocketServer::handshake_summary`].  This gives a few
+/// figures about the request which may be logged as a fingerprint of
+/// the client, to help spot unusual clients, without keeping the
+/// request itself.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeSummary {
+    /// Number of header lines in the request
+    pub headers: usize,
+    /// Length of the request in bytes, up to and including the blank
+    /// line which ends it
+    pub request_len: usize,
+    /// Length of the body skipped after the request, see
+    /// [`ServerHandshake::set_skip_body`]
+    pub body_len: usize,
+    /// Whether the client offered any subprotocols
+    pub subprotocols_offered: bool,
+    /// Whether the client offered any extensions
+    pub extensions_offered: bool,
+}
+
+/// Details of a websocket upgrade request, passed to the `select`
+/// callback of [`WebsocketServer::from_http_select`] to allow the
+/// subprotocol to be chosen according to the request
+pub struct UpgradeRequest<'a> {
+    path: &'a str,
+    headers: &'a [httparse::Header<'a>],
+}
+
+impl<'a> UpgradeRequest<'a> {
+    pub(crate) fn new(path: Option<&'a str>, headers: &'a [httparse::Header<'a>]) -> Self {
+        Self {
+            path: path.unwrap_or(""),
+            headers,
+        }
+    }
+
+    /// Get the request target, e.g. `/chat?room=1`
+    pub fn path(&self) -> &'a str {
+        self.path
+    }
+
+    /// Get the value of the first header with the given name,
+    /// ignoring case
+    pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+        self.headers()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Iterate over all the headers as `(field_name, field_value)`
+    pub fn headers(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> + 'a {
+        self.headers.iter().map(|h| (h.name, h.value))
+    }
+
+    /// Iterate over the items of a header which is a comma-separated
+    /// list, such as `Sec-WebSocket-Extensions`, ignoring case in the
+    /// name.  Where the header appears several times, the items of
+    /// all of them are given in order, which is equivalent to joining
+    /// the values with commas, as RFC 7230 requires.  Items are
+    /// trimmed of whitespace, and empty items are skipped.  Note that
+    /// a comma within a quoted string is not recognised as such.
+    pub fn header_list<'b>(&self, name: &'b str) -> impl Iterator<Item = &'a [u8]> + 'b
+    where
+        'a: 'b,
+    {
+        self.headers()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(|b| *b == b','))
+            .map(<[u8]>::trim_ascii)
+            .filter(|item| !item.is_empty())
+    }
+
+    /// Iterate over the cookies sent in `Cookie` headers as `(name,
+    /// value)`.  Browsers send one `Cookie` header with the cookies
+    /// separated by semicolons, but cookies from several headers are
+    /// also handled.  Cookies which are not valid UTF-8 or have no
+    /// `=` are skipped.
+    pub fn cookies(&self) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        self.headers()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, v)| v.split(|b| *b == b';'))
+            .filter_map(|item| std::str::from_utf8(item).ok()?.split_once('='))
+            .map(|(name, value)| (name.trim(), value.trim()))
+    }
+
+    /// Iterate over the subprotocols offered by the client in
+    /// `Sec-WebSocket-Protocol` headers, in order of preference.
+    /// Items which are not valid UTF-8 are skipped.
+    pub fn subprotocols(&self) -> impl Iterator<Item = &'a str> + 'a {
+        self.header_list("Sec-WebSocket-Protocol")
+            .filter_map(|item| std::str::from_utf8(item).ok())
+    }
+}
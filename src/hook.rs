@@ -0,0 +1,70 @@
+use crate::Error;
+
+/// Direction of a frame passed to a [`FrameHook`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Data received from the peer
+    Incoming,
+    /// Data about to be sent to the peer
+    Outgoing,
+}
+
+/// Hook to inspect or rewrite data frame payloads
+///
+/// See [`WebsocketServer::set_frame_hook`].  The arguments are the
+/// direction, `true` for a text message or `false` for binary, and a
+/// copy of the payload data, which may be modified in place or
+/// replaced with data of a different length.
+///
+/// [`WebsocketServer::set_frame_hook`]: crate::WebsocketServer::set_frame_hook
+pub type FrameHook = Box<dyn FnMut(Direction, bool, &mut Vec<u8>) + Send>;
+
+/// Details of the data passed to a [`WsExtension`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ExtFrame {
+    /// `true` for a text message, or `false` for binary
+    pub is_text: bool,
+    /// RSV1, RSV2 and RSV3 bits of the frame, in the low 3 bits as
+    /// for `FrameHeader::rsv`.  For an outgoing frame these start as
+    /// zero, and the extension sets the bits it requires.
+    pub rsv: u8,
+    /// The data is the start of a message
+    pub start: bool,
+    /// The data is the end of a message
+    pub end: bool,
+}
+
+/// Extension which uses the reserved bits of data frames and
+/// transforms their payloads
+///
+/// See [`WebsocketServer::set_extension`].  This is the hook for
+/// extensions negotiated with `Sec-WebSocket-Extensions`, such as
+/// permessage-deflate, once the caller has agreed the parameters in
+/// the handshake.  Only the data of text and binary frames passes
+/// through the extension.  Control frames are never transformed, and
+/// must not have their reserved bits set.
+///
+/// [`WebsocketServer::set_extension`]: crate::WebsocketServer::set_extension
+pub trait WsExtension: Send {
+    /// Get the reserved bits which this extension may use, in the
+    /// low 3 bits as for `FrameHeader::rsv`, e.g. `4` for RSV1.  An
+    /// incoming data frame with any other reserved bit set is still a
+    /// protocol violation.
+    fn rsv_bits(&self) -> u8;
+
+    /// Transform the payload of an outgoing data frame in place, and
+    /// set its reserved bits in `frame.rsv`.  This is called once for
+    /// each frame sent, so a message may be split across several
+    /// calls.  The bits set must be amongst those of
+    /// [`WsExtension::rsv_bits`].
+    fn outgoing(&mut self, frame: &mut ExtFrame, data: &mut Vec<u8>);
+
+    /// Transform incoming payload data in place, given the reserved
+    /// bits of the frame it arrived in.  Data is passed on as it
+    /// arrives, so a single frame may be split across several calls.
+    /// The final call for a message has `frame.end` set, even if
+    /// there is no data left.  If this fails, the connection is
+    /// closed with code 1007 (invalid payload data) and the error is
+    /// returned.
+    fn incoming(&mut self, frame: &ExtFrame, data: &mut Vec<u8>) -> Result<(), Error>;
+}
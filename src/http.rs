@@ -0,0 +1,190 @@
+use crate::handshake::is_token;
+use httparse::{Error, Header, Status};
+
+/// Minimal parser for the HTTP upgrade request
+///
+/// This is used instead of `httparse::Request` with the `mini-http`
+/// feature, and has the same interface for the parts used by the
+/// handshake.  It handles only what a websocket upgrade request
+/// needs: the request line and simple `name: value` header lines.
+/// Obsolete line folding is rejected.  This is less code than
+/// `httparse`, which is of interest on microcontrollers.
+///
+/// The headers are stored in `httparse::Header` slots and errors are
+/// reported as `httparse` errors, since `httparse` is needed by
+/// `embedded_websocket` anyway.  That way the rest of the handshake,
+/// the errors returned and the limit on the number of headers set
+/// with `ServerHandshake::set_max_headers` are the same whichever
+/// parser is used.
+pub(crate) struct Request<'h, 'b> {
+    pub method: Option<&'b str>,
+    pub path: Option<&'b str>,
+    pub version: Option<u8>,
+    pub headers: &'h mut [Header<'b>],
+}
+
+impl<'h, 'b> Request<'h, 'b> {
+    pub fn new(headers: &'h mut [Header<'b>]) -> Self {
+        Self {
+            method: None,
+            path: None,
+            version: None,
+            headers,
+        }
+    }
+
+    /// Parse the request from the start of `data`.  Returns
+    /// `Status::Complete(len)` with the length of the request
+    /// including the blank line which ends it, or `Status::Partial`
+    /// if more data is required.
+    pub fn parse(&mut self, data: &'b [u8]) -> Result<Status<usize>, Error> {
+        let mut pos = 0;
+        let Some(line) = next_line(data, &mut pos)? else {
+            return Ok(Status::Partial);
+        };
+        let mut parts = line.split(|b| *b == b' ');
+        let (Some(method), Some(path), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Error::Token);
+        };
+        if method.is_empty() || !method.iter().all(|b| is_token(*b)) {
+            return Err(Error::Token);
+        }
+        if path.is_empty() || !path.iter().all(|b| b.is_ascii_graphic()) {
+            return Err(Error::Token);
+        }
+        let version = match version {
+            b"HTTP/1.0" => 0,
+            b"HTTP/1.1" => 1,
+            _ => return Err(Error::Version),
+        };
+
+        let headers = std::mem::take(&mut self.headers);
+        let mut count = 0;
+        loop {
+            let Some(line) = next_line(data, &mut pos)? else {
+                return Ok(Status::Partial);
+            };
+            if line.is_empty() {
+                break;
+            }
+            let colon = line
+                .iter()
+                .position(|b| *b == b':')
+                .ok_or(Error::HeaderName)?;
+            let name = &line[..colon];
+            if name.is_empty() || !name.iter().all(|b| is_token(*b)) {
+                return Err(Error::HeaderName);
+            }
+            let value = line[colon + 1..].trim_ascii();
+            if value.iter().any(|b| b.is_ascii_control() && *b != b'\t') {
+                return Err(Error::HeaderValue);
+            }
+            let slot = headers.get_mut(count).ok_or(Error::TooManyHeaders)?;
+            // Token characters are all ASCII
+            slot.name = std::str::from_utf8(name).map_err(|_| Error::HeaderName)?;
+            slot.value = value;
+            count += 1;
+        }
+        // Token and path characters are all ASCII
+        self.method = std::str::from_utf8(method).ok();
+        self.path = std::str::from_utf8(path).ok();
+        self.version = Some(version);
+        self.headers = &mut headers[..count];
+        Ok(Status::Complete(pos))
+    }
+}
+
+// Get the next line, ending in CRLF or a bare LF, or `None` if the
+// line is not yet complete
+fn next_line<'b>(data: &'b [u8], pos: &mut usize) -> Result<Option<&'b [u8]>, Error> {
+    let start = *pos;
+    let Some(len) = data[start..].iter().position(|b| *b == b'\n') else {
+        return Ok(None);
+    };
+    *pos = start + len + 1;
+    let line = &data[start..start + len];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.contains(&b'\r') {
+        return Err(Error::NewLine);
+    }
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httparse::EMPTY_HEADER;
+
+    const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\nHost: x\r\nUpgrade:  websocket \r\n\r\n";
+
+    fn parse(data: &[u8], slots: usize) -> Result<Status<usize>, Error> {
+        let mut headers = vec![EMPTY_HEADER; slots];
+        Request::new(&mut headers).parse(data)
+    }
+
+    #[test]
+    fn complete_request() {
+        let mut headers = [EMPTY_HEADER; 4];
+        let mut req = Request::new(&mut headers);
+        assert_eq!(req.parse(REQUEST), Ok(Status::Complete(REQUEST.len())));
+        assert_eq!(req.method, Some("GET"));
+        assert_eq!(req.path, Some("/chat"));
+        assert_eq!(req.version, Some(1));
+        assert_eq!(req.headers.len(), 2);
+        assert_eq!(req.headers[1].name, "Upgrade");
+        assert_eq!(req.headers[1].value, b"websocket");
+    }
+
+    #[test]
+    fn partial_request() {
+        for len in 0..REQUEST.len() {
+            assert_eq!(parse(&REQUEST[..len], 4), Ok(Status::Partial), "{len}");
+        }
+        // Anything after the blank line is left for the caller
+        let data = [REQUEST, b"\x81\x00"].concat();
+        assert_eq!(parse(&data, 4), Ok(Status::Complete(REQUEST.len())));
+    }
+
+    #[test]
+    fn too_many_headers() {
+        assert_eq!(parse(REQUEST, 1), Err(Error::TooManyHeaders));
+        assert_eq!(parse(REQUEST, 2), Ok(Status::Complete(REQUEST.len())));
+    }
+
+    #[test]
+    fn obs_fold_is_rejected() {
+        for fold in [&b" more"[..], b"\tmore", b" X-Folded: more"] {
+            let data = [&b"GET / HTTP/1.1\r\nHost: x\r\n"[..], fold, b"\r\n\r\n"].concat();
+            assert_eq!(parse(&data, 4), Err(Error::HeaderName));
+        }
+    }
+
+    #[test]
+    fn malformed_request_line_and_headers() {
+        let cases: [(&[u8], Error); 6] = [
+            (b"GET /\r\n\r\n", Error::Token),
+            (b"GET / HTTP/1.1 x\r\n\r\n", Error::Token),
+            (b"G(T / HTTP/1.1\r\n\r\n", Error::Token),
+            (b"GET / HTTP/2\r\n\r\n", Error::Version),
+            (b"GET / HTTP/1.1\r\nHost x\r\n\r\n", Error::HeaderName),
+            (
+                b"GET / HTTP/1.1\r\nHost: a\x01b\r\n\r\n",
+                Error::HeaderValue,
+            ),
+        ];
+        for (data, err) in cases {
+            assert_eq!(
+                parse(data, 4),
+                Err(err),
+                "{:?}",
+                String::from_utf8_lossy(data)
+            );
+        }
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nHost: a\rb\r\n\r\n", 4),
+            Err(Error::NewLine)
+        );
+    }
+}
@@ -0,0 +1,25 @@
+use crate::{Error, WebsocketServer};
+use pipebuf::PBufRdWr;
+use serde::{de::DeserializeOwned, Serialize};
+
+impl WebsocketServer {
+    /// Serialize a value as JSON and send it as an unfragmented text
+    /// message.  Returns `Error::Unknown` if the value can't be
+    /// serialized, e.g. a map with non-string keys.
+    pub fn send_json<T: Serialize + ?Sized>(
+        &mut self,
+        pb: PBufRdWr,
+        value: &T,
+    ) -> Result<(), Error> {
+        let text = serde_json::to_string(value).map_err(|_| Error::Unknown)?;
+        self.send_text(pb, &text)
+    }
+}
+
+/// Parse a complete message received from the peer as JSON.  This
+/// accepts binary messages as well as text messages.  Invalid UTF-8
+/// is reported as an error, so text messages don't need to be
+/// checked first.
+pub fn parse_json<T: DeserializeOwned>(message: &[u8]) -> Result<T, serde_json::Error> {
+    serde_json::from_slice(message)
+}
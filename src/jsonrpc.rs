@@ -0,0 +1,272 @@
+use crate::{Error, WebsocketServer};
+use pipebuf::PBufRdWr;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Error object of a JSON-RPC 2.0 response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RpcError {
+    /// Error code.  The range -32768 to -32000 is reserved by the
+    /// specification.
+    pub code: i64,
+    /// Short description of the error
+    pub message: String,
+    /// Additional information about the error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    /// Invalid JSON was received
+    pub const PARSE_ERROR: i64 = -32700;
+    /// The JSON received is not a valid JSON-RPC message
+    pub const INVALID_REQUEST: i64 = -32600;
+    /// The method does not exist
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    /// Invalid method parameters
+    pub const INVALID_PARAMS: i64 = -32602;
+    /// Internal JSON-RPC error
+    pub const INTERNAL_ERROR: i64 = -32603;
+
+    /// Create an error with no additional data
+    pub fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+/// A JSON-RPC 2.0 message
+///
+/// Each complete text message carries either a single JSON-RPC
+/// message, or a batch of them as a JSON array.  Decode with
+/// [`RpcMessage::parse_batch`], and send with [`RpcMessage::send`] or
+/// [`RpcMessage::send_batch`].  For making calls to the peer and
+/// matching up the responses, see [`RpcClient`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum RpcMessage {
+    /// Call which expects a response with the same `id`
+    Request {
+        id: Value,
+        method: String,
+        params: Option<Value>,
+    },
+    /// Call which expects no response
+    Notification {
+        method: String,
+        params: Option<Value>,
+    },
+    /// Result or error in response to a `Request`.  The `id` is
+    /// `null` if the request was so broken that its `id` could not be
+    /// determined.
+    Response {
+        id: Value,
+        result: Result<Value, RpcError>,
+    },
+}
+
+impl RpcMessage {
+    /// Decode a complete incoming message, which may be a single
+    /// JSON-RPC message or a batch.  On failure, returns an error
+    /// suitable for sending back to the peer as a response with a
+    /// `null` id: [`RpcError::PARSE_ERROR`] for invalid JSON, or
+    /// [`RpcError::INVALID_REQUEST`] if the JSON isn't valid
+    /// JSON-RPC.  If any member of a batch is invalid, the whole
+    /// batch is rejected.
+    pub fn parse_batch(message: &[u8]) -> Result<Vec<Self>, RpcError> {
+        let value: Value = serde_json::from_slice(message)
+            .map_err(|_| RpcError::new(RpcError::PARSE_ERROR, "Parse error"))?;
+        match value {
+            Value::Array(list) if list.is_empty() => Err(invalid_request()),
+            Value::Array(list) => list.into_iter().map(Self::from_value).collect(),
+            value => Ok(vec![Self::from_value(value)?]),
+        }
+    }
+
+    /// Convert JSON into a message, checking that it is valid
+    /// JSON-RPC 2.0
+    pub fn from_value(value: Value) -> Result<Self, RpcError> {
+        let Value::Object(mut obj) = value else {
+            return Err(invalid_request());
+        };
+        if obj.remove("jsonrpc").as_ref().and_then(Value::as_str) != Some("2.0") {
+            return Err(invalid_request());
+        }
+        let id = obj.remove("id");
+        if !matches!(
+            id,
+            None | Some(Value::Null | Value::Number(_) | Value::String(_))
+        ) {
+            return Err(invalid_request());
+        }
+        if let Some(method) = obj.remove("method") {
+            let Value::String(method) = method else {
+                return Err(invalid_request());
+            };
+            let params = obj.remove("params");
+            if !matches!(params, None | Some(Value::Array(_) | Value::Object(_))) {
+                return Err(invalid_request());
+            }
+            return Ok(match id {
+                Some(id) => Self::Request { id, method, params },
+                None => Self::Notification { method, params },
+            });
+        }
+        let id = id.ok_or_else(invalid_request)?;
+        let result = match (obj.remove("result"), obj.remove("error")) {
+            (Some(result), None) => Ok(result),
+            (None, Some(error)) => {
+                Err(serde_json::from_value(error).map_err(|_| invalid_request())?)
+            }
+            _ => return Err(invalid_request()),
+        };
+        Ok(Self::Response { id, result })
+    }
+
+    /// Convert the message to JSON
+    pub fn to_value(&self) -> Value {
+        let mut obj = Map::new();
+        obj.insert("jsonrpc".into(), "2.0".into());
+        match self {
+            Self::Request { id, method, params } => {
+                obj.insert("id".into(), id.clone());
+                obj.insert("method".into(), method.as_str().into());
+                if let Some(params) = params {
+                    obj.insert("params".into(), params.clone());
+                }
+            }
+            Self::Notification { method, params } => {
+                obj.insert("method".into(), method.as_str().into());
+                if let Some(params) = params {
+                    obj.insert("params".into(), params.clone());
+                }
+            }
+            Self::Response { id, result } => {
+                obj.insert("id".into(), id.clone());
+                match result {
+                    Ok(result) => obj.insert("result".into(), result.clone()),
+                    Err(error) => obj.insert("error".into(), serde_json::json!(error)),
+                };
+            }
+        }
+        Value::Object(obj)
+    }
+
+    /// Send the message as a text message
+    pub fn send(&self, ws: &mut WebsocketServer, pb: PBufRdWr) -> Result<(), Error> {
+        ws.send_json(pb, &self.to_value())
+    }
+
+    /// Send several messages as a batch in a single text message
+    pub fn send_batch(batch: &[Self], ws: &mut WebsocketServer, pb: PBufRdWr) -> Result<(), Error> {
+        let list: Vec<Value> = batch.iter().map(Self::to_value).collect();
+        ws.send_json(pb, &list)
+    }
+}
+
+fn invalid_request() -> RpcError {
+    RpcError::new(RpcError::INVALID_REQUEST, "Invalid Request")
+}
+
+/// Response matched to the call made with [`RpcClient::call`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcReply {
+    /// ID returned by [`RpcClient::call`]
+    pub id: u64,
+    /// Method which was called
+    pub method: String,
+    /// Result or error sent by the peer
+    pub result: Result<Value, RpcError>,
+}
+
+/// Calling side of JSON-RPC, matching responses to calls
+///
+/// Here "client" means the JSON-RPC role, i.e. the side making the
+/// calls, which may be either end of the websocket.  Calls are given
+/// increasing numeric IDs, and a record is kept of calls awaiting a
+/// response.  Pass incoming messages to [`RpcClient::handle`] to pick
+/// out the responses.
+#[derive(Default)]
+pub struct RpcClient {
+    next_id: u64,
+    pending: HashMap<u64, String>,
+}
+
+impl RpcClient {
+    /// Create a client with no calls outstanding
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a request, returning the ID which will identify the
+    /// response
+    pub fn call(
+        &mut self,
+        ws: &mut WebsocketServer,
+        pb: PBufRdWr,
+        method: &str,
+        params: Option<Value>,
+    ) -> Result<u64, Error> {
+        let id = self.next_id;
+        RpcMessage::Request {
+            id: id.into(),
+            method: method.to_string(),
+            params,
+        }
+        .send(ws, pb)?;
+        self.next_id += 1;
+        self.pending.insert(id, method.to_string());
+        Ok(id)
+    }
+
+    /// Send a notification, which gets no response
+    pub fn notify(
+        &mut self,
+        ws: &mut WebsocketServer,
+        pb: PBufRdWr,
+        method: &str,
+        params: Option<Value>,
+    ) -> Result<(), Error> {
+        RpcMessage::Notification {
+            method: method.to_string(),
+            params,
+        }
+        .send(ws, pb)
+    }
+
+    /// Match an incoming message to an outstanding call.  If it is
+    /// the response to one, the call is no longer outstanding and
+    /// `Ok(reply)` is returned.  Otherwise the message is handed back
+    /// as `Err(msg)`.  That may be a request or notification from the
+    /// peer, or a response that doesn't match any call, e.g. an error
+    /// response with a `null` id.
+    pub fn handle(&mut self, msg: RpcMessage) -> Result<RpcReply, RpcMessage> {
+        match msg {
+            RpcMessage::Response { id, result } => {
+                let call = id
+                    .as_u64()
+                    .and_then(|n| Some((n, self.pending.remove(&n)?)));
+                match call {
+                    Some((id, method)) => Ok(RpcReply { id, method, result }),
+                    None => Err(RpcMessage::Response { id, result }),
+                }
+            }
+            msg => Err(msg),
+        }
+    }
+
+    /// Forget an outstanding call, e.g. after a timeout.  A response
+    /// arriving later will be handed back by [`RpcClient::handle`] as
+    /// unmatched.  Returns `false` if the call was not outstanding.
+    pub fn cancel(&mut self, id: u64) -> bool {
+        self.pending.remove(&id).is_some()
+    }
+
+    /// Get the number of calls awaiting a response
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
@@ -14,24 +14,195 @@
 //! On the sending side, a "push" is indicated after each message
 //! sent.
 //!
-//! TODO: Support client-side with a `WebsocketClient` wrapper.
-//! (Similar to existing code but would need testing.)
+//! To queue up messages before the handshake has completed, use
+//! [`ServerHandshake`].  To send the same message to many
+//! connections, use [`Broadcaster`].  For servers handling many
+//! clients, [`WsConnections`] may be used to keep track of them.
+//!
+//! With the `serde` feature, connection state may be saved with
+//! `WebsocketServer::snapshot` and restored in another process with
+//! `WebsocketServer::from_snapshot`.
+//!
+//! With the `mux` feature, `Mux` allows many logical channels to
+//! share one websocket.
+//!
+//! With the `json` feature, values may be sent as JSON text messages
+//! with `WebsocketServer::send_json`, and complete messages decoded
+//! with `parse_json`.  Building on that, the `graphql-ws` feature
+//! provides `GraphqlMessage` for the `graphql-transport-ws`
+//! subprotocol, and the `jsonrpc` feature provides `RpcMessage` and
+//! `RpcClient` for JSON-RPC 2.0.
+//!
+//! With the `mini-http` feature, the upgrade request is parsed by a
+//! minimal built-in parser instead of `httparse`, which gives
+//! smaller code for microcontrollers.
+//!
+//! With the `mqtt` feature, `MqttStream` presents the binary
+//! messages of an MQTT-over-websocket connection as a single byte
+//! stream for an MQTT codec.  With the `stomp` feature,
+//! `StompFrame` and `parse_stomp` handle STOMP 1.2 framing.  With
+//! the `engineio` feature, `EioPacket` handles the Engine.IO packet
+//! framing used by Socket.IO.
+//!
+//! For the client side, the upgrade request is written with
+//! [`write_client_handshake`] and the response checked with
+//! [`read_client_handshake`], after which [`WebsocketClient`]
+//! handles the connection, sharing the receive and send code of the
+//! server.  With the `socks5` feature, `Socks5Connect` may be used
+//! to reach the server through a SOCKS5 proxy before the upgrade
+//! request.  [`WsEndpoint`] allows clients and servers to be held
+//! together as trait objects.  Both ends support keepalive with
+//! [`WebsocketServer::set_keepalive`], taking the time from a
+//! [`WsClock`] like the other time-based features.
 //!
 //! TODO: Rewrite this as a native PipeBuf-based websocket
-//! implementation that for Ping/Pong/Close consumes only whole frames
-//! (with limits), to simplify things.  The message content can still
-//! be streamed, though.  Also see [Autobahn
+//! implementation.  Frames are already parsed and encoded natively,
+//! because `embedded_websocket` mishandles frames received in
+//! several parts, and control frames sent within a fragmented
+//! message, but the connection state and the server's handshake
+//! still come from `embedded_websocket`.  Also see [Autobahn
 //! testsuite](https://github.com/crossbario/autobahn-testsuite).
+//! Once that is done, a `native-only` feature could compile out both
+//! `embedded_websocket` and `httparse`, for embedded users who want
+//! a smaller dependency tree and code size.  The handshake would
+//! then need its own parser for the upgrade request, and the
+//! `Sec-WebSocket-Accept` calculation would need a SHA-1.
+//!
+//! TODO: Support the permessage-deflate extension (RFC 7692).  The
+//! RSV1 bit and the payload transform are available to a
+//! [`WsExtension`], and a client may offer the extension with
+//! `ClientConfig::extensions` and see the parameters accepted by the
+//! server in `UpgradeResponse::extensions`, such as
+//! `server_no_context_takeover` and `client_max_window_bits`, so
+//! what is missing is the compression itself.  Sends should then
+//! allow compression to be skipped per message, e.g. for data which
+//! is already compressed, and compressed versus uncompressed byte
+//! counts should be available to check that the extension is
+//! worthwhile.
+//! Inflated data must be checked against `max_msg_len` as it is
+//! produced, and there should also be limits on the inflation ratio
+//! and on the decompressed size of each message, so that a small
+//! "zip bomb" frame can't force a large allocation.
 //!
 //! [embedded-websocket]: https://crates.io/crates/embedded-websocket
 //! [`PipeBuf`]: https://crates.io/crates/pipebuf
 
 use embedded_websocket as ws;
+use events::EventLog;
+use frame::Role;
 use httparse::Status;
-use pipebuf::{PBufRdWr, PBufWr};
+use ratelimit::RateLimiter;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
 use ws::WebSocketReceiveMessageType as RxMsgType;
 use ws::WebSocketSendMessageType as TxMsgType;
-use ws::{WebSocketSendMessageType, WebSocketServer, WebSocketSubProtocol};
+
+// Number of outstanding `Ping`s remembered for matching `Pong`s
+const MAX_PINGS_OUT: usize = 16;
+
+// Maximum space reserved for an incoming message beyond the data
+// which has already arrived
+const PRESIZE_AHEAD: usize = 65536;
+
+mod broadcast;
+mod client;
+mod clock;
+mod close;
+mod connections;
+mod crc32;
+mod endpoint;
+#[cfg(feature = "engineio")]
+mod engineio;
+mod error;
+mod events;
+mod frame;
+#[cfg(feature = "graphql-ws")]
+mod graphql_ws;
+mod handshake;
+mod hook;
+#[cfg(feature = "mini-http")]
+mod http;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "jsonrpc")]
+mod jsonrpc;
+mod metrics;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "mux")]
+mod mux;
+mod observer;
+mod ping;
+mod quirks;
+mod ratelimit;
+mod sink;
+#[cfg(feature = "serde")]
+mod snapshot;
+#[cfg(feature = "socks5")]
+mod socks5;
+mod split;
+#[cfg(feature = "stomp")]
+mod stomp;
+mod violation;
+pub use broadcast::Broadcaster;
+pub use client::read_client_handshake_scan;
+pub use client::UpgradeResponse;
+pub use client::WebsocketClient;
+pub use client::{read_client_handshake, read_client_handshake_cookies};
+pub use client::{write_client_handshake, write_client_handshake_entropy};
+pub use client::{ClientConfig, ClientHandshakeError, HandshakeKey, RejectedResponse};
+pub use clock::{StdClock, WsClock};
+pub use close::CloseInfo;
+pub use connections::{WsConnection, WsConnections};
+pub use endpoint::WsEndpoint;
+#[cfg(feature = "engineio")]
+pub use engineio::EioPacket;
+pub use error::Error;
+pub use events::WsEvent;
+pub use frame::FrameHeader;
+#[cfg(feature = "graphql-ws")]
+pub use graphql_ws::GRAPHQL_CLOSE_UNAUTHORIZED;
+#[cfg(feature = "graphql-ws")]
+pub use graphql_ws::{GraphqlMessage, GraphqlRequest, GRAPHQL_TRANSPORT_WS_SUBPROTOCOL};
+#[cfg(feature = "graphql-ws")]
+pub use graphql_ws::{GRAPHQL_CLOSE_INIT_TIMEOUT, GRAPHQL_CLOSE_INVALID_MESSAGE};
+#[cfg(feature = "graphql-ws")]
+pub use graphql_ws::{GRAPHQL_CLOSE_SUBSCRIBER_EXISTS, GRAPHQL_CLOSE_TOO_MANY_INIT};
+pub use handshake::{HandshakeError, HandshakeSummary, ServerHandshake, UpgradeRequest};
+pub use hook::{Direction, ExtFrame, FrameHook, WsExtension};
+#[cfg(feature = "json")]
+pub use json::parse_json;
+#[cfg(feature = "jsonrpc")]
+pub use jsonrpc::{RpcClient, RpcError, RpcMessage, RpcReply};
+pub use metrics::WsMetrics;
+#[cfg(feature = "mqtt")]
+pub use mqtt::{MqttStream, MQTT_SUBPROTOCOL};
+#[cfg(feature = "mux")]
+pub use mux::{Mux, MuxEvent, MUX_MAX_BUFFERED, MUX_MAX_CHANNELS, MUX_SUBPROTOCOL};
+pub use observer::WsObserver;
+pub use ping::PingStats;
+pub use quirks::Quirks;
+pub use ratelimit::{Budget, RateLimit, RateLimitAction};
+pub use sink::{WsServerSink, WsSink};
+#[cfg(feature = "serde")]
+pub use snapshot::WsSnapshot;
+#[cfg(feature = "socks5")]
+pub use socks5::{Socks5Connect, Socks5Error};
+pub use split::{WsReceiver, WsSender};
+#[cfg(feature = "stomp")]
+pub use stomp::{parse_stomp, StompFrame, STOMP_SUBPROTOCOL};
+pub use violation::Violation;
+
+// Re-exports so that users don't need to depend on matching versions
+// of these crates themselves
+pub use embedded_websocket;
+pub use pipebuf;
+pub use pipebuf::{PBufRd, PBufRdWr, PBufState, PBufTrip, PBufWr, PipeBuf, PipeBufPair};
+#[cfg(feature = "json")]
+pub use serde_json;
+pub use ws::{WebSocketCloseStatusCode, WebSocketSendMessageType};
+pub use ws::{WebSocketServer, WebSocketState, WebSocketSubProtocol};
 
 /// Wraps an [`embedded_websocket::WebSocketServer`]
 ///
@@ -39,9 +210,237 @@ use ws::{WebSocketSendMessageType, WebSocketServer, WebSocketSubProtocol};
 /// https://docs.rs/embedded-websocket/0.8.0/embedded_websocket/type.WebSocketServer.html
 pub struct WebsocketServer {
     ws: ws::WebSocketServer,
-    in_data: Vec<u8>,
+    role: Role,
     max_msg_len: usize,
     max_aux_len: usize,
+    tx_fragmented: bool,
+    // Bytes of the current incoming frame not yet passed to `ws`
+    rx_remaining: u64,
+    // Bytes consumed from `pb.rd`, and frame headers consumed
+    rx_offset: u64,
+    rx_frames: u64,
+    error_position: Option<StreamPosition>,
+    // First error which left the connection unusable
+    failure: Option<Error>,
+    tx_bytes: u64,
+    tx_frames: u64,
+    // State of the generator of masking keys, for a client
+    tx_mask: [u64; 2],
+    // Details of the current incoming frame
+    rx_opcode: u8,
+    rx_type: RxMsgType,
+    rx_fin: bool,
+    rx_rsv: u8,
+    rx_mask: Option<[u8; 4]>,
+    // Offset within the payload of the current incoming frame
+    rx_pos: u64,
+    // `Some(is_text)` if `ws` is between fragments of a message
+    rx_fragment: Option<bool>,
+    rx_message: Option<PartialMessage>,
+    // Discard the rest of the current incoming message
+    rx_discard: bool,
+    policy: MessagePolicy,
+    rate_limit: Option<RateLimiter>,
+    budget: Option<Budget>,
+    // Incoming data messages started
+    rx_messages: u64,
+    violations: u32,
+    violation_tolerance: Option<u32>,
+    quirks: Quirks,
+    truncate_close_reason: bool,
+    max_frame_len: Option<usize>,
+    push_fragments: bool,
+    push_replies: bool,
+    read_quota: Option<usize>,
+    watermarks: Option<(usize, usize)>,
+    backlogged: bool,
+    drain_on_close: bool,
+    close_sent_at: Option<Duration>,
+    // `pb.rd` EOF has been seen and consumed
+    rx_eof: bool,
+    // `pb.wr` was found closed whilst the connection was open
+    output_ended: bool,
+    close_on_input_eof: bool,
+    last_close: Option<CloseInfo>,
+    events: Option<EventLog>,
+    frame_hook: Option<FrameHook>,
+    extension: Option<Box<dyn WsExtension>>,
+    metrics: Option<Arc<dyn WsMetrics>>,
+    clock: Option<Arc<dyn WsClock>>,
+    observer: Option<Box<dyn WsObserver>>,
+    ping_stats: PingStats,
+    // Sequence number of the next `Pong` expected
+    pong_next: u64,
+    // Payloads of `Ping`s sent and not yet answered, oldest first
+    pings_out: VecDeque<Vec<u8>>,
+    unmatched_pongs: u64,
+    // Ping interval and reply timeout, if keepalive is enabled
+    keepalive: Option<(Duration, Duration)>,
+    // Sequence number and time of the last keepalive `Ping`, until
+    // answered
+    keepalive_ping: Option<(u64, Duration)>,
+    // Time of the last incoming data seen, and whether there has
+    // been any since, when there is no registered clock
+    last_rx: Option<Duration>,
+    rx_active: bool,
+    // CRC-32 of the incoming message so far, if enabled, and of the
+    // last complete message
+    rx_crc: Option<u32>,
+    message_crc: Option<u32>,
+    // Length of the outgoing message so far, for `metrics`
+    tx_msg_len: u64,
+    hook_buf: Vec<u8>,
+    ext_buf: Vec<u8>,
+    // Message buffer used by `messages()`
+    rx_buf: PipeBuf,
+    subprotocol: Option<WebSocketSubProtocol>,
+    handshake_summary: Option<HandshakeSummary>,
+}
+
+/// State of the underlying transport streams
+///
+/// See [`WebsocketServer::transport_state`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TransportState {
+    /// Both directions are open
+    Open,
+    /// The input stream `pb.rd` has ended, but `pb.wr` is still open
+    InputEnded,
+    /// The output stream `pb.wr` has been closed or aborted, but
+    /// input may still arrive
+    OutputEnded,
+    /// Both directions have ended
+    Closed,
+}
+
+/// State of a connection
+///
+/// See [`WebsocketServer::state`].  This follows the protocol state
+/// of the wrapped [`WebSocketServer`], except that a connection which
+/// has been shut down by a fatal error is reported as `Failed`
+/// rather than as closing or closed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The opening handshake has not completed
+    Connecting,
+    /// Messages may be sent and received
+    Open,
+    /// A `Close` has been sent, and the peer's reply is awaited
+    CloseSent,
+    /// The peer has sent a `Close`, which has not yet been answered
+    CloseReceived,
+    /// The closing handshake has completed
+    Closed,
+    /// A fatal error has occurred, see [`WebsocketServer::failure`].
+    /// Further calls fail with `Error::ConnectionFailed`.
+    Failed,
+}
+
+/// Change in the outgoing backlog
+///
+/// See [`WebsocketServer::check_backlog`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backlog {
+    /// The unread data in the outgoing pipe-buffer has risen above
+    /// the high watermark, i.e. the client is not keeping up
+    High,
+    /// The unread data has fallen back to the low watermark or below
+    Low,
+}
+
+/// Position in the incoming stream
+///
+/// See [`WebsocketServer::error_position`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StreamPosition {
+    /// Byte offset from the start of the websocket stream, i.e. from
+    /// the end of the HTTP handshake
+    pub offset: u64,
+    /// Index of the frame, counting from 0 for the first frame after
+    /// the handshake
+    pub frame: u64,
+}
+
+/// Frame and byte counts in each direction
+///
+/// See [`WebsocketServer::counters`].  Byte counts include frame
+/// headers, but not the HTTP handshake.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Frames received.  A frame is counted as soon as its header
+    /// has been consumed.
+    pub rx_frames: u64,
+    /// Bytes consumed from `pb.rd`
+    pub rx_bytes: u64,
+    /// Frames sent, including control frames
+    pub tx_frames: u64,
+    /// Bytes written to `pb.wr`
+    pub tx_bytes: u64,
+}
+
+/// Tripwire value to detect whether a connection made progress
+///
+/// See [`WebsocketServer::tripwire`] and [`WsConnection::tripwire`].
+/// This combines the [`PBufTrip`] values of the pipe-buffers that a
+/// connection works with.  Save a value before a call, and compare
+/// it with the value afterwards.  If they differ, then the call
+/// changed something, and the other components sharing those
+/// pipe-buffers should be run.  Since this is cheap, it may be used
+/// where the `bool` returned from a call doesn't cover everything
+/// of interest, e.g. replies written by
+/// [`WebsocketServer::receive`].
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct WsTrip {
+    rd: PBufTrip,
+    wr: PBufTrip,
+    message: Option<PBufTrip>,
+}
+
+/// Which types of data message are accepted
+///
+/// See [`WebsocketServer::set_message_policy`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MessagePolicy {
+    /// Accept both text and binary messages
+    #[default]
+    Both,
+    /// Accept only text messages
+    TextOnly,
+    /// Accept only binary messages
+    BinaryOnly,
+}
+
+impl MessagePolicy {
+    fn allows(self, is_text: bool) -> bool {
+        match self {
+            Self::Both => true,
+            Self::TextOnly => is_text,
+            Self::BinaryOnly => !is_text,
+        }
+    }
+}
+
+/// Complete message received from the peer
+///
+/// See [`WebsocketServer::messages`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// `true` for a text message, `false` for binary
+    pub is_text: bool,
+    /// Message data
+    pub data: Vec<u8>,
+}
+
+/// Details of a message which is part-way through being received
+///
+/// See [`WebsocketServer::partial_message`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PartialMessage {
+    /// `true` for a text message, `false` for binary
+    pub is_text: bool,
+    /// Number of bytes of message data received so far
+    pub len: u64,
 }
 
 impl WebsocketServer {
@@ -54,17 +453,29 @@ impl WebsocketServer {
     /// - `Ok(None)` if more data is required
     ///
     /// - `Ok(Some(Self))` if valid HTTP websocket headers were found
-    /// and consumed and the websocket is now ready.  A protocol reply
-    /// will have been sent back on `pb.wr`.
+    ///   and consumed and the websocket is now ready.  A protocol reply
+    ///   will have been sent back on `pb.wr`.
     ///
     /// - `Err(_)` if the HTTP headers are invalid, or contain invalid
-    /// data for a websocket stream.  All the initial data will be
-    /// left unconsumed in the pipe buffer in case it can be
-    /// interpreted as another protocol
+    ///   data for a websocket stream.  All the initial data will be
+    ///   left unconsumed in the pipe buffer in case it can be
+    ///   interpreted as another protocol.  An upgrade request with a
+    ///   method other than `GET` gives `HandshakeError::BadMethod`, and
+    ///   one with an HTTP version other than 1.1 gives
+    ///   `HandshakeError::BadHttpVersion`.  A `Sec-WebSocket-Key`
+    ///   which is not the base64 encoding of 16 bytes gives
+    ///   `HandshakeError::BadKey`.  A request which announces a body
+    ///   gives `HandshakeError::RequestBody`.  See [`HandshakeError`],
+    ///   and see [`ServerHandshake::set_lenient_key`] to relax the key
+    ///   check, or [`ServerHandshake::set_strict_headers`] to reject
+    ///   repeated headers.
     ///
     /// `subprotocol` argument may be used to specify a subprotocol to
-    /// pass back to the client, if required.  See
-    /// `embedded_websocket` documentation.
+    /// pass back to the client, if required.  It is only passed back
+    /// if the client offered it in `Sec-WebSocket-Protocol`, since
+    /// otherwise the client would have to fail the connection.  Use
+    /// [`WebsocketServer::subprotocol`] to check whether it was
+    /// selected.
     ///
     /// `max_msg_len` puts a limit on the size of data that will be
     /// allowed in the message buffer before failing the websocket, as
@@ -79,42 +490,242 @@ impl WebsocketServer {
     /// `Ping` and `Close` messages before failing the websocket, as a
     /// protection against denial of service attacks.  In case of
     /// exceeding this limit, `Error::WriteToBufferTooSmall` is
-    /// returned.
+    /// returned.  This is checked against the frame header, before
+    /// any of the frame is consumed, since control frames are only
+    /// handled once they have arrived in full.  RFC 6455 limits
+    /// control frames to 125 bytes in any case, so a larger value has
+    /// no effect.
     ///
     /// `header_cb` is called for each HTTP header line as
     /// `header_cb(field_name, field_value)` once the websocket
     /// connection has been verified in order to allow the caller to
     /// extract whatever details may be required, such as `Origin`.
+    /// The name and value are slices of the request data in `pb.rd`,
+    /// not copies.  Apart from any growth of `pb.wr` to hold the
+    /// reply, accepting the connection makes no heap allocations, so
+    /// this is suitable for servers which must not allocate per
+    /// connection until one is accepted.
+    ///
+    /// This and the other `from_http_*` functions are shorthand for
+    /// creating a [`ServerHandshake`] and calling it once.  Since
+    /// nothing is kept between calls, the data is searched for the
+    /// end of the request from the start each time.  If the request
+    /// may arrive in many small pieces, keep a `ServerHandshake` for
+    /// the connection instead, which only searches the new data.
     pub fn from_http_scan(
-        mut pb: PBufRdWr,
+        pb: PBufRdWr,
+        subprotocol: Option<&WebSocketSubProtocol>,
+        max_msg_len: usize,
+        max_aux_len: usize,
+        header_cb: impl FnMut(&str, &[u8]),
+    ) -> Result<Option<Self>, HandshakeError> {
+        ServerHandshake::new(subprotocol.cloned(), max_msg_len, max_aux_len)
+            .accept_scan(pb, header_cb)
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers and initialise the websocket
+    /// stream from them, as for [`WebsocketServer::from_http_scan`],
+    /// but also passing the request line to `request_cb` as
+    /// `request_cb(method, target, version)` before any headers are
+    /// passed to `header_cb`.  This allows the path and query to be
+    /// seen along with the headers.  `version` is the minor version
+    /// of HTTP/1.x, which is always 1 for an accepted request.
+    pub fn from_http_scan_request(
+        pb: PBufRdWr,
+        subprotocol: Option<&WebSocketSubProtocol>,
+        max_msg_len: usize,
+        max_aux_len: usize,
+        request_cb: impl FnOnce(&str, &str, u8),
+        header_cb: impl FnMut(&str, &[u8]),
+    ) -> Result<Option<Self>, HandshakeError> {
+        ServerHandshake::new(subprotocol.cloned(), max_msg_len, max_aux_len)
+            .accept_scan_request(pb, request_cb, header_cb)
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers and initialise the websocket
+    /// stream from them, as for [`WebsocketServer::from_http_scan`],
+    /// but passing header values to `header_cb` as `Ok(&str)` if they
+    /// are valid UTF-8, or otherwise as `Err(&[u8])`.
+    pub fn from_http_scan_utf8(
+        pb: PBufRdWr,
+        subprotocol: Option<&WebSocketSubProtocol>,
+        max_msg_len: usize,
+        max_aux_len: usize,
+        header_cb: impl FnMut(&str, Result<&str, &[u8]>),
+    ) -> Result<Option<Self>, HandshakeError> {
+        let header_cb = handshake::utf8_values(header_cb);
+        Self::from_http_scan(pb, subprotocol, max_msg_len, max_aux_len, header_cb)
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers and initialise the websocket
+    /// stream from them, allowing the caller to refuse the connection
+    /// based on the headers.
+    ///
+    /// `check` is called for each HTTP header line as for the
+    /// `header_cb` of [`WebsocketServer::from_http_scan`], and may
+    /// return `Err(status)` to refuse the connection, e.g. 403 for an
+    /// unacceptable `Origin`, or 401 for missing authentication.  In
+    /// that case no further headers are passed to `check`, a response
+    /// with that HTTP status is written to `pb.wr`, which is then
+    /// closed, and `HandshakeError::Refused(status)` is returned.
+    ///
+    /// See [`WebsocketServer::from_http_scan`] for details of the
+    /// other arguments and returns.
+    pub fn from_http_check(
+        pb: PBufRdWr,
         subprotocol: Option<&WebSocketSubProtocol>,
         max_msg_len: usize,
         max_aux_len: usize,
-        mut header_cb: impl FnMut(&str, &[u8]),
-    ) -> Result<Option<Self>, ws::Error> {
-        // `Header` is 2 pointers, so this is 128 bytes (on 64-bit)
-        let mut headers = [httparse::EMPTY_HEADER; 32];
-        let mut request = httparse::Request::new(&mut headers);
+        check: impl FnMut(&str, &[u8]) -> Result<(), u16>,
+    ) -> Result<Option<Self>, HandshakeError> {
+        ServerHandshake::new(subprotocol.cloned(), max_msg_len, max_aux_len).accept_check(pb, check)
+    }
+
+    /// Attempt to interpret the initial data in the given pipe-buffer
+    /// stream as websocket HTTP headers and initialise the websocket
+    /// stream from them, choosing the subprotocol according to the
+    /// request.
+    ///
+    /// Once the request has been verified as a websocket upgrade,
+    /// `select` is called with the details of the request, including
+    /// the path, headers and offered subprotocols, and returns the
+    /// subprotocol to pass back to the client, or `None`.  If it
+    /// returns a subprotocol which the client did not offer, or which
+    /// is longer than the 24 bytes that `embedded_websocket` can
+    /// handle, then no subprotocol is passed back.
+    ///
+    /// See [`WebsocketServer::from_http_scan`] for details of the
+    /// other arguments and returns.
+    pub fn from_http_select(
+        pb: PBufRdWr,
+        select: impl FnOnce(&UpgradeRequest) -> Option<String>,
+        max_msg_len: usize,
+        max_aux_len: usize,
+    ) -> Result<Option<Self>, HandshakeError> {
+        ServerHandshake::new(None, max_msg_len, max_aux_len).accept_select(pb, select)
+    }
+
+    /// Handle the upgrade request, once `ServerHandshake` has found
+    /// the end of it.  See `from_http_check` and `from_http_select`.
+    /// `checks` gives the optional checks to make on the request.
+    pub(crate) fn accept_http(
+        mut pb: PBufRdWr,
+        select: impl FnOnce(&UpgradeRequest) -> Option<WebSocketSubProtocol>,
+        max_msg_len: usize,
+        max_aux_len: usize,
+        checks: handshake::Checks,
+        request_cb: impl FnOnce(&str, &str, u8),
+        mut check: impl FnMut(&str, &[u8]) -> Result<(), u16>,
+    ) -> Result<Option<Self>, HandshakeError> {
+        #[cfg(feature = "mini-http")]
+        use http::Request;
+        #[cfg(not(feature = "mini-http"))]
+        use httparse::Request;
+        // `Header` is 4 words, so this is 1KiB (on 64-bit).  More
+        // space is only allocated for a request which needs it.
+        let mut stack = [httparse::EMPTY_HEADER; 32];
+        let mut heap = Vec::new();
+        let headers = if checks.max_headers <= stack.len() {
+            &mut stack[..checks.max_headers]
+        } else {
+            let lines = pb.rd.data().iter().filter(|b| **b == b'\n').count();
+            if lines <= stack.len() {
+                &mut stack[..]
+            } else {
+                heap.resize(lines.min(checks.max_headers), httparse::EMPTY_HEADER);
+                &mut heap[..]
+            }
+        };
+        let mut request = Request::new(headers);
         match request.parse(pb.rd.data()) {
-            Err(e) => Err(ws::Error::HttpHeader(e)),
+            Err(httparse::Error::TooManyHeaders) => Err(HandshakeError::TooManyHeaders),
+            Err(e) => Err(Error::HttpHeader(e).into()),
             Ok(Status::Partial) => Ok(None), // Wait for more data
-            Ok(Status::Complete(count)) => {
-                let headers = request.headers.iter().map(|f| (f.name, f.value));
+            Ok(Status::Complete(request_len)) => {
+                let mut count = request_len;
+                // Reject bad upgrade requests.  The key must be
+                // checked before `read_http_header` sees it, because
+                // that panics if the key is too long.
+                if handshake::is_upgrade(request.headers) {
+                    if request.method != Some("GET") {
+                        return Err(HandshakeError::BadMethod);
+                    }
+                    if request.version != Some(1) {
+                        return Err(HandshakeError::BadHttpVersion);
+                    }
+                    if !handshake::key_ok(request.headers, checks.quirks) {
+                        return Err(HandshakeError::BadKey);
+                    }
+                    match handshake::body_len(request.headers) {
+                        Some(len) if len <= checks.skip_body as u64 => count += len as usize,
+                        _ => return Err(HandshakeError::RequestBody),
+                    }
+                    if checks.strict_headers && handshake::has_duplicates(request.headers) {
+                        return Err(HandshakeError::DuplicateHeader);
+                    }
+                    if pb.rd.len() < count {
+                        return Ok(None); // Wait for the body to be skipped
+                    }
+                }
+                // Only pass on the headers that `read_http_header`
+                // needs, and drop an over-long key, because it panics
+                // on a key or subprotocol longer than 24 bytes.  This
+                // applies even if the request doesn't look like an
+                // upgrade.  The offered subprotocols are handled here.
+                let headers = request
+                    .headers
+                    .iter()
+                    .map(|f| handshake::normalise(f.name, f.value))
+                    .filter(|(name, value)| match *name {
+                        "Upgrade" => true,
+                        "Sec-WebSocket-Key" => value.len() <= 24,
+                        _ => false,
+                    });
                 match ws::read_http_header(headers)? {
-                    None => Err(ws::Error::Unknown), // Actually: not valid WS HTTP headers
+                    None => Err(Error::Unknown.into()), // Actually: not valid WS HTTP headers
                     Some(ws_context) => {
+                        request_cb(
+                            request.method.unwrap_or(""),
+                            request.path.unwrap_or(""),
+                            request.version.unwrap_or(0),
+                        );
+                        for h in request.headers.iter() {
+                            if let Err(status) = check(h.name, h.value) {
+                                let e = HandshakeError::Refused(status);
+                                e.write_response(pb.wr.reborrow());
+                                pb.wr.close();
+                                return Err(e);
+                            }
+                        }
+                        let req = UpgradeRequest::new(request.path, request.headers);
+                        let subprotocol =
+                            select(&req).filter(|p| req.subprotocols().any(|o| o == p.as_str()));
+                        let summary = HandshakeSummary {
+                            headers: request.headers.len(),
+                            request_len,
+                            body_len: count - request_len,
+                            subprotocols_offered: req.subprotocols().next().is_some(),
+                            extensions_offered: req
+                                .header_list("Sec-WebSocket-Extensions")
+                                .next()
+                                .is_some(),
+                        };
                         let mut ws = WebSocketServer::new_server();
                         let blen = ws.server_accept(
                             &ws_context.sec_websocket_key,
-                            subprotocol,
+                            subprotocol.as_ref(),
                             pb.wr.space(1024),
                         )?;
-                        for h in request.headers.iter() {
-                            header_cb(h.name, h.value);
-                        }
                         pb.wr.commit(blen);
                         pb.rd.consume(count);
-                        Ok(Some(Self::from_wss(ws, max_msg_len, max_aux_len)))
+                        let mut this = Self::from_wss(ws, max_msg_len, max_aux_len);
+                        this.subprotocol = subprotocol;
+                        this.handshake_summary = Some(summary);
+                        this.quirks = checks.quirks;
+                        Ok(Some(this))
                     }
                 }
             }
@@ -132,10 +743,52 @@ impl WebsocketServer {
         subprotocol: Option<&WebSocketSubProtocol>,
         max_msg_len: usize,
         max_aux_len: usize,
-    ) -> Result<Option<Self>, ws::Error> {
+    ) -> Result<Option<Self>, HandshakeError> {
         Self::from_http_scan(pb, subprotocol, max_msg_len, max_aux_len, |_, _| ())
     }
 
+    /// Initialise a websocket stream bootstrapped over HTTP/2 using
+    /// extended CONNECT (RFC 8441).  In this case the handshake is
+    /// handled by the HTTP/2 layer, and the pipe-buffers passed to
+    /// later calls carry the data of the HTTP/2 stream, so this only
+    /// checks the request and creates the websocket in the open
+    /// state.
+    ///
+    /// `method` and `protocol` are the values of the `:method` and
+    /// `:protocol` pseudo-headers, which must be `CONNECT` and
+    /// `websocket`.  `headers` are the regular header fields as
+    /// `(field_name, field_value)`, which must include
+    /// `sec-websocket-version: 13`.  Returns
+    /// `HandshakeError::BadMethod` if the method is wrong, or
+    /// `HandshakeError::Ws(Error::Unknown)` if this is not otherwise a
+    /// valid websocket request.
+    ///
+    /// If this succeeds, the caller must send a `200` response via
+    /// the HTTP/2 layer, including a `sec-websocket-protocol` field
+    /// if a subprotocol was selected.  See
+    /// [`WebsocketServer::from_wss`] for `max_msg_len` and
+    /// `max_aux_len`.
+    pub fn from_extended_connect<'a>(
+        method: &str,
+        protocol: Option<&str>,
+        headers: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+        max_msg_len: usize,
+        max_aux_len: usize,
+    ) -> Result<Self, HandshakeError> {
+        let version_ok = headers.into_iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("sec-websocket-version") && value == b"13"
+        });
+        if protocol != Some("websocket") || !version_ok {
+            return Err(Error::Unknown.into());
+        }
+        if method != "CONNECT" {
+            return Err(HandshakeError::BadMethod);
+        }
+        let mut ws = WebSocketServer::new_server();
+        ws.state = WebSocketState::Open;
+        Ok(Self::from_wss(ws, max_msg_len, max_aux_len))
+    }
+
     /// Create from an already-initialised [`WebSocketServer`]
     ///
     /// `max_msg_len` puts a limit on the size of data that will be
@@ -151,153 +804,3909 @@ impl WebsocketServer {
     /// `Ping` and `Close` messages before failing the websocket, as a
     /// protection against denial of service attacks.  In case of
     /// exceeding this limit, `Error::WriteToBufferTooSmall` is
-    /// returned.
+    /// returned.  This is checked against the frame header, before
+    /// any of the frame is consumed, since control frames are only
+    /// handled once they have arrived in full.  RFC 6455 limits
+    /// control frames to 125 bytes in any case, so a larger value has
+    /// no effect.
     pub fn from_wss(ws: WebSocketServer, max_msg_len: usize, max_aux_len: usize) -> Self {
         Self {
             ws,
-            in_data: Vec::new(),
+            role: Role::Server,
             max_msg_len,
             max_aux_len,
+            tx_fragmented: false,
+            rx_remaining: 0,
+            rx_offset: 0,
+            rx_frames: 0,
+            error_position: None,
+            failure: None,
+            tx_bytes: 0,
+            tx_frames: 0,
+            tx_mask: [0; 2],
+            rx_opcode: 0,
+            rx_type: RxMsgType::Binary,
+            rx_fin: true,
+            rx_rsv: 0,
+            rx_mask: None,
+            rx_pos: 0,
+            rx_fragment: None,
+            rx_message: None,
+            rx_discard: false,
+            policy: MessagePolicy::Both,
+            rate_limit: None,
+            budget: None,
+            rx_messages: 0,
+            violations: 0,
+            violation_tolerance: None,
+            quirks: Quirks::default(),
+            truncate_close_reason: false,
+            max_frame_len: None,
+            push_fragments: true,
+            push_replies: true,
+            read_quota: None,
+            watermarks: None,
+            backlogged: false,
+            drain_on_close: false,
+            close_sent_at: None,
+            rx_eof: false,
+            output_ended: false,
+            close_on_input_eof: false,
+            last_close: None,
+            events: None,
+            frame_hook: None,
+            extension: None,
+            metrics: None,
+            clock: None,
+            observer: None,
+            ping_stats: PingStats::default(),
+            pong_next: 0,
+            pings_out: VecDeque::new(),
+            unmatched_pongs: 0,
+            keepalive: None,
+            keepalive_ping: None,
+            last_rx: None,
+            rx_active: false,
+            rx_crc: None,
+            message_crc: None,
+            tx_msg_len: 0,
+            hook_buf: Vec::new(),
+            ext_buf: Vec::new(),
+            rx_buf: PipeBuf::new(),
+            subprotocol: None,
+            handshake_summary: None,
+        }
+    }
+
+    /// Restrict which types of data message are accepted.  If a
+    /// message of a type which is not allowed arrives, the connection
+    /// is closed with code 1003 (unsupported data), and the data of
+    /// that message is discarded.  By default both text and binary
+    /// messages are accepted.
+    pub fn set_message_policy(&mut self, policy: MessagePolicy) {
+        self.policy = policy;
+    }
+
+    /// Limit the rate of incoming messages and data, or pass `None`
+    /// to remove the limit.  `clock` gives the current time.  The
+    /// caller must keep the connection updated with the current time
+    /// from the same clock using [`WebsocketServer::tick`] for the
+    /// allowance to be replenished.
+    /// With [`RateLimitAction::Pause`], [`WebsocketServer::receive`]
+    /// stops consuming input when the limit is reached, so the caller
+    /// should call it again after a later `tick`.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>, clock: &impl WsClock) {
+        self.rate_limit = limit.map(|limit| RateLimiter::new(limit, clock.now()));
+    }
+
+    /// Get the rate limit set with [`WebsocketServer::set_rate_limit`],
+    /// if any.  This stays in place after the limit has closed the
+    /// connection.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.as_ref().map(|rl| rl.limit)
+    }
+
+    /// Test whether the rate limit has closed the connection, with
+    /// [`RateLimitAction::Close`].  After this, the limit is no
+    /// longer enforced, so that the rest of the close handshake can
+    /// be read, but it stays in place and is kept in snapshots.
+    pub fn rate_limit_tripped(&self) -> bool {
+        self.rate_limit.as_ref().is_some_and(|rl| rl.tripped)
+    }
+
+    /// Limit the total number of incoming messages and bytes over the
+    /// lifetime of the connection, or pass `None` to remove the
+    /// limit.  Once the budget is used up, [`WebsocketServer::receive`]
+    /// closes the connection with the code given in the [`Budget`],
+    /// and all further incoming data messages are discarded.  A
+    /// message in progress at that point is aborted, so the caller
+    /// must reset the `message` buffer.  The byte limit is checked
+    /// between chunks of input, so it may be exceeded by up to the
+    /// amount of data consumed in one go.
+    pub fn set_budget(&mut self, budget: Option<Budget>) {
+        self.budget = budget;
+    }
+
+    /// Count minor conformance violations by the peer, and close the
+    /// connection with code 1002 (protocol error) once more than
+    /// `tolerance` of them have occurred, or pass `None` to ignore
+    /// them.  `Some(0)` gives strict behaviour.  See [`Violation`]
+    /// for what is counted.  A `Close` frame with an invalid payload
+    /// is answered with code 1002 instead of echoing the peer's code
+    /// whatever the tolerance, since echoing it would send an invalid
+    /// frame.  By default violations are ignored, although they are
+    /// still counted and recorded in the event log.
+    pub fn set_violation_tolerance(&mut self, tolerance: Option<u32>) {
+        self.violation_tolerance = tolerance;
+    }
+
+    /// Accept unmasked frames from the client, which RFC 6455 forbids,
+    /// for interoperation with in-process or legacy embedded clients
+    /// that skip masking.  Off by default.  Masking protects
+    /// intermediaries from cache poisoning by scripts in browsers, so
+    /// this should only be enabled on closed systems where all the
+    /// clients are known.
+    pub fn set_accept_unmasked(&mut self, accept: bool) {
+        self.quirks.unmasked = accept;
+    }
+
+    /// Set which faults of broken peers to tolerate.  This replaces
+    /// any earlier call to [`WebsocketServer::set_accept_unmasked`].
+    /// `Quirks::lenient_key` has no effect here, since the handshake
+    /// is already complete, so set that with
+    /// [`ServerHandshake::set_quirks`], which also passes all the
+    /// quirks on to the accepted connection.  See [`Quirks`].
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Get the faults of broken peers which are tolerated.  See
+    /// [`WebsocketServer::set_quirks`].
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Get the number of minor conformance violations seen so far.
+    /// See [`WebsocketServer::set_violation_tolerance`].
+    pub fn violations(&self) -> u32 {
+        self.violations
+    }
+
+    /// Limit the number of bytes consumed from `pb.rd` by each call
+    /// to [`WebsocketServer::receive`], or pass `None` for no limit.
+    /// This allows an event loop to share its time fairly between
+    /// connections, so that one client sending huge messages cannot
+    /// starve the others.  When the quota is used up, `receive`
+    /// returns, and the caller may give other connections a turn
+    /// before calling it again.
+    pub fn set_read_quota(&mut self, quota: Option<usize>) {
+        // Must allow at least a whole frame header to be read
+        self.read_quota = quota.map(|q| q.max(14));
+    }
+
+    /// Limit the payload size of each outgoing data frame, or pass
+    /// `None` for no limit, which is the default.  Messages, or
+    /// fragments of messages, with more data than this are sent as
+    /// several frames.  This is for peers with small fixed receive
+    /// buffers, such as `embedded_websocket` clients.  The limit is
+    /// at least 1 byte.  Text is only split between characters, so a
+    /// frame may be shorter than the limit, or longer if the limit is
+    /// less than the length of a character.  Control frames are not
+    /// affected, and nor are
+    /// frames from a [`Broadcaster`], which are encoded once for all
+    /// connections.
+    pub fn set_max_frame_len(&mut self, max: Option<usize>) {
+        self.max_frame_len = max.map(|m| m.max(1));
+    }
+
+    /// Choose whether each fragment of a streamed message sent with
+    /// [`WebsocketServer::send`] indicates a push.  If `true`, which
+    /// is the default, each fragment is pushed, which gives the
+    /// lowest latency, e.g. for RPC.  If `false`, only the final
+    /// fragment is pushed, so the fragments are flushed to the
+    /// transport together, which reduces the number of flushes for
+    /// bulk transfers.  Control frames sent by the caller are always
+    /// pushed.
+    pub fn set_push_fragments(&mut self, push: bool) {
+        self.push_fragments = push;
+    }
+
+    /// Choose whether the `Pong` and `CloseReply` frames sent
+    /// automatically by [`WebsocketServer::receive`] indicate a push.
+    /// If `true`, which is the default, they are pushed like the
+    /// messages sent by the caller.  If `false`, they are left for
+    /// the next push or flush of the outgoing stream, which suits a
+    /// caller which flushes once after processing all the input.  A
+    /// `CloseReply` is followed by closing `pb.wr` in any case.
+    pub fn set_push_replies(&mut self, push: bool) {
+        self.push_replies = push;
+    }
+
+    /// Choose whether to calculate a CRC-32 of each incoming data
+    /// message as it streams through [`WebsocketServer::receive`].
+    /// This allows the integrity of large messages to be checked
+    /// without having to buffer the whole message to hash it.  The
+    /// CRC is the one used by zlib and gzip, and covers the message
+    /// data as delivered, i.e. after any [`FrameHook`].  Get it with
+    /// [`WebsocketServer::message_checksum`] once the message is
+    /// complete.  By default no CRC is calculated.
+    pub fn set_message_checksum(&mut self, enable: bool) {
+        self.rx_crc = enable.then_some(0);
+        self.message_crc = None;
+    }
+
+    /// Get the CRC-32 of the last complete incoming data message, if
+    /// enabled with [`WebsocketServer::set_message_checksum`].  This
+    /// is available from when the message buffer is closed until the
+    /// first data of the next message arrives.  Returns `None` if
+    /// checksums are not enabled, or if a message is in progress.
+    pub fn message_checksum(&self) -> Option<u32> {
+        self.message_crc
+    }
+
+    /// Truncate a close reason longer than 123 bytes passed to
+    /// [`WebsocketServer::close`], instead of failing with
+    /// `Error::InvalidFrameLength`.  The reason is cut at a UTF-8
+    /// character boundary, so it remains valid UTF-8.  Off by
+    /// default.
+    pub fn set_truncate_close_reason(&mut self, truncate: bool) {
+        self.truncate_close_reason = truncate;
+    }
+
+    /// Once we have sent a `Close`, silently discard any further
+    /// incoming data messages whilst waiting for the close handshake
+    /// to complete.  This saves the caller having to keep on
+    /// processing and resetting the message buffer during shutdown.
+    /// If part of a message has already been passed to the caller, the
+    /// `message` buffer is aborted on the next call, and the rest of
+    /// that message is discarded.  Off by default.
+    pub fn set_drain_on_close(&mut self, drain: bool) {
+        self.drain_on_close = drain;
+    }
+
+    /// Enforce a time limit on the close handshake.  If we have sent
+    /// a `Close` and the peer has not replied within `limit`, then the
+    /// output stream `pb.wr` is aborted, so that the transport can be
+    /// shut down.  This should be called regularly from a timer,
+    /// taking the current time from `clock`.  Returns `true` if the
+    /// limit was exceeded.
+    ///
+    /// The time is measured from when the `Close` was sent, as read
+    /// from the clock registered with [`WebsocketServer::set_clock`],
+    /// which must be the same clock as passed here.  Without a
+    /// registered clock, the time the `Close` was sent isn't known, so
+    /// it is measured from the first call to this method after that
+    /// instead, which may allow the peer up to a timer period more.
+    pub fn close_deadline(
+        &mut self,
+        mut pb: PBufRdWr,
+        clock: &impl WsClock,
+        limit: Duration,
+    ) -> bool {
+        if self.ws.state != WebSocketState::CloseSent {
+            return false;
+        }
+        let now = clock.now();
+        let sent_at = *self.close_sent_at.get_or_insert(now);
+        if now.saturating_sub(sent_at) < limit {
+            return false;
+        }
+        if !pb.wr.is_eof() {
+            pb.wr.abort();
+        }
+        true
+    }
+
+    /// If the input stream `pb.rd` ends whilst `pb.wr` is still open,
+    /// i.e. the transport was half-closed by the peer, then send a
+    /// `Close` with code 1001 (going away) if the close handshake has
+    /// not already started, and close `pb.wr`.  If the input was
+    /// aborted instead, meaning that the transport failed, then no
+    /// `Close` is sent and `pb.wr` is aborted, passing on the
+    /// abnormal ending.  Off by default, in which case the caller must
+    /// decide what to do with the output stream.  See
+    /// [`WebsocketServer::transport_state`].
+    pub fn set_close_on_input_eof(&mut self, close: bool) {
+        self.close_on_input_eof = close;
+    }
+
+    /// Get the state of the transport streams as seen by this
+    /// connection.  The input is considered to have ended once
+    /// [`WebsocketServer::receive`] has consumed the EOF on `pb.rd`.
+    /// Once either direction has ended, further sends are refused
+    /// with `Error::WebSocketNotOpen`.
+    pub fn transport_state(&self, pb: PBufRdWr) -> TransportState {
+        match (self.rx_eof, pb.wr.is_eof()) {
+            (false, false) => TransportState::Open,
+            (true, false) => TransportState::InputEnded,
+            (false, true) => TransportState::OutputEnded,
+            (true, true) => TransportState::Closed,
+        }
+    }
+
+    /// Record the last `capacity` protocol events in a ring buffer,
+    /// or pass 0 to stop recording.  This is cheap enough to leave
+    /// enabled in production, so that when a connection fails
+    /// unexpectedly, the events leading up to the failure can be
+    /// logged using [`WebsocketServer::events`].
+    pub fn set_event_log(&mut self, capacity: usize) {
+        self.events = (capacity > 0).then(|| EventLog::new(capacity));
+    }
+
+    /// Get the recorded protocol events, oldest first.  See
+    /// [`WebsocketServer::set_event_log`].
+    pub fn events(&self) -> impl Iterator<Item = &WsEvent> {
+        self.events.iter().flat_map(EventLog::iter)
+    }
+
+    /// Register an implementation of [`WsMetrics`] to be called as
+    /// messages and bytes are received and sent, on errors and on
+    /// closing, or pass `None` to remove it.  The same
+    /// implementation may be shared between many connections.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn WsMetrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Register a [`WsClock`] to timestamp events as they happen, or
+    /// pass `None` to remove it.  At present this records when a
+    /// `Close` is sent, so that [`WebsocketServer::close_deadline`]
+    /// can measure its limit from that moment.  This must be the same
+    /// clock as is passed to the other time-based calls.  The same
+    /// clock may be shared between many connections.
+    pub fn set_clock(&mut self, clock: Option<Arc<dyn WsClock>>) {
+        self.clock = clock;
+    }
+
+    /// Register a [`WsObserver`] to be called on the main events in
+    /// the lifecycle of the connection, or pass `None` to remove it.
+    /// To also observe the completion of the handshake, set it with
+    /// [`ServerHandshake::set_observer`] instead.
+    pub fn set_observer(&mut self, observer: Option<Box<dyn WsObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Register a hook which is called with the payload data of
+    /// every text or binary message received or sent, or pass `None`
+    /// to remove it.  This allows concerns such as redaction,
+    /// transcoding or auditing to be handled in one place.  The hook
+    /// is given a copy of the data, which it may modify or replace.
+    ///
+    /// Incoming data is passed to the hook as it arrives, so a single
+    /// frame may be split across several calls.  Outgoing data is
+    /// passed once per call to [`WebsocketServer::send`].  Frames
+    /// sent using [`Broadcaster`] are already encoded, so are not
+    /// passed to the hook.
+    pub fn set_frame_hook(&mut self, hook: Option<FrameHook>) {
+        self.frame_hook = hook;
+    }
+
+    /// Set an extension to interpret the reserved bits of data frames
+    /// and transform their payloads, or pass `None` to remove it.
+    /// This should be set straight after the handshake, with the
+    /// parameters agreed in `Sec-WebSocket-Extensions`, before any
+    /// data frames are sent or received.  Incoming data passes
+    /// through the extension before the frame hook, and outgoing data
+    /// after it, so the frame hook always sees the message as the
+    /// application does.  Frames sent using [`Broadcaster`] and
+    /// frames read with [`WebsocketServer::receive_frame`] or
+    /// [`WebsocketServer::forward_frames`] do not pass through the
+    /// extension.  See [`WsExtension`].
+    pub fn set_extension(&mut self, extension: Option<Box<dyn WsExtension>>) {
+        self.extension = extension;
+    }
+
+    /// Set low and high watermarks for the amount of unread data in
+    /// the outgoing pipe-buffer, or pass `None` to disable checking.
+    /// See [`WebsocketServer::check_backlog`].
+    pub fn set_watermarks(&mut self, watermarks: Option<(usize, usize)>) {
+        self.watermarks = watermarks;
+        self.backlogged = false;
+    }
+
+    /// Check the amount of unread data in the outgoing pipe-buffer
+    /// against the watermarks set with
+    /// [`WebsocketServer::set_watermarks`].  Returns `Some(_)` if the
+    /// backlog has crossed one of the watermarks since the last
+    /// check.  This allows a server to detect clients that are not
+    /// reading their data, in order to drop or deprioritise them.
+    /// Call this after sending, or periodically.
+    pub fn check_backlog(&mut self, pb: PBufRdWr) -> Option<Backlog> {
+        let (low, high) = self.watermarks?;
+        if !self.backlogged && pb.wr.exceeds_limit(high) {
+            self.backlogged = true;
+            Some(Backlog::High)
+        } else if self.backlogged && !pb.wr.exceeds_limit(low) {
+            self.backlogged = false;
+            Some(Backlog::Low)
+        } else {
+            None
+        }
+    }
+
+    /// Test whether the outgoing backlog was above the high watermark
+    /// at the last [`WebsocketServer::check_backlog`]
+    pub fn is_backlogged(&self) -> bool {
+        self.backlogged
+    }
+
+    /// Inform the connection of the current time, as given by
+    /// `clock`.  This is required for time-based features such as
+    /// rate limiting.
+    pub fn tick(&mut self, clock: &impl WsClock) {
+        if let Some(rl) = &mut self.rate_limit {
+            rl.set_time(clock.now());
         }
     }
 
     /// Send an unfragmented websocket text message
-    pub fn send_text(&mut self, pb: PBufRdWr, data: &str) -> Result<(), ws::Error> {
+    pub fn send_text(&mut self, pb: PBufRdWr, data: &str) -> Result<(), Error> {
         self.send(pb, WebSocketSendMessageType::Text, true, data.as_bytes())
     }
 
     /// Send an unfragmented websocket binary message
-    pub fn send_binary(&mut self, pb: PBufRdWr, data: &[u8]) -> Result<(), ws::Error> {
+    pub fn send_binary(&mut self, pb: PBufRdWr, data: &[u8]) -> Result<(), Error> {
         self.send(pb, WebSocketSendMessageType::Binary, true, data)
     }
 
-    /// Send an arbitrary websocket message.  This is a wrapper around
-    /// [`WebSocketServer::write`].  For an unfragmented message,
-    /// `eom` should be `true`.  For a fragmented message, it should
-    /// be `true` only for the final fragment.
+    /// Send a `Ping` carrying the next sequence number, as 8 bytes
+    /// big-endian, and return that number.  The sequence numbers
+    /// echoed in the `Pong` replies are checked to detect lost pings
+    /// and duplicated replies.  See [`WebsocketServer::ping_stats`].
+    pub fn send_ping_seq(&mut self, pb: PBufRdWr) -> Result<u64, Error> {
+        let seq = self.ping_stats.sent;
+        self.send(pb, WebSocketSendMessageType::Ping, true, &seq.to_be_bytes())?;
+        self.ping_stats.sent += 1;
+        Ok(seq)
+    }
+
+    /// Get the statistics for pings sent with
+    /// [`WebsocketServer::send_ping_seq`].  Note that a `Pong`
+    /// carrying 8 bytes in reply to some other `Ping` may be mistaken
+    /// for a reply to a sequence-numbered ping.
+    pub fn ping_stats(&self) -> PingStats {
+        self.ping_stats
+    }
+
+    /// Get the number of `Pong`s received which didn't match the
+    /// payload of any outstanding `Ping` sent on this connection.
+    /// RFC 6455 allows unsolicited `Pong`s to be sent as a heartbeat,
+    /// so a liveness check should only count the matched ones.  Up
+    /// to 16 outstanding `Ping`s are remembered.  Since a `Pong` may
+    /// answer only the most recent `Ping`, a matching `Pong` also
+    /// clears any older ones.
+    pub fn unmatched_pongs(&self) -> u64 {
+        self.unmatched_pongs
+    }
+
+    /// Get the number of `Ping`s sent which have not yet been
+    /// answered.  See [`WebsocketServer::unmatched_pongs`].
+    pub fn outstanding_pings(&self) -> usize {
+        self.pings_out.len()
+    }
+
+    /// Enable keepalive, sending a sequence-numbered `Ping` whenever
+    /// nothing has been received for `interval`, and giving up on the
+    /// peer if neither the `Pong` nor any other data arrives within
+    /// `timeout` of sending it.  A zero `interval` disables
+    /// keepalive.  Browsers don't ping servers, and an idle
+    /// connection may be dropped silently by a NAT or proxy along the
+    /// way, so this is needed by long-lived connections on both ends.
+    /// [`WebsocketServer::keepalive`] must then be called regularly
+    /// from a timer.
+    pub fn set_keepalive(&mut self, interval: Duration, timeout: Duration) {
+        self.keepalive = (!interval.is_zero()).then_some((interval, timeout));
+        self.keepalive_ping = None;
+    }
+
+    /// Send a keepalive `Ping` if one is due, and check for the peer
+    /// having timed out.  This should be called regularly from a
+    /// timer, taking the current time from `clock`, at the latest
+    /// at the time given by [`WebsocketServer::next_ping_due`].  If
+    /// the peer has timed out, `pb.wr` is aborted, so that the
+    /// transport can be shut down, and `Ok(true)` is returned.  There
+    /// is no point sending a `Close`, since the peer is not
+    /// responding.  Nothing is done unless keepalive has been enabled
+    /// with [`WebsocketServer::set_keepalive`] and the connection is
+    /// open.
+    ///
+    /// Incoming data is timed using the clock registered with
+    /// [`WebsocketServer::set_clock`], which must be the same clock as
+    /// passed here.  Without a registered clock, incoming data is
+    /// timed by the first call to this method after it arrives, which
+    /// may delay a `Ping` by up to a timer period.
+    pub fn keepalive(&mut self, pb: PBufRdWr, clock: &impl WsClock) -> Result<bool, Error> {
+        let Some((interval, timeout)) = self.keepalive else {
+            return Ok(false);
+        };
+        if self.ws.state != WebSocketState::Open {
+            return Ok(false);
+        }
+        let now = clock.now();
+        if std::mem::take(&mut self.rx_active) {
+            self.last_rx = Some(now);
+        }
+        let last_rx = *self.last_rx.get_or_insert(now);
+        if let Some((seq, sent_at)) = self.keepalive_ping {
+            let payload = seq.to_be_bytes();
+            if last_rx >= sent_at || !self.pings_out.iter().any(|p| p[..] == payload) {
+                self.keepalive_ping = None;
+            } else if now.saturating_sub(sent_at) >= timeout {
+                let mut wr = pb.wr;
+                if !wr.is_eof() {
+                    wr.abort();
+                }
+                return Ok(true);
+            } else {
+                return Ok(false);
+            }
+        }
+        if now.saturating_sub(last_rx) >= interval {
+            let seq = self.send_ping_seq(pb)?;
+            self.keepalive_ping = Some((seq, now));
+        }
+        Ok(false)
+    }
+
+    /// Get the time at which [`WebsocketServer::keepalive`] should
+    /// next be called, by the clock passed to it, i.e. when the next
+    /// `Ping` is due, or when the reply to the last one times out.
+    /// Returns `None` if keepalive is not enabled, the connection is
+    /// not open, or `keepalive` has not yet been called.  Incoming
+    /// data changes the time, so this should be checked again after
+    /// each call to [`WebsocketServer::receive`].
+    pub fn next_ping_due(&self) -> Option<Duration> {
+        let (interval, timeout) = self.keepalive?;
+        if self.ws.state != WebSocketState::Open {
+            return None;
+        }
+        match self.keepalive_ping {
+            Some((_, sent_at)) => Some(sent_at + timeout),
+            None => Some(self.last_rx? + interval),
+        }
+    }
+
+    /// Get the time at which data was last received from the peer,
+    /// as read from the clock registered with
+    /// [`WebsocketServer::set_clock`], or else from the clock passed
+    /// to [`WebsocketServer::keepalive`].  Returns `None` if no time
+    /// has been recorded yet.
+    pub fn last_activity(&self) -> Option<Duration> {
+        self.last_rx
+    }
+
+    /// Get the number of bytes of header sent with a frame carrying
+    /// `len` bytes of payload.  This is from 2 to 10 bytes, depending
+    /// on the length.
+    pub fn frame_overhead(&self, len: usize) -> usize {
+        self.role.header_len(len)
+    }
+
+    /// Get the free space required in the outgoing stream to send a
+    /// text or binary message of `msg_len` bytes, including the
+    /// headers of all the frames it is sent as.  This allows a caller
+    /// with a fixed-size outgoing buffer to check that a message will
+    /// fit before calling [`WebsocketServer::send`], rather than
+    /// failing part-way through writing it.  The result is an upper
+    /// bound, since a little extra space is reserved whilst encoding
+    /// each frame, and with [`WebsocketServer::set_max_frame_len`]
+    /// it allows for text being split short of the limit to keep
+    /// characters whole.  Changes made to the data by a frame hook
+    /// are not taken into account.
+    pub fn space_needed(&self, msg_len: usize) -> usize {
+        let max = self.max_frame_len.unwrap_or(usize::MAX);
+        if msg_len <= max {
+            return self.role.frame_reserve(msg_len);
+        }
+        // Text frames may be up to 3 bytes short of the limit, or
+        // longer if the limit is less than a character.  Only the
+        // last frame needs the extra reserved space.
+        let frames = msg_len.div_ceil(max.saturating_sub(3).max(1));
+        let header_len = self.role.header_len(max.max(4));
+        msg_len + (frames - 1) * header_len + self.role.frame_reserve(0)
+    }
+
+    /// Send an arbitrary websocket message.  For an unfragmented
+    /// message, `eom` should be `true`.  For a fragmented message, it
+    /// should be `true` only for the final fragment.  `Ping` and
+    /// `Pong` messages may be sent in between the fragments of a text
+    /// or binary message, and don't affect it.  A push is indicated
+    /// after each call, unless disabled for non-final fragments with
+    /// [`WebsocketServer::set_push_fragments`].
     pub fn send(
         &mut self,
-        mut pb: PBufRdWr,
+        pb: PBufRdWr,
         msg: WebSocketSendMessageType,
         eom: bool,
         data: &[u8],
-    ) -> Result<(), ws::Error> {
-        if pb.wr.is_eof() {
-            Err(ws::Error::WebSocketNotOpen)
-        } else {
-            let reserve = 12 + data.len(); // Server frame header is max 10
-            let used = self.ws.write(msg, eom, data, pb.wr.space(reserve))?;
-            pb.wr.commit(used);
-            pb.wr.push();
-            Ok(())
+    ) -> Result<(), Error> {
+        self.send_wr(pb.wr, msg, eom, data)
+    }
+
+    /// Send an arbitrary websocket message, given only the outgoing
+    /// stream.  This is the same as [`WebsocketServer::send`], for
+    /// callers which keep the two halves of the transport stream in
+    /// different places, since sending never needs the incoming
+    /// stream.
+    pub fn send_wr(
+        &mut self,
+        mut wr: PBufWr,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.write_message(wr.reborrow(), msg, eom, data)?;
+        if eom || self.push_fragments || !matches!(msg, TxMsgType::Text | TxMsgType::Binary) {
+            wr.push();
         }
+        Ok(())
     }
 
-    /// Send a reply with the contents of `self.in_data`
-    fn send_reply(
+    /// Write a message to `wr` without indicating a push
+    pub(crate) fn write_message(
         &mut self,
-        mut pb: PBufRdWr,
+        mut wr: PBufWr,
         msg: WebSocketSendMessageType,
-    ) -> Result<(), ws::Error> {
-        if pb.wr.is_eof() {
-            Err(ws::Error::WebSocketNotOpen)
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.failed()?;
+        if !self.can_send(&wr) {
+            Err(Error::WebSocketNotOpen)
         } else {
-            let data = &self.in_data[..];
-            let reserve = 12 + data.len(); // Server frame header is max 10
-            let used = self.ws.write(msg, true, data, pb.wr.space(reserve))?;
-            pb.wr.commit(used);
+            let before = self.ws.state;
+            let hooked = if matches!(msg, TxMsgType::Text | TxMsgType::Binary) {
+                self.run_hook(Direction::Outgoing, msg == TxMsgType::Text, data)
+            } else {
+                None
+            };
+            let data = hooked.as_deref().unwrap_or(data);
+            let data_len = data.len() as u64;
+            let ping = (msg == TxMsgType::Ping).then(|| data.to_vec());
+            let result = self.write_frames(wr.reborrow(), msg, eom, data);
+            if let Some(buf) = hooked {
+                self.hook_buf = buf;
+            }
+            result?;
+            if msg == TxMsgType::CloseReply {
+                // As for `WebSocketServer::write`
+                self.ws.state = WebSocketState::Closed;
+            }
+            self.note_state(before);
+            if let Some(ping) = ping {
+                if self.pings_out.len() == MAX_PINGS_OUT {
+                    self.pings_out.pop_front();
+                }
+                self.pings_out.push_back(ping);
+            }
+            if matches!(msg, TxMsgType::Text | TxMsgType::Binary) {
+                self.tx_fragmented = !eom;
+                self.tx_msg_len += data_len;
+                if eom {
+                    if let Some(m) = &self.metrics {
+                        m.message_sent(msg == TxMsgType::Text, self.tx_msg_len);
+                    }
+                    self.tx_msg_len = 0;
+                }
+            }
+            self.check_invariants(before);
             Ok(())
         }
     }
 
-    /// Process as much data as possible from the stream.  Whilst
-    /// processing, sends back `Pong` and `CloseReply` messages as
-    /// necessary according to protocol.  If the stream is closed at a
-    /// websocket protocol level, closes the output stream `pb.wr`.
-    ///
-    /// Received message data is streamed into the `message`
-    /// pipe-buffer.  When the end of the message is reached, EOF is
-    /// indicated on the pipe-buffer (with state `Closing`).  This
-    /// takes care of websocket fragments being used to stream data.
-    /// The caller may wait for an EOF and process the entire message,
-    /// or else process the data as it comes in (streaming style).
-    /// Even partial fragments may result in data being added to the
-    /// pipe-buffer, so you can't count on seeing data with the
-    /// original fragment boundaries.  `*is_text` will be set
-    /// according to the message type: `true` for text, `false` for
-    /// binary.  When EOF is indicated on the `message` pipe-buffer,
-    /// the caller must process the contents and reset the buffer
-    /// (with `PipeBuf::reset()`) before calling this method again, so
-    /// that a new message can be read into it.
-    ///
-    /// Returns `Ok(true)` if there was activity, `Ok(false)` if it is
+    /// Write message data as one frame, or as several if it exceeds
+    /// the maximum frame payload.  Text is only split between UTF-8
+    /// sequences, since some peers can't handle a character split
+    /// across frames, even though RFC 6455 allows it.
+    fn write_frames(
+        &mut self,
+        mut wr: PBufWr,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let max = match msg {
+            TxMsgType::Text | TxMsgType::Binary => self.max_frame_len.unwrap_or(usize::MAX),
+            _ => usize::MAX,
+        };
+        let mut continuation = self.tx_fragmented;
+        let mut rest = data;
+        loop {
+            let mut len = rest.len().min(max);
+            if msg == TxMsgType::Text {
+                let is_boundary = |i: usize| i == rest.len() || (rest[i] & 0xC0) != 0x80;
+                let limit = len;
+                while len > 0 && !is_boundary(len) {
+                    len -= 1;
+                }
+                if len == 0 {
+                    // Limit is smaller than this character
+                    len = limit;
+                    while !is_boundary(len) {
+                        len += 1;
+                    }
+                }
+            }
+            let (chunk, tail) = rest.split_at(len);
+            let fin = eom && tail.is_empty();
+            let mut opcode = frame::opcode(msg, continuation);
+            let ext_out = match &mut self.extension {
+                Some(ext) if matches!(msg, TxMsgType::Text | TxMsgType::Binary) => {
+                    let mut buf = std::mem::take(&mut self.ext_buf);
+                    buf.clear();
+                    buf.extend_from_slice(chunk);
+                    let mut f = ExtFrame {
+                        is_text: msg == TxMsgType::Text,
+                        rsv: 0,
+                        start: !continuation,
+                        end: fin,
+                    };
+                    ext.outgoing(&mut f, &mut buf);
+                    // The reserved bits go above the opcode
+                    opcode |= (f.rsv & 7) << 4;
+                    Some(buf)
+                }
+                _ => None,
+            };
+            let payload = ext_out.as_deref().unwrap_or(chunk);
+            let result = self.encode_frame(wr.reborrow(), fin, opcode, payload);
+            if let Some(buf) = ext_out {
+                self.ext_buf = buf;
+            }
+            result?;
+            if tail.is_empty() {
+                return Ok(());
+            }
+            continuation = true;
+            rest = tail;
+        }
+    }
+
+    /// Encode a single frame into `wr`, and count it as sent
+    fn encode_frame(
+        &mut self,
+        mut wr: PBufWr,
+        fin: bool,
+        opcode: u8,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let key = match self.role {
+            Role::Server => [0; 4],
+            Role::Client => self.next_key(),
+        };
+        let reserve = self.role.frame_reserve(payload.len());
+        let space = wr.space(reserve);
+        let used = self.role.write_frame(fin, opcode, payload, key, space)?;
+        self.count_tx(&space[..used]);
+        wr.commit(used);
+        Ok(())
+    }
+
+    /// Get the masking key for the next outgoing frame of a client,
+    /// using xorshift128+
+    fn next_key(&mut self) -> [u8; 4] {
+        let [mut a, b] = self.tx_mask;
+        a ^= a << 23;
+        a ^= a >> 17;
+        a ^= b ^ (b >> 26);
+        self.tx_mask = [b, a];
+        let key = (a.wrapping_add(b) >> 32) as u32;
+        key.to_be_bytes()
+    }
+
+    /// Create a client connection, for use once the server has
+    /// accepted the upgrade request.  The masking keys are generated
+    /// from the 16 bytes of `seed`.
+    pub(crate) fn new_client(max_msg_len: usize, max_aux_len: usize, seed: [u8; 16]) -> Self {
+        let mut ws = WebSocketServer::new_server();
+        ws.state = WebSocketState::Open;
+        let mut this = Self::from_wss(ws, max_msg_len, max_aux_len);
+        this.role = Role::Client;
+        let (a, b) = seed.split_at(8);
+        this.tx_mask = [
+            u64::from_le_bytes(a.try_into().unwrap()),
+            u64::from_le_bytes(b.try_into().unwrap()),
+        ];
+        if this.tx_mask == [0; 2] {
+            // The generator would only ever give zero
+            this.tx_mask = [1, 0];
+        }
+        this
+    }
+
+    /// Split into independent sending and receiving halves, which
+    /// may be owned by different components.  See [`WsSender`] and
+    /// [`WsReceiver`] for details.
+    pub fn split(self) -> (WsSender, WsReceiver) {
+        split::split(self)
+    }
+
+    /// Initiate the close handshake by sending a `Close` message.
+    /// This is a wrapper around [`WebSocketServer::close`].  The
+    /// state changes to `CloseSent`, after which any further sends
+    /// fail with `Error::WebSocketNotOpen`.  When the peer's reply
+    /// arrives, [`WebsocketServer::receive`] closes `pb.wr`.
+    ///
+    /// The `reason` must be at most 123 bytes, so that the `Close`
+    /// frame fits within the 125-byte limit for control frames,
+    /// otherwise `Error::InvalidFrameLength` is returned and nothing
+    /// is sent.  See [`WebsocketServer::set_truncate_close_reason`]
+    /// to truncate it instead.
+    pub fn close(
+        &mut self,
+        pb: PBufRdWr,
+        code: WebSocketCloseStatusCode,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        self.close_wr(pb.wr, code, reason)
+    }
+
+    /// Initiate the close handshake, given only the outgoing stream.
+    /// See [`WebsocketServer::close`] and
+    /// [`WebsocketServer::send_wr`].
+    pub fn close_wr(
+        &mut self,
+        mut wr: PBufWr,
+        code: WebSocketCloseStatusCode,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        if wr.is_eof() || self.ws.state != WebSocketState::Open {
+            Err(Error::WebSocketNotOpen)
+        } else if !self.truncate_close_reason
+            && reason.is_some_and(|r| r.len() > close::MAX_REASON_LEN)
+        {
+            Err(Error::InvalidFrameLength)
+        } else {
+            let reason = reason.map(close::truncate_reason);
+            // `WebSocketServer::close` panics when given a reason, so
+            // build the payload here and send it as a plain `Close`
+            // frame
+            let mut payload = close::close_code(code).to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.unwrap_or("").as_bytes());
+            self.encode_frame(wr.reborrow(), true, 8, &payload)?;
+            self.ws.state = WebSocketState::CloseSent;
+            self.close_sent_at = self.clock.as_ref().map(|c| c.now());
+            self.closed(CloseInfo::from_payload(false, &payload));
+            self.note_state(WebSocketState::Open);
+            wr.push();
+            self.check_invariants(WebSocketState::Open);
+            Ok(())
+        }
+    }
+
+    /// Initiate the close handshake with code 1000 (normal closure)
+    /// and no reason.  See [`WebsocketServer::close`].
+    pub fn close_normal(&mut self, pb: PBufRdWr) -> Result<(), Error> {
+        self.close(pb, WebSocketCloseStatusCode::NormalClosure, None)
+    }
+
+    /// Shut down the connection from our side in one call, for when
+    /// the application is going away now and won't wait for the
+    /// close handshake to complete.  If the connection is still open,
+    /// a `Close` with code 1001 (going away) is sent if there is space
+    /// for it, and then `pb.wr` is closed.  With a fixed-capacity
+    /// outgoing buffer which is full, the `Close` is skipped.  Any
+    /// failure to send the `Close` is ignored.  Returns `true` if the
+    /// `Close` was sent.
+    pub fn shutdown(&mut self, mut pb: PBufRdWr) -> bool {
+        let room = pb
+            .wr
+            .free_space()
+            .is_none_or(|free| free >= self.role.frame_reserve(2));
+        let sent = self.ws.state == WebSocketState::Open
+            && room
+            && self
+                .close(
+                    pb.reborrow(),
+                    WebSocketCloseStatusCode::EndpointUnavailable,
+                    None,
+                )
+                .is_ok();
+        if !pb.wr.is_eof() {
+            pb.wr.close();
+        }
+        sent
+    }
+
+    /// Get details of the close handshake, if it has started.  This
+    /// remains available after the connection has closed.
+    pub fn last_close(&self) -> Option<&CloseInfo> {
+        self.last_close.as_ref()
+    }
+
+    /// Test whether the peer has started the close handshake, which
+    /// means that a `CloseReply` has been sent.  A `Close` frame is
+    /// handled as soon as it has arrived in full, so the reply is
+    /// sent in the same [`WebsocketServer::receive`] call.  Once this
+    /// is `true`, there is no point queuing any more data, since
+    /// nothing may follow the `CloseReply`.
+    pub fn owes_close_reply(&self) -> bool {
+        self.last_close.as_ref().is_some_and(|c| c.by_peer)
+    }
+
+    /// Get the number of further bytes which must arrive on `rd` to
+    /// complete the current incoming frame, i.e. its header if that
+    /// has not been read yet, and then the rest of its payload.  This
+    /// allows a transport to size its next read, and to avoid waking
+    /// up [`WebsocketServer::receive`] on a connection which is
+    /// trickling in data when nothing can be done with it yet.
+    /// Returns 0 if the frame is complete in `rd`, or if `rd` has
+    /// reached EOF.
+    ///
+    /// Data frames are streamed into the message buffer as they
+    /// arrive, so `receive` can make progress on the payload of a data
+    /// frame with any amount of data.  However a frame header, or a
+    /// control frame, is only handled once it has arrived in full.
+    /// The read quota and rate limit are not taken into account.
+    pub fn bytes_needed(&self, rd: PBufRd) -> u64 {
+        if rd.has_pending_eof() || rd.is_eof() {
+            return 0;
+        }
+        let data = rd.data();
+        let needed = match self.rx_remaining {
+            0 => match FrameHeader::parse(data) {
+                None => frame::header_len(data) as u64,
+                Some(h) => h.len.saturating_add(h.header_len as u64),
+            },
+            remaining => remaining,
+        };
+        needed.saturating_sub(data.len() as u64)
+    }
+
+    /// If [`WebsocketServer::receive`] has failed with a protocol or
+    /// limit error, get the position in the incoming stream at which
+    /// the error was detected.  This allows interoperability problems
+    /// to be matched up with a packet capture.
+    pub fn error_position(&self) -> Option<StreamPosition> {
+        self.error_position
+    }
+
+    /// Get the error which made the connection unusable, if any.
+    /// Errors are either fatal or recoverable.  An error returned by
+    /// a receive call is fatal if [`Error::is_fatal`] says so, for
+    /// example an invalid frame from the peer, or a message exceeding
+    /// `max_msg_len`.  This includes errors in sending a reply or a
+    /// `Close` whilst receiving, since the input which caused them
+    /// has already been consumed.  After this the incoming stream
+    /// can't be interpreted any further, and the connection must be
+    /// torn down.  Errors from sending leave the connection usable,
+    /// for example `Error::InvalidFrameLength` from
+    /// [`WebsocketServer::close`] for an overlong reason, or an error
+    /// from a frame hook or serializer, and the call may be retried,
+    /// e.g. with a smaller payload.  `Error::WebSocketNotOpen` on
+    /// sending means that the connection is closing.  So an error is
+    /// fatal if this returns `Some` after the call.
+    ///
+    /// Once a fatal error has occurred, the connection is poisoned:
+    /// every further call to receive or send fails with
+    /// `Error::ConnectionFailed`, rather than acting on a half-updated
+    /// state, and [`WebsocketServer::state`] reports
+    /// `ConnectionState::Failed`.  Only
+    /// [`WebsocketServer::close`] is still allowed, so that the peer
+    /// may be told why, e.g. with code 1009 (message too big) after a
+    /// message exceeded `max_msg_len`.
+    pub fn failure(&self) -> Option<&Error> {
+        self.failure.as_ref()
+    }
+
+    /// Get counts of frames and bytes sent and received.  These
+    /// only ever increase, so comparing them between two calls is a
+    /// cheap way to check whether a connection is moving any data.
+    pub fn counters(&self) -> Counters {
+        Counters {
+            rx_frames: self.rx_frames,
+            rx_bytes: self.rx_offset,
+            tx_frames: self.tx_frames,
+            tx_bytes: self.tx_bytes,
+        }
+    }
+
+    /// Get a tripwire value covering the transport streams.  Any
+    /// data consumed or written, or EOF consumed or indicated, by
+    /// this connection changes the value.  See [`WsTrip`].
+    pub fn tripwire(&self, pb: PBufRdWr) -> WsTrip {
+        WsTrip {
+            rd: pb.rd.tripwire(),
+            wr: pb.wr.tripwire(),
+            message: None,
+        }
+    }
+
+    /// Get the subprotocol which was selected in the handshake, if
+    /// any
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+
+    /// Get a summary of the upgrade request which opened this
+    /// connection, for logging.  This is `None` unless the connection
+    /// was accepted from an HTTP/1.1 upgrade request, and it is not
+    /// kept in a `WsSnapshot`.
+    pub fn handshake_summary(&self) -> Option<HandshakeSummary> {
+        self.handshake_summary
+    }
+
+    /// Get the current state of the connection.  This is
+    /// `ConnectionState::Failed` once a fatal error has occurred,
+    /// even if a `Close` is sent afterwards, so that a connection
+    /// which failed may be told apart from one which closed normally.
+    pub fn state(&self) -> ConnectionState {
+        if self.failure.is_some() {
+            return ConnectionState::Failed;
+        }
+        match self.ws.state {
+            WebSocketState::None | WebSocketState::Connecting => ConnectionState::Connecting,
+            WebSocketState::Open => ConnectionState::Open,
+            WebSocketState::CloseSent => ConnectionState::CloseSent,
+            WebSocketState::CloseReceived => ConnectionState::CloseReceived,
+            WebSocketState::Closed => ConnectionState::Closed,
+            WebSocketState::Aborted => ConnectionState::Failed,
+        }
+    }
+
+    /// Get a reference to the wrapped [`WebSocketServer`]
+    pub fn inner(&self) -> &WebSocketServer {
+        &self.ws
+    }
+
+    /// Get a mutable reference to the wrapped [`WebSocketServer`], to
+    /// allow calling `embedded_websocket` APIs which are not wrapped
+    /// by this crate.  Take care not to leave the websocket in a
+    /// state which this wrapper does not expect, e.g. in the middle
+    /// of a fragmented message.
+    pub fn inner_mut(&mut self) -> &mut WebSocketServer {
+        &mut self.ws
+    }
+
+    /// Discard the wrapper and return the wrapped
+    /// [`WebSocketServer`].  Any partially-received `Ping` or `Close`
+    /// data held by the wrapper is lost.
+    pub fn into_inner(self) -> WebSocketServer {
+        self.ws
+    }
+
+    /// If a message is part-way through being received, return its
+    /// type and the number of bytes received so far
+    pub fn partial_message(&self) -> Option<PartialMessage> {
+        self.rx_message
+    }
+
+    /// If there is a frame hook, pass it a copy of `data` and return
+    /// the result.  The returned buffer should be put back into
+    /// `self.hook_buf` afterwards to reuse the allocation.
+    fn run_hook(&mut self, dir: Direction, is_text: bool, data: &[u8]) -> Option<Vec<u8>> {
+        let hook = self.frame_hook.as_mut()?;
+        let mut buf = std::mem::take(&mut self.hook_buf);
+        buf.clear();
+        buf.extend_from_slice(data);
+        hook(dir, is_text, &mut buf);
+        Some(buf)
+    }
+
+    /// Count an encoded frame sent
+    fn count_tx(&mut self, frame: &[u8]) {
+        self.tx_frames += 1;
+        self.tx_bytes += frame.len() as u64;
+        if let Some(m) = &self.metrics {
+            m.bytes_sent(frame.len() as u64);
+        }
+        if let Some(log) = &mut self.events {
+            log.push(WsEvent::FrameOut {
+                opcode: frame[0] & 0x0F,
+                fin: frame[0] & 0x80 != 0,
+                len: frame.len(),
+            });
+        }
+    }
+
+    /// Record a change of protocol state from `before`, if any
+    fn note_state(&mut self, before: WebSocketState) {
+        if let Some(log) = &mut self.events {
+            if self.ws.state != before {
+                log.push(WsEvent::State(self.ws.state));
+            }
+        }
+    }
+
+    /// Get the message type of an incoming frame, checking that it
+    /// is valid in the current state
+    fn frame_type(&self, h: &FrameHeader) -> Result<RxMsgType, Error> {
+        use WebSocketState::{CloseSent, Open};
+        if !matches!(self.ws.state, Open | CloseSent) {
+            return Err(Error::WebSocketNotOpen);
+        }
+        Ok(match (h.opcode, self.rx_fragment) {
+            (0, Some(true)) => RxMsgType::Text,
+            (0, Some(false)) => RxMsgType::Binary,
+            (0, None) => return Err(Error::UnexpectedContinuationFrame),
+            // A new message may not start until the previous
+            // fragmented message is complete
+            (1 | 2, Some(_)) => return Err(Error::InvalidOpCode),
+            (1, None) => RxMsgType::Text,
+            (2, None) => RxMsgType::Binary,
+            (8, _) if self.ws.state == CloseSent => RxMsgType::CloseCompleted,
+            (8, _) => RxMsgType::CloseMustReply,
+            (9, _) => RxMsgType::Ping,
+            (10, _) => RxMsgType::Pong,
+            _ => return Err(Error::InvalidOpCode),
+        })
+    }
+
+    /// Check the header of a new incoming frame against RFC 6455 and
+    /// the current state, and get its message type.  An invalid
+    /// frame fails the connection.  This covers the length, masking,
+    /// opcode and the sequence of fragments, but not the reserved
+    /// bits, which an extension may use, or the payload.
+    fn check_header(&mut self, pb: PBufRdWr, h: &FrameHeader) -> Result<RxMsgType, Error> {
+        if h.len > i64::MAX as u64 {
+            // RFC 6455 requires the top bit to be clear
+            return Err(self.fail(Error::InvalidFrameLength, true));
+        }
+        self.check_masked(pb, h)?;
+        let msg_type = self.frame_type(h).map_err(|e| self.fail(e, true))?;
+        if h.opcode >= 8 && h.len > 125 {
+            return Err(self.fail(Error::InvalidFrameLength, true));
+        }
+        Ok(msg_type)
+    }
+
+    /// Count a control frame without FIN as a violation, unless that
+    /// is tolerated, closing the connection if there are too many
+    fn check_control_fin(&mut self, pb: PBufRdWr, h: &FrameHeader) -> Result<(), Error> {
+        if h.opcode >= 8
+            && !h.fin
+            && !self.quirks.fragmented_control
+            && self.violation(Violation::FragmentedControl)
+        {
+            self.close_if_open(pb, WebSocketCloseStatusCode::ProtocolError)?;
+        }
+        Ok(())
+    }
+
+    /// Reject an unmasked frame from a client, or a masked frame from
+    /// a server, by closing the connection with code 1002 (protocol
+    /// error) and returning an error, unless a server accepts
+    /// unmasked frames
+    fn check_masked(&mut self, pb: PBufRdWr, h: &FrameHeader) -> Result<(), Error> {
+        if self.role.mask_ok(h, self.quirks.unmasked) {
+            return Ok(());
+        }
+        self.close_if_open(pb, WebSocketCloseStatusCode::ProtocolError)?;
+        let e = match self.role {
+            Role::Server => Error::Unmasked,
+            Role::Client => Error::Masked,
+        };
+        Err(self.fail(e, true))
+    }
+
+    /// Start the close handshake because of a problem found whilst
+    /// receiving, unless it has already started, or `pb.wr` has been
+    /// closed so that nothing more can be sent
+    fn close_if_open(&mut self, pb: PBufRdWr, code: WebSocketCloseStatusCode) -> Result<(), Error> {
+        if self.ws.state != WebSocketState::Open || pb.wr.is_eof() {
+            return Ok(());
+        }
+        self.close(pb, code, None)
+    }
+
+    /// Record the position of a receive error, and keep it as the
+    /// failure of the connection if it is fatal.  `new_frame` is
+    /// `true` if the error relates to a frame whose header has not yet
+    /// been consumed.
+    fn fail(&mut self, e: Error, new_frame: bool) -> Error {
+        if let Some(m) = &self.metrics {
+            m.error(&e);
+        }
+        if let Some(o) = &mut self.observer {
+            o.error(&e);
+        }
+        self.error_position = Some(StreamPosition {
+            offset: self.rx_offset,
+            frame: if new_frame {
+                self.rx_frames
+            } else {
+                self.rx_frames.saturating_sub(1)
+            },
+        });
+        if self.failure.is_none() && e.is_fatal() {
+            self.failure = Some(e);
+        }
+        e
+    }
+
+    /// Record a fatal error from a receive call as the failure of the
+    /// connection, if it didn't already pass through `fail`.  Errors
+    /// from sending a reply or a `Close` whilst receiving arrive here
+    /// directly, but the input which caused them has already been
+    /// consumed, so the connection can't continue.
+    fn latch<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        result.map_err(|e| match self.failure {
+            None if e.is_fatal() => self.fail(e, false),
+            _ => e,
+        })
+    }
+
+    /// Refuse any further use of a connection which has failed
+    fn failed(&self) -> Result<(), Error> {
+        match self.failure {
+            Some(_) => Err(Error::ConnectionFailed),
+            None => Ok(()),
+        }
+    }
+
+    /// Test whether data messages may be sent right now
+    fn can_send(&self, wr: &PBufWr) -> bool {
+        !wr.is_eof()
+            && !self.rx_eof
+            && matches!(
+                self.ws.state,
+                WebSocketState::Open | WebSocketState::CloseReceived
+            )
+    }
+
+    /// In debug builds, check that the internal state is consistent
+    /// after a successful call, and that the protocol state has only
+    /// moved forwards from `before`.  This catches integration bugs,
+    /// e.g. misuse of [`WebsocketServer::inner_mut`], close to where
+    /// they occur.  This compiles to nothing in release builds.
+    fn check_invariants(&self, before: WebSocketState) {
+        use WebSocketState::{Aborted, CloseReceived, CloseSent, Closed, Open};
+        debug_assert!(
+            self.rx_message.is_none() || self.rx_fragment.is_some() || self.rx_remaining != 0,
+            "Partial message recorded outside of any frame or fragmented message"
+        );
+        debug_assert!(
+            self.rx_remaining == 0 || self.rx_frames != 0,
+            "Inside a frame before any frame header was read"
+        );
+        let after = self.ws.state;
+        debug_assert!(
+            before == after
+                || matches!(
+                    (before, after),
+                    (Open, CloseSent | CloseReceived | Closed | Aborted)
+                        | (CloseSent | CloseReceived, Closed | Aborted)
+                        | (Closed, Aborted)
+                ),
+            "Invalid protocol state transition from {before:?} to {after:?}"
+        );
+    }
+
+    /// Send an already-encoded websocket frame
+    pub(crate) fn send_frame(&mut self, mut pb: PBufRdWr, frame: &[u8]) -> Result<(), Error> {
+        self.write_frame(pb.wr.reborrow(), frame)?;
+        pb.wr.push();
+        Ok(())
+    }
+
+    /// Write a pre-encoded frame without indicating a push
+    pub(crate) fn write_frame(&mut self, mut wr: PBufWr, frame: &[u8]) -> Result<(), Error> {
+        self.failed()?;
+        if !self.can_send(&wr) {
+            Err(Error::WebSocketNotOpen)
+        } else if self.tx_fragmented && matches!(frame[0] & 0x0F, 1 | 2) {
+            // A new data message can't start until the fragmented
+            // one being sent is complete
+            Err(Error::InvalidOpCode)
+        } else {
+            wr.append(frame);
+            self.count_tx(frame);
+            Ok(())
+        }
+    }
+
+    /// Send a reply to a control frame.  If `pb.wr` has been closed,
+    /// the reply is dropped, since the peer can't be answered.  A
+    /// `Pong` is also dropped once the close handshake has started.
+    /// Sending the `CloseReply` completes the close handshake.
+    fn send_reply(
+        &mut self,
+        mut pb: PBufRdWr,
+        msg: WebSocketSendMessageType,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let pong = matches!(msg, TxMsgType::Pong);
+        if pb.wr.is_eof() || (pong && self.ws.state != WebSocketState::Open) {
+            Ok(())
+        } else {
+            let before = self.ws.state;
+            self.encode_frame(pb.wr.reborrow(), true, frame::opcode(msg, false), data)?;
+            if msg == TxMsgType::CloseReply {
+                self.ws.state = WebSocketState::Closed;
+            }
+            self.note_state(before);
+            if self.push_replies {
+                pb.wr.push();
+            }
+            Ok(())
+        }
+    }
+
+    /// Process as much data as possible from the stream.  Whilst
+    /// processing, sends back `Pong` and `CloseReply` messages as
+    /// necessary according to protocol.  If the stream is closed at a
+    /// websocket protocol level, closes the output stream `pb.wr`.
+    ///
+    /// Received message data is streamed into the `message`
+    /// pipe-buffer.  When the end of the message is reached, EOF is
+    /// indicated on the pipe-buffer (with state `Closing`).  This
+    /// takes care of websocket fragments being used to stream data.
+    /// The caller may wait for an EOF and process the entire message,
+    /// or else process the data as it comes in (streaming style).
+    /// Even partial fragments may result in data being added to the
+    /// pipe-buffer, so you can't count on seeing data with the
+    /// original fragment boundaries.  `*is_text` will be set
+    /// according to the message type: `true` for text, `false` for
+    /// binary.  When EOF is indicated on the `message` pipe-buffer,
+    /// the caller must process the contents and reset the buffer
+    /// (with `PipeBuf::reset()`) before calling this method again, so
+    /// that a new message can be read into it.
+    ///
+    /// A different `message` pipe-buffer may be passed part-way
+    /// through a message, for example to spill a very large message
+    /// to some other sink once it crosses a size threshold.  The
+    /// data will continue to be streamed into the new buffer.  Use
+    /// [`WebsocketServer::partial_message`] to find out the type of
+    /// the message and how much has been received so far.
+    ///
+    /// Returns `Ok(true)` if there was activity, `Ok(false)` if it is
     /// not possible to advance right now, or `Err(_)` in case of
     /// protocol or limit errors.  After each call check to see
     /// whether a partial or complete message was received.  In case
     /// of EOF on a message, there may be more websocket frames still
     /// to read, so call again.
+    ///
+    /// When EOF is reached on `pb.rd`, it is consumed, along with any
+    /// incomplete frame left in the buffer.  If this happens
+    /// part-way through a message, the `message` pipe-buffer is
+    /// aborted.  From then on [`WebsocketServer::transport_state`]
+    /// reports that the input has ended.  A normal EOF (`close`) is
+    /// taken as the transport shutting down cleanly, whereas an
+    /// aborted EOF (`abort`) means the connection was lost.  In that
+    /// case, unless the close handshake had already started,
+    /// [`WebsocketServer::last_close`] reports code 1006 (abnormal
+    /// closure).  In the other direction, this crate closes `pb.wr`
+    /// normally once the close handshake is done, and aborts it when
+    /// the connection is being abandoned, as by
+    /// [`WebsocketServer::close_deadline`].
+    ///
+    /// If `pb.wr` is closed by the application or some other
+    /// component, rather than by the close handshake, then `receive`
+    /// carries on consuming input and delivering messages, but drops
+    /// the replies to `Ping` and `Close`, and no longer starts the
+    /// close handshake on problems it finds.  In this case
+    /// [`WebsocketServer::transport_state`] reports that the output
+    /// has ended, and `WsEvent::OutputEnded` is logged.
+    ///
+    /// Frames and messages of any length allowed by RFC 6455 are
+    /// handled, including those over 4 GiB, even on 32-bit platforms,
+    /// since the data is streamed through and `max_msg_len` only
+    /// limits the data left unread in `message`.  A frame with the
+    /// top bit of its 64-bit length set gives
+    /// `Error::InvalidFrameLength`.
+    ///
+    /// Frames from the client must be masked, as required by RFC
+    /// 6455.  On receiving an unmasked frame, the connection is
+    /// closed with code 1002 (protocol error), and `Error::Unmasked`
+    /// is returned, unless [`WebsocketServer::set_accept_unmasked`]
+    /// has been used to allow them.
     pub fn receive(
+        &mut self,
+        pb: PBufRdWr,
+        message: PBufWr,
+        is_text: &mut bool,
+    ) -> Result<bool, Error> {
+        let result = self.receive_inner(pb, message, is_text);
+        self.latch(result)
+    }
+
+    fn receive_inner(
         &mut self,
         mut pb: PBufRdWr,
         mut message: PBufWr,
         is_text: &mut bool,
-    ) -> Result<bool, ws::Error> {
+    ) -> Result<bool, Error> {
         assert!(!message.is_eof(), "Caller must .reset() buffer after EOF");
+        self.failed()?;
+        self.check_output(&pb.wr);
+        let state_before = self.ws.state;
         let mut activity = false;
-        while !pb.rd.is_empty() {
-            // Make sure there is space to read all available data
-            let space = message.space(pb.rd.len());
-            match self.ws.read(pb.rd.data(), space) {
-                Err(ws::Error::ReadFrameIncomplete) => break,
-                Err(e) => return Err(e),
-                Ok(rr) => {
-                    pb.rd.consume(rr.len_from);
-                    let to_commit = rr.len_to;
-                    activity = true;
-                    match rr.message_type {
-                        RxMsgType::Text | RxMsgType::Binary => {
-                            *is_text = rr.message_type == RxMsgType::Text;
-                            message.commit(to_commit);
-                            if message.exceeds_limit(self.max_msg_len) {
-                                return Err(ws::Error::WriteToBufferTooSmall);
-                            }
-                            if rr.end_of_message {
-                                message.close();
-                                break;
+        let mut quota = self.read_quota.unwrap_or(usize::MAX);
+        let mut stalled = false;
+        while !pb.rd.is_empty() && quota > 0 {
+            // Keep track of frame boundaries
+            let header = match self.rx_remaining {
+                0 => match FrameHeader::parse(pb.rd.data()) {
+                    None => {
+                        stalled = true;
+                        break;
+                    }
+                    Some(h) => Some(h),
+                },
+                _ => None,
+            };
+            let new_msg = matches!(header, Some(h) if matches!(h.opcode, 1 | 2));
+            if new_msg && !self.policy.allows(header.is_some_and(|h| h.opcode == 1)) {
+                self.close_if_open(pb.reborrow(), WebSocketCloseStatusCode::InvalidMessageType)?;
+                self.rx_discard = true;
+            }
+            if self.drain_on_close
+                && self.ws.state == WebSocketState::CloseSent
+                && self.discard_message(new_msg, &mut message)
+            {
+                activity = true;
+                break;
+            }
+            let mut avail = pb.rd.len().min(quota);
+            if let Some(rl) = self.rate_limit.as_mut().filter(|rl| !rl.tripped) {
+                let bytes = rl.bytes_allowed();
+                if bytes == 0 || (new_msg && !rl.message_allowed()) {
+                    if rl.limit.action == RateLimitAction::Pause {
+                        break;
+                    }
+                    rl.tripped = true;
+                    self.close_if_open(pb.reborrow(), WebSocketCloseStatusCode::PolicyViolation)?;
+                    if self.discard_message(new_msg, &mut message) {
+                        activity = true;
+                        break;
+                    }
+                } else {
+                    avail = avail.min(bytes.min(usize::MAX as u64) as usize);
+                }
+            }
+            if let Some(b) = self.budget {
+                if (b.bytes != 0 && self.rx_offset >= b.bytes)
+                    || (new_msg && b.messages != 0 && self.rx_messages >= b.messages)
+                {
+                    self.close_if_open(pb.reborrow(), b.code)?;
+                    if self.discard_message(new_msg, &mut message) {
+                        activity = true;
+                        break;
+                    }
+                }
+            }
+            let (msg_type, header_len, remaining) = match header {
+                Some(h) => (self.check_header(pb.reborrow(), &h)?, h.header_len, h.len),
+                None => (self.rx_type, 0, self.rx_remaining),
+            };
+            // Control frames are handled only once they have arrived
+            // in full, which is at most 131 bytes, so that their data
+            // can be taken straight from `pb.rd`.  A control frame is
+            // never split by the quota or rate limit.
+            let control = header.is_some_and(|h| h.opcode >= 8);
+            if control {
+                if remaining > self.max_aux_len as u64 {
+                    return Err(self.fail(Error::WriteToBufferTooSmall, true));
+                }
+                if pb.rd.len() < header_len + remaining as usize {
+                    stalled = true;
+                    break;
+                }
+            } else if avail < header_len {
+                break; // Quota or rate limit doesn't allow for the header
+            }
+            let len = match control {
+                true => remaining as usize,
+                false => ((avail - header_len) as u64).min(remaining) as usize,
+            };
+            let (mask, pos) = header.map_or((self.rx_mask, self.rx_pos), |h| (h.mask, 0));
+            if new_msg && !self.rx_discard && remaining > len as u64 {
+                // Reserve space for the first frame up front, rather
+                // than growing the buffer chunk by chunk as the data
+                // arrives.  The length is only the peer's claim, so
+                // don't reserve more than 64KiB beyond the data which
+                // has actually arrived.  After that the buffer grows
+                // as the data comes in.
+                let ahead = (remaining - len as u64).min(PRESIZE_AHEAD as u64) as usize;
+                message.space((len + ahead).min(self.max_msg_len.max(len)));
+            }
+            let mut payload = [0; 125];
+            let space = match control {
+                true => &mut payload[..len],
+                false => message.space(len),
+            };
+            frame::unmask(mask, pos, &pb.rd.data()[header_len..][..len], space);
+            let len_from = header_len + len;
+            pb.rd.consume(len_from);
+            self.count_rx(len_from);
+            quota = quota.saturating_sub(len_from);
+            if let Some(rl) = &mut self.rate_limit {
+                rl.charge_bytes(len_from as u64);
+                if new_msg {
+                    rl.charge_message();
+                }
+            }
+            if let Some(h) = header {
+                self.start_frame(&h);
+                if new_msg {
+                    self.rx_messages += 1;
+                    if !self.rx_discard {
+                        if let Some(o) = &mut self.observer {
+                            o.message_start(msg_type == RxMsgType::Text, h.fin.then_some(h.len));
+                        }
+                    }
+                }
+                let rsv_allowed = match &self.extension {
+                    Some(ext) if h.opcode < 8 => ext.rsv_bits(),
+                    _ => 0,
+                };
+                if h.rsv & !rsv_allowed != 0 && self.violation(Violation::ReservedBits) {
+                    self.close_if_open(pb.reborrow(), WebSocketCloseStatusCode::ProtocolError)?;
+                }
+                self.check_control_fin(pb.reborrow(), &h)?;
+                self.rx_type = msg_type;
+                if h.opcode <= 2 {
+                    self.rx_fragment = (!h.fin).then_some(msg_type == RxMsgType::Text);
+                }
+            }
+            self.rx_remaining -= len as u64;
+            self.rx_pos += len as u64;
+            // End of the frame for control frames, or end of the
+            // whole message for data frames
+            let end_of_message = self.rx_remaining == 0
+                && (self.rx_fin || !matches!(msg_type, RxMsgType::Text | RxMsgType::Binary));
+            let to_commit = len;
+            activity = true;
+            match msg_type {
+                RxMsgType::Text | RxMsgType::Binary if self.rx_discard => {
+                    if end_of_message {
+                        self.rx_discard = false;
+                        self.rx_message = None;
+                    }
+                }
+                RxMsgType::Text | RxMsgType::Binary => {
+                    *is_text = msg_type == RxMsgType::Text;
+                    let mut to_commit = to_commit;
+                    let start = self.rx_message.is_none();
+                    let ext_out = match &mut self.extension {
+                        Some(ext) if to_commit > 0 || start || end_of_message => {
+                            let mut buf = std::mem::take(&mut self.ext_buf);
+                            buf.clear();
+                            buf.extend_from_slice(&space[..to_commit]);
+                            let f = ExtFrame {
+                                is_text: *is_text,
+                                rsv: self.rx_rsv,
+                                start,
+                                end: end_of_message,
+                            };
+                            let result = ext.incoming(&f, &mut buf);
+                            if let Err(e) = result {
+                                self.ext_buf = buf;
+                                let code = WebSocketCloseStatusCode::InvalidPayloadData;
+                                self.close_if_open(pb.reborrow(), code)?;
+                                return Err(self.fail(e, false));
                             }
+                            Some(buf)
+                        }
+                        _ => None,
+                    };
+                    let data = ext_out.as_deref().unwrap_or(&space[..to_commit]);
+                    let hooked = match data.len() {
+                        0 => None,
+                        _ => self.run_hook(Direction::Incoming, *is_text, data),
+                    };
+                    let data = hooked.as_deref().or(ext_out.as_deref());
+                    if let Some(crc) = &mut self.rx_crc {
+                        if start {
+                            *crc = 0;
+                            self.message_crc = None;
                         }
+                        *crc = crc32::crc32_update(*crc, data.unwrap_or(&space[..to_commit]));
+                    }
+                    match data {
+                        None => message.commit(to_commit),
+                        Some(data) => {
+                            // Replaces the uncommitted data in `space`
+                            message.append(data);
+                            to_commit = data.len();
+                        }
+                    }
+                    if let Some(buf) = hooked {
+                        self.hook_buf = buf;
+                    }
+                    if let Some(buf) = ext_out {
+                        self.ext_buf = buf;
+                    }
+                    let partial = self.rx_message.get_or_insert(PartialMessage {
+                        is_text: *is_text,
+                        len: 0,
+                    });
+                    partial.len += to_commit as u64;
+                    let msg_len = partial.len;
+                    if end_of_message {
+                        self.rx_message = None;
+                        self.message_crc = self.rx_crc;
+                    }
+                    if message.exceeds_limit(self.max_msg_len) {
+                        return Err(self.fail(Error::WriteToBufferTooSmall, false));
+                    }
+                    if end_of_message {
+                        if let Some(m) = &self.metrics {
+                            m.message_received(*is_text, msg_len);
+                        }
+                        if let Some(o) = &mut self.observer {
+                            o.message_end(*is_text, msg_len);
+                        }
+                        message.close();
+                        break;
+                    }
+                }
+                RxMsgType::CloseCompleted
+                | RxMsgType::CloseMustReply
+                | RxMsgType::Ping
+                | RxMsgType::Pong => {
+                    // The whole frame is here, see above
+                    let data = &space[..to_commit];
+                    if let Some(o) = &mut self.observer {
+                        o.control_frame(self.rx_opcode, data);
+                    }
+                    match msg_type {
                         RxMsgType::CloseCompleted => {
+                            if let Some(info) = &mut self.last_close {
+                                info.completed = true;
+                            }
                             pb.wr.close();
                         }
-                        RxMsgType::CloseMustReply | RxMsgType::Ping | RxMsgType::Pong => {
-                            // Due to text/binary message
-                            // fragmentation and embedded_websocket's
-                            // behaviour regarding partial frames, we
-                            // have to build up close/ping/pong data
-                            // separately.
-                            self.in_data.extend_from_slice(&space[..to_commit]);
-                            if self.in_data.len() > self.max_aux_len {
-                                return Err(ws::Error::WriteToBufferTooSmall);
-                            }
-                            if rr.end_of_message {
-                                match rr.message_type {
-                                    RxMsgType::CloseMustReply => {
-                                        self.send_reply(pb.reborrow(), TxMsgType::CloseReply)?;
-                                        pb.wr.close();
-                                    }
-                                    RxMsgType::Ping => {
-                                        self.send_reply(pb.reborrow(), TxMsgType::Pong)?;
-                                    }
-                                    RxMsgType::Pong => (), // Ignore Pongs
-                                    _ => (),
-                                }
-                                self.in_data.clear();
+                        RxMsgType::CloseMustReply => {
+                            let mut info = CloseInfo::from_payload(true, data);
+                            let code;
+                            let reply = if !close::reason_ok(data) {
+                                // Fail with 1007 (invalid payload data)
+                                code = 1007_u16.to_be_bytes();
+                                &code[..]
+                            } else if let Some(v) = violation::check_close(data) {
+                                // A payload which can't be echoed is
+                                // always answered with 1002 (protocol
+                                // error), whatever the tolerance
+                                self.violation(v);
+                                code = 1002_u16.to_be_bytes();
+                                &code[..]
+                            } else {
+                                data
+                            };
+                            self.send_reply(pb.reborrow(), TxMsgType::CloseReply, reply)?;
+                            info.completed = true;
+                            self.closed(info);
+                            pb.wr.close();
+                        }
+                        RxMsgType::Ping => {
+                            self.send_reply(pb.reborrow(), TxMsgType::Pong, data)?;
+                        }
+                        RxMsgType::Pong => {
+                            self.ping_stats.pong(&mut self.pong_next, data);
+                            match self.pings_out.iter().position(|p| p == data) {
+                                Some(i) => drop(self.pings_out.drain(..=i)),
+                                None => self.unmatched_pongs += 1,
                             }
                         }
+                        _ => (),
                     }
                 }
             }
         }
+        if pb.rd.has_pending_eof() && (pb.rd.is_empty() || stalled) {
+            if self.rx_message.take().is_some() && !self.rx_discard {
+                message.abort();
+            }
+            self.input_ended(pb.reborrow())?;
+            activity = true;
+        }
+        self.check_invariants(state_before);
+        Ok(activity)
+    }
+
+    /// Process as much data as possible from the stream, given the
+    /// incoming and outgoing halves separately.  This is the same as
+    /// [`WebsocketServer::receive`], for callers which keep the two
+    /// halves of the transport stream in different places.
+    pub fn receive_rd_wr(
+        &mut self,
+        rd: PBufRd,
+        wr: PBufWr,
+        message: PBufWr,
+        is_text: &mut bool,
+    ) -> Result<bool, Error> {
+        self.receive(PBufRdWr { rd, wr }, message, is_text)
+    }
+
+    /// Process as much data as possible from the stream, passing
+    /// each complete message to `handler(is_text, data)`.  This
+    /// calls [`WebsocketServer::receive`] repeatedly, resetting the
+    /// `message` pipe-buffer after each message, until no further
+    /// progress can be made.  Data of a message which is still
+    /// incomplete is left in `message`, ready for the next call.  A
+    /// message aborted by EOF on `pb.rd` is discarded.
+    ///
+    /// Returns `Ok(true)` if there was activity, `Ok(false)` if it is
+    /// not possible to advance right now, or `Err(_)` in case of
+    /// protocol or limit errors, as for [`WebsocketServer::receive`].
+    pub fn receive_all(
+        &mut self,
+        mut pb: PBufRdWr,
+        message: &mut PipeBuf,
+        mut handler: impl FnMut(bool, &[u8]),
+    ) -> Result<bool, Error> {
+        let mut activity = false;
+        let mut is_text = false;
+        loop {
+            let act = self.receive(pb.reborrow(), message.wr(), &mut is_text)?;
+            activity |= act;
+            match message.state() {
+                PBufState::Closing => {
+                    handler(is_text, message.rd().data());
+                    message.reset();
+                }
+                PBufState::Aborting => message.reset(),
+                _ if !act => break,
+                _ => (),
+            }
+        }
+        Ok(activity)
+    }
+
+    /// Iterate over the complete messages which can be received from
+    /// the stream right now.  This is a simpler alternative to
+    /// [`WebsocketServer::receive`] for applications which don't need
+    /// to stream message data.  Each message is copied out into a
+    /// [`Message`], and incomplete messages are kept in a buffer
+    /// internal to the connection until the rest arrives, so this
+    /// should not be mixed with calls to `receive` except between
+    /// messages.  Note that `max_msg_len` limits the size of the
+    /// whole message in this case.
+    ///
+    /// The iterator ends when no further progress can be made, or
+    /// after returning an error.  A message aborted by EOF on `pb.rd`
+    /// is discarded.
+    pub fn messages<'a>(
+        &'a mut self,
+        mut pb: PBufRdWr<'a>,
+    ) -> impl Iterator<Item = Result<Message, Error>> + 'a {
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let mut message = std::mem::replace(&mut self.rx_buf, PipeBuf::new());
+            let mut is_text = false;
+            let result = loop {
+                match self.receive(pb.reborrow(), message.wr(), &mut is_text) {
+                    Err(e) => break Some(Err(e)),
+                    Ok(act) => match message.state() {
+                        PBufState::Closing => {
+                            let data = message.rd().data().to_vec();
+                            message.reset();
+                            break Some(Ok(Message { is_text, data }));
+                        }
+                        PBufState::Aborting => message.reset(),
+                        _ if !act => break None,
+                        _ => (),
+                    },
+                }
+            };
+            self.rx_buf = message;
+            failed = matches!(result, Some(Err(_)));
+            result
+        })
+    }
+
+    /// Process incoming frames at a low level, for protocol
+    /// analysers or custom extensions.  Each call processes at most
+    /// one frame.  When a new frame starts, `*header` is set to its
+    /// header.  Its payload, unmasked, is then streamed into the
+    /// `payload` pipe-buffer, and EOF is indicated on that
+    /// pipe-buffer at the end of the frame.  As for
+    /// [`WebsocketServer::receive`], the caller must process the
+    /// contents and reset the buffer before calling again, and
+    /// `max_msg_len` limits the amount of unread data.
+    ///
+    /// No attempt is made to assemble fragmented messages, but the
+    /// frame headers are checked as for [`WebsocketServer::receive`]:
+    /// an unmasked frame, an invalid or out-of-sequence opcode, a
+    /// control frame over 125 bytes or a length with the top bit set
+    /// fails the connection, and a control frame without FIN counts
+    /// as a violation.  The reserved bits are passed on in
+    /// `FrameHeader::rsv` for an extension to interpret.  Payloads
+    /// are not checked at all, so it is up to the caller to check
+    /// that text, including the reason of a `Close`, is valid UTF-8.
+    /// `Ping` and `Close` are not replied to automatically, so the
+    /// caller must do this using [`WebsocketServer::send`], although
+    /// the protocol state is still updated when a `Close` arrives.
+    /// This should not be mixed with calls to
+    /// [`WebsocketServer::receive`] except between messages.
+    ///
+    /// Returns `Ok(true)` if there was activity, `Ok(false)` if it is
+    /// not possible to advance right now, or `Err(_)` for an invalid
+    /// frame or if the payload limit is exceeded.  EOF on `pb.rd` is
+    /// handled as for [`WebsocketServer::receive`].
+    pub fn receive_frame(
+        &mut self,
+        pb: PBufRdWr,
+        payload: PBufWr,
+        header: &mut Option<FrameHeader>,
+    ) -> Result<bool, Error> {
+        let result = self.receive_frame_inner(pb, payload, header);
+        self.latch(result)
+    }
+
+    fn receive_frame_inner(
+        &mut self,
+        mut pb: PBufRdWr,
+        mut payload: PBufWr,
+        header: &mut Option<FrameHeader>,
+    ) -> Result<bool, Error> {
+        assert!(!payload.is_eof(), "Caller must .reset() buffer after EOF");
+        self.failed()?;
+        let state_before = self.ws.state;
+        let mut activity = false;
+        let mut quota = self.read_quota.unwrap_or(usize::MAX);
+        let mut stalled = false;
+        while !pb.rd.is_empty() && quota > 0 {
+            if self.rx_remaining == 0 {
+                let Some(h) = FrameHeader::parse(pb.rd.data()) else {
+                    stalled = true;
+                    break;
+                };
+                let msg_type = self.check_header(pb.reborrow(), &h)?;
+                pb.rd.consume(h.header_len);
+                self.count_rx(h.header_len);
+                quota = quota.saturating_sub(h.header_len);
+                self.start_frame(&h);
+                self.check_control_fin(pb.reborrow(), &h)?;
+                if h.opcode <= 2 {
+                    self.rx_fragment = (!h.fin).then_some(msg_type == RxMsgType::Text);
+                }
+                *header = Some(h);
+                activity = true;
+                if h.len == 0 {
+                    payload.close();
+                    break;
+                }
+                continue;
+            }
+            let len = (pb.rd.len().min(quota) as u64).min(self.rx_remaining) as usize;
+            let space = payload.space(len);
+            frame::unmask(self.rx_mask, self.rx_pos, &pb.rd.data()[..len], space);
+            payload.commit(len);
+            pb.rd.consume(len);
+            self.count_rx(len);
+            quota -= len;
+            self.rx_remaining -= len as u64;
+            self.rx_pos += len as u64;
+            activity = true;
+            if payload.exceeds_limit(self.max_msg_len) {
+                return Err(self.fail(Error::WriteToBufferTooSmall, false));
+            }
+            if self.rx_remaining == 0 {
+                payload.close();
+                break;
+            }
+        }
+        if pb.rd.has_pending_eof() && (pb.rd.is_empty() || stalled) {
+            if self.rx_remaining != 0 {
+                payload.abort();
+            }
+            self.input_ended(pb.reborrow())?;
+            activity = true;
+        }
+        self.check_invariants(state_before);
         Ok(activity)
     }
+
+    /// Forward incoming frames unchanged to `out`, for example in a
+    /// reverse proxy which passes client frames on to a backend
+    /// server.  Each frame header is checked as for
+    /// [`WebsocketServer::receive_frame`] before any of the frame is
+    /// forwarded, so that an invalid frame is never passed on, and
+    /// the protocol state is tracked, but payloads are not decoded.
+    /// Since client frames are masked, they may be passed on
+    /// byte-for-byte to another server.  If `unmask` is `true`, then
+    /// the masking is removed instead, as required when passing the
+    /// frames on to a client.
+    ///
+    /// `Ping` and `Close` are forwarded rather than replied to, so
+    /// the other end is expected to reply.  If `out` holds more than
+    /// `max_msg_len` bytes of unread data, forwarding pauses until it
+    /// has been drained.  When EOF is reached on `pb.rd`, `out` is
+    /// closed, or aborted if EOF occurs part-way through a frame.
+    ///
+    /// Returns `Ok(true)` if there was activity, `Ok(false)` if it is
+    /// not possible to advance right now, or `Err(_)` in case of an
+    /// invalid frame.
+    pub fn forward_frames(
+        &mut self,
+        pb: PBufRdWr,
+        out: PBufWr,
+        unmask: bool,
+    ) -> Result<bool, Error> {
+        let result = self.forward_frames_inner(pb, out, unmask);
+        self.latch(result)
+    }
+
+    fn forward_frames_inner(
+        &mut self,
+        mut pb: PBufRdWr,
+        mut out: PBufWr,
+        unmask: bool,
+    ) -> Result<bool, Error> {
+        self.failed()?;
+        let state_before = self.ws.state;
+        let mut activity = false;
+        let mut stalled = false;
+        while !pb.rd.is_empty() && !out.exceeds_limit(self.max_msg_len) {
+            if self.rx_remaining == 0 {
+                let Some(h) = FrameHeader::parse(pb.rd.data()) else {
+                    stalled = true;
+                    break;
+                };
+                let msg_type = self.check_header(pb.reborrow(), &h)?;
+                let data = &pb.rd.data()[..h.header_len];
+                if unmask && h.mask.is_some() {
+                    let hdr = out.space(h.header_len - 4);
+                    hdr.copy_from_slice(&data[..h.header_len - 4]);
+                    hdr[1] &= 0x7F;
+                    out.commit(h.header_len - 4);
+                } else {
+                    out.append(data);
+                }
+                pb.rd.consume(h.header_len);
+                self.count_rx(h.header_len);
+                self.start_frame(&h);
+                self.check_control_fin(pb.reborrow(), &h)?;
+                if h.opcode <= 2 {
+                    self.rx_fragment = (!h.fin).then_some(msg_type == RxMsgType::Text);
+                }
+                activity = true;
+                continue;
+            }
+            let len = (pb.rd.len() as u64).min(self.rx_remaining) as usize;
+            let data = &pb.rd.data()[..len];
+            if unmask {
+                frame::unmask(self.rx_mask, self.rx_pos, data, out.space(len));
+                out.commit(len);
+            } else {
+                out.append(data);
+            }
+            pb.rd.consume(len);
+            self.count_rx(len);
+            self.rx_remaining -= len as u64;
+            self.rx_pos += len as u64;
+            activity = true;
+        }
+        if activity {
+            out.push();
+        }
+        if pb.rd.has_pending_eof() && (pb.rd.is_empty() || stalled) {
+            if self.rx_remaining != 0 {
+                out.abort();
+            } else {
+                out.close();
+            }
+            self.input_ended(pb.reborrow())?;
+            activity = true;
+        }
+        self.check_invariants(state_before);
+        Ok(activity)
+    }
+
+    /// Discard the rest of the incoming message which is starting or
+    /// in progress.  Returns `true` if a partial message had to be
+    /// aborted, in which case `receive` must return to let the caller
+    /// reset the message buffer.
+    fn discard_message(&mut self, new_msg: bool, message: &mut PBufWr) -> bool {
+        if self.rx_discard {
+            return false;
+        }
+        if new_msg {
+            self.rx_discard = true;
+        } else if self.rx_message.is_some() {
+            self.rx_discard = true;
+            message.abort();
+            return true;
+        }
+        false
+    }
+
+    /// Record the start of the close handshake
+    fn closed(&mut self, info: CloseInfo) {
+        if let Some(m) = &self.metrics {
+            m.closed(info.by_peer, info.code);
+        }
+        if let Some(o) = &mut self.observer {
+            o.closed(info.by_peer, info.code, &info.reason);
+        }
+        self.last_close = Some(info);
+    }
+
+    /// Record bytes consumed from `pb.rd`
+    fn count_rx(&mut self, len: usize) {
+        self.rx_offset += len as u64;
+        match &self.clock {
+            Some(c) => self.last_rx = Some(c.now()),
+            None => self.rx_active = true,
+        }
+        if let Some(m) = &self.metrics {
+            m.bytes_received(len as u64);
+        }
+    }
+
+    /// Record a conformance violation by the peer.  Returns `true` if
+    /// the tolerance has been exceeded, and the connection should be
+    /// closed.
+    fn violation(&mut self, v: Violation) -> bool {
+        self.violations = self.violations.saturating_add(1);
+        if let Some(log) = &mut self.events {
+            log.push(WsEvent::Violation(v));
+        }
+        self.violation_tolerance
+            .is_some_and(|t| self.violations > t)
+    }
+
+    /// Bookkeeping for the start of an incoming frame, once its
+    /// header has been consumed
+    fn start_frame(&mut self, h: &FrameHeader) {
+        self.rx_frames += 1;
+        self.rx_opcode = h.opcode;
+        self.rx_fin = h.fin;
+        self.rx_rsv = h.rsv;
+        self.rx_mask = h.mask;
+        self.rx_pos = 0;
+        self.rx_remaining = h.len;
+        if let Some(log) = &mut self.events {
+            log.push(WsEvent::FrameIn {
+                opcode: h.opcode,
+                fin: h.fin,
+                len: h.len,
+            });
+        }
+        if h.opcode == 8 {
+            let before = self.ws.state;
+            self.ws.state = match before {
+                WebSocketState::CloseSent => WebSocketState::Closed,
+                _ => WebSocketState::CloseReceived,
+            };
+            self.note_state(before);
+        }
+    }
+
+    /// Notice if `pb.wr` has been closed by the application or some
+    /// other component, rather than as part of the close handshake
+    fn check_output(&mut self, wr: &PBufWr) {
+        if wr.is_eof() && !self.output_ended && self.ws.state == WebSocketState::Open {
+            self.output_ended = true;
+            if let Some(log) = &mut self.events {
+                log.push(WsEvent::OutputEnded);
+            }
+        }
+    }
+
+    /// Handle EOF on `pb.rd`, discarding any incomplete frame
+    fn input_ended(&mut self, mut pb: PBufRdWr) -> Result<(), Error> {
+        self.count_rx(pb.rd.len());
+        pb.rd.consume(pb.rd.len());
+        let aborted = pb.rd.is_aborted();
+        pb.rd.consume_eof();
+        if aborted && self.last_close.is_none() {
+            // Code 1006 is reserved for reporting a connection which
+            // ended without a `Close`, and is never sent
+            self.closed(CloseInfo {
+                by_peer: true,
+                code: Some(1006),
+                reason: String::new(),
+                completed: false,
+            });
+        }
+        if self.close_on_input_eof && !pb.wr.is_eof() {
+            if aborted {
+                pb.wr.abort();
+            } else {
+                self.close_if_open(pb.reborrow(), WebSocketCloseStatusCode::EndpointUnavailable)?;
+                pb.wr.close();
+            }
+        }
+        self.rx_eof = true;
+        if let Some(log) = &mut self.events {
+            log.push(match aborted {
+                true => WsEvent::InputAborted,
+                false => WsEvent::InputEnded,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    const KEY: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    // Allocator which counts the allocations made by each thread, so
+    // that tests running in parallel don't disturb each other
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    // Client frame header, masked with `KEY`
+    fn header(fin_op: u8, len: u64) -> Vec<u8> {
+        let mut v = vec![fin_op];
+        match len {
+            0..=125 => v.push(0x80 | len as u8),
+            126..=65535 => {
+                v.push(0x80 | 126);
+                v.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                v.push(0x80 | 127);
+                v.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+        v.extend_from_slice(&KEY);
+        v
+    }
+
+    // Connection which has completed the handshake, with the reply
+    // already taken from the output
+    fn connect(max_msg_len: usize) -> (PipeBufPair, WebsocketServer) {
+        let mut pair = PipeBufPair::new();
+        pair.lower().wr.append(
+            b"GET /chat HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        );
+        let ws = WebsocketServer::from_http(pair.upper(), None, max_msg_len, 125)
+            .unwrap()
+            .unwrap();
+        take_output(&mut pair);
+        (pair, ws)
+    }
+
+    // Take everything the server has written
+    fn take_output(pair: &mut PipeBufPair) -> Vec<u8> {
+        let mut lower = pair.lower();
+        let out = lower.rd.data().to_vec();
+        lower.rd.consume(out.len());
+        out
+    }
+
+    // Attempt the handshake with a single call
+    fn handshake(request: &[u8]) -> Result<Option<WebsocketServer>, HandshakeError> {
+        let mut pair = PipeBufPair::new();
+        pair.lower().wr.append(request);
+        WebsocketServer::from_http(pair.upper(), None, 1000, 125)
+    }
+
+    #[test]
+    fn long_key_without_upgrade_is_rejected() {
+        let key = "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        for upgrade in ["upgrade: websocket", "Upgrade: h2c", "X-Upgrade: websocket"] {
+            let request = format!(
+                "GET / HTTP/1.1\r\nHost: x\r\n{upgrade}\r\n{key}Sec-WebSocket-Version: 13\r\n\r\n"
+            );
+            assert!(handshake(request.as_bytes()).is_err(), "{upgrade}");
+        }
+    }
+
+    #[test]
+    fn header_names_and_token_in_any_case() {
+        let request = b"GET / HTTP/1.1\r\nhost: x\r\nupgrade: WebSocket\r\n\
+                        connection: upgrade\r\nSEC-WEBSOCKET-KEY: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        sec-websocket-version: 13\r\n\r\n";
+        assert!(handshake(request).unwrap().is_some());
+    }
+
+    #[test]
+    fn long_protocol_without_upgrade_is_rejected() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\nUpgrade: h2c\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Protocol: a-subprotocol-name-longer-than-24-bytes\r\n\r\n";
+        assert!(handshake(request).is_err());
+    }
+
+    // Complete client frame
+    fn frame(fin_op: u8, payload: &[u8]) -> Vec<u8> {
+        let mut v = header(fin_op, payload.len() as u64);
+        v.extend(payload.iter().enumerate().map(|(i, b)| b ^ KEY[i % 4]));
+        v
+    }
+
+    // Send the peer's `Close` with the given payload, and return the
+    // server's reply
+    fn close_reply(quirks: Quirks, payload: &[u8]) -> Vec<u8> {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_quirks(quirks);
+        pair.lower().wr.append(&frame(0x88, payload));
+        ws.receive(pair.upper(), PipeBuf::new().wr(), &mut false)
+            .unwrap();
+        take_output(&mut pair)
+    }
+
+    #[test]
+    fn invalid_close_payload_is_answered_with_1002() {
+        for quirks in [
+            Quirks::default(),
+            Quirks {
+                fragmented_control: true,
+                ..Quirks::default()
+            },
+        ] {
+            assert_eq!(close_reply(quirks, &[3]), [0x88, 2, 3, 0xea]);
+            assert_eq!(close_reply(quirks, &[3, 0xed]), [0x88, 2, 3, 0xea]);
+            assert_eq!(
+                close_reply(quirks, &[3, 0xe8, b'x']),
+                [0x88, 3, 3, 0xe8, b'x']
+            );
+        }
+    }
+
+    #[test]
+    fn broadcast_during_fragmented_send() {
+        let (mut pair, mut ws) = connect(1000);
+        let text = Broadcaster::text("b");
+        let ping = Broadcaster::new(TxMsgType::Ping, b"p").unwrap();
+        ws.send(pair.upper(), TxMsgType::Text, false, b"a1")
+            .unwrap();
+        assert_eq!(text.send(&mut ws, pair.upper()), Err(Error::InvalidOpCode));
+        ping.send(&mut ws, pair.upper()).unwrap();
+        ws.send(pair.upper(), TxMsgType::Text, true, b"a2").unwrap();
+        text.send(&mut ws, pair.upper()).unwrap();
+        assert_eq!(
+            take_output(&mut pair),
+            [
+                &[0x01, 2, b'a', b'1'][..],
+                &[0x89, 1, b'p'],
+                &[0x80, 2, b'a', b'2'],
+                &[0x81, 1, b'b']
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn fragmented_control_frame() {
+        for (tolerate, violations) in [(false, 1), (true, 0)] {
+            let (mut pair, mut ws) = connect(1000);
+            ws.set_quirks(Quirks {
+                fragmented_control: tolerate,
+                ..Quirks::default()
+            });
+            pair.lower().wr.append(&frame(0x09, b"p"));
+            ws.receive(pair.upper(), PipeBuf::new().wr(), &mut false)
+                .unwrap();
+            assert_eq!(take_output(&mut pair), [0x8a, 1, b'p']);
+            assert_eq!(ws.violations(), violations);
+        }
+    }
+
+    #[test]
+    fn close_code_validity_ignores_tolerance() {
+        for tolerance in [None, Some(0), Some(100)] {
+            for code in [999_u16, 1005, 1006, 1015, 1016, 2999, 5000] {
+                let (mut pair, mut ws) = connect(1000);
+                ws.set_violation_tolerance(tolerance);
+                pair.lower().wr.append(&frame(0x88, &code.to_be_bytes()));
+                ws.receive(pair.upper(), PipeBuf::new().wr(), &mut false)
+                    .unwrap();
+                assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xea], "{code}");
+                assert_eq!(ws.violations(), 1);
+                assert_eq!(ws.last_close().and_then(|c| c.code), Some(code));
+            }
+            for code in [1000_u16, 1003, 1007, 1014, 3000, 4999] {
+                let (mut pair, mut ws) = connect(1000);
+                ws.set_violation_tolerance(tolerance);
+                pair.lower().wr.append(&frame(0x88, &code.to_be_bytes()));
+                ws.receive(pair.upper(), PipeBuf::new().wr(), &mut false)
+                    .unwrap();
+                let [hi, lo] = code.to_be_bytes();
+                assert_eq!(take_output(&mut pair), [0x88, 2, hi, lo], "{code}");
+                assert_eq!(ws.violations(), 0);
+            }
+        }
+    }
+
+    #[cfg(feature = "stomp")]
+    #[test]
+    fn stomp_content_length_overflow() {
+        let message = b"SEND\ncontent-length:18446744073709551615\n\nbody\0";
+        assert_eq!(parse_stomp(message), Err(Error::InvalidPayload));
+        let message = b"SEND\ncontent-length:5\n\nbody\0";
+        assert_eq!(parse_stomp(message), Err(Error::InvalidPayload));
+    }
+
+    #[test]
+    fn declared_length_does_not_reserve_space() {
+        let (mut pair, mut ws) = connect(usize::MAX);
+        pair.lower().wr.append(&header(0x82, 1 << 40));
+        pair.lower().wr.append(&[0; 100]);
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().len(), 100);
+        assert_eq!(ws.bytes_needed(pair.upper().rd), (1 << 40) - 100);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn snapshot_keeps_configuration() {
+        let clock = || Duration::ZERO;
+        let (_pair, mut ws) = connect(1000);
+        ws.set_message_policy(MessagePolicy::TextOnly);
+        ws.set_rate_limit(
+            Some(RateLimit {
+                messages: 10,
+                bytes: 0,
+                window: Duration::from_secs(1),
+                action: RateLimitAction::Close,
+            }),
+            &clock,
+        );
+        ws.set_budget(Some(Budget {
+            messages: 5,
+            bytes: 500,
+            code: WebSocketCloseStatusCode::EndpointUnavailable,
+        }));
+        ws.set_violation_tolerance(Some(3));
+        ws.set_quirks(Quirks {
+            unmasked: true,
+            ..Quirks::default()
+        });
+        ws.set_max_frame_len(Some(100));
+        ws.set_read_quota(Some(50));
+        ws.set_watermarks(Some((10, 20)));
+        ws.set_message_checksum(true);
+        ws.set_event_log(8);
+        let snap = ws.snapshot().unwrap();
+        let json = serde_json::to_string(&snap).unwrap();
+        let snap2: WsSnapshot = serde_json::from_str(&json).unwrap();
+        let ws2 = WebsocketServer::from_snapshot(&snap2, &clock).unwrap();
+        assert_eq!(ws2.snapshot(), Some(snap));
+        assert_eq!(ws2.policy, MessagePolicy::TextOnly);
+        assert_eq!(ws2.budget.unwrap().code, ws.budget.unwrap().code);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn snapshot_with_bad_subprotocol_is_refused() {
+        let (_pair, ws) = connect(1000);
+        let mut value = serde_json::to_value(ws.snapshot().unwrap()).unwrap();
+        value["subprotocol"] = "x".repeat(25).into();
+        let snap: WsSnapshot = serde_json::from_value(value.clone()).unwrap();
+        let clock = || Duration::ZERO;
+        assert!(WebsocketServer::from_snapshot(&snap, &clock).is_err());
+        value["subprotocol"] = "x".repeat(24).into();
+        value["state"] = 7.into();
+        let snap: WsSnapshot = serde_json::from_value(value).unwrap();
+        assert!(WebsocketServer::from_snapshot(&snap, &clock).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn snapshot_with_bad_limits_is_clamped() {
+        let (_pair, ws) = connect(1000);
+        let mut value = serde_json::to_value(ws.snapshot().unwrap()).unwrap();
+        value["max_frame_len"] = 0.into();
+        value["read_quota"] = 0.into();
+        value["event_log"] = usize::MAX.into();
+        value["pings_out"] = vec![vec![0_u8; 8]; 1000].into();
+        let snap: WsSnapshot = serde_json::from_value(value).unwrap();
+        let mut ws = WebsocketServer::from_snapshot(&snap, &|| Duration::ZERO).unwrap();
+        assert_eq!(ws.outstanding_pings(), MAX_PINGS_OUT);
+
+        let mut pair = PipeBufPair::new();
+        ws.send_text(pair.upper(), "ab").unwrap();
+        assert_eq!(take_output(&mut pair), [0x01, 1, b'a', 0x80, 1, b'b']);
+        pair.lower().wr.append(&frame(0x82, b"cd"));
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().data(), b"cd");
+        assert!(msg.rd().has_pending_eof());
+    }
+
+    #[test]
+    fn fatal_error_latches_failed_state() {
+        let (mut pair, mut ws) = connect(1000);
+        pair.lower().wr.append(&frame(0x83, b"x"));
+        let mut msg = PipeBuf::new();
+        let err = ws.receive(pair.upper(), msg.wr(), &mut false).unwrap_err();
+        assert_ne!(err, Error::ConnectionFailed);
+        assert_eq!(ws.failure(), Some(&err));
+        assert_eq!(ws.state(), ConnectionState::Failed);
+        assert_eq!(
+            ws.receive(pair.upper(), msg.wr(), &mut false),
+            Err(Error::ConnectionFailed)
+        );
+        assert_eq!(
+            ws.send_text(pair.upper(), "a"),
+            Err(Error::ConnectionFailed)
+        );
+        ws.close(pair.upper(), WebSocketCloseStatusCode::ProtocolError, None)
+            .unwrap();
+        assert_eq!(ws.state(), ConnectionState::Failed);
+        assert_eq!(ws.failure(), Some(&err));
+    }
+
+    #[test]
+    fn header_limit() {
+        let mut request = String::from("GET / HTTP/1.1\r\nHost: x\r\n");
+        for i in 0..40 {
+            request.push_str(&format!("X-Header-{i}: {i}\r\n"));
+        }
+        request.push_str(
+            "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+        );
+        let accept = |max: Option<usize>| {
+            let mut pair = PipeBufPair::new();
+            pair.lower().wr.append(request.as_bytes());
+            let mut hs = ServerHandshake::new(None, 1000, 125);
+            if let Some(max) = max {
+                hs.set_max_headers(max);
+            }
+            hs.accept_scan(pair.upper(), |_, _| ())
+        };
+        let err = accept(None).err().unwrap();
+        assert_eq!(err, HandshakeError::TooManyHeaders);
+        assert_eq!(err.status(), 431);
+        assert!(accept(Some(45)).unwrap().is_some());
+        assert!(accept(Some(1000)).unwrap().is_some());
+        assert_eq!(accept(Some(44)).err(), Some(HandshakeError::TooManyHeaders));
+        assert_eq!(accept(Some(4)).err(), Some(HandshakeError::TooManyHeaders));
+    }
+
+    #[test]
+    fn request_arriving_bytewise() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+                        Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Version: 13\r\n\r\n";
+        let mut hs = ServerHandshake::new(None, 1000, 125);
+        let mut pair = PipeBufPair::new();
+        let mut pair2 = PipeBufPair::new();
+        for (i, b) in request.iter().enumerate() {
+            pair.lower().wr.append(&[*b]);
+            pair2.lower().wr.append(&[*b]);
+            let ws = hs.accept_scan(pair.upper(), |_, _| ()).unwrap();
+            let ws2 = WebsocketServer::from_http(pair2.upper(), None, 1000, 125).unwrap();
+            let last = i == request.len() - 1;
+            assert_eq!(ws.is_some(), last);
+            assert_eq!(ws2.is_some(), last);
+        }
+        assert_eq!(take_output(&mut pair), take_output(&mut pair2));
+    }
+
+    #[test]
+    fn length_over_4gib_is_streamed() {
+        let len = 5 << 30;
+        let (mut pair, mut ws) = connect(4096);
+        pair.lower().wr.append(&header(0x82, len));
+        let mut msg = PipeBuf::new();
+        for i in 1..=3 {
+            pair.lower().wr.append(&[0; 4096]);
+            ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+            assert_eq!(msg.rd().len(), 4096);
+            msg.rd().consume(4096);
+            assert_eq!(ws.partial_message().unwrap().len, i * 4096);
+            assert_eq!(ws.bytes_needed(pair.upper().rd), len - i * 4096);
+        }
+        // The limit applies to the data left unread
+        pair.lower().wr.append(&[0; 4097]);
+        assert_eq!(
+            ws.receive(pair.upper(), msg.wr(), &mut false),
+            Err(Error::WriteToBufferTooSmall)
+        );
+        assert_eq!(ws.state(), ConnectionState::Failed);
+    }
+
+    #[test]
+    fn length_with_top_bit_is_rejected() {
+        let (mut pair, mut ws) = connect(usize::MAX);
+        pair.lower().wr.append(&header(0x82, 1 << 63));
+        assert_eq!(
+            ws.receive(pair.upper(), PipeBuf::new().wr(), &mut false),
+            Err(Error::InvalidFrameLength)
+        );
+        let (mut pair, mut ws) = connect(usize::MAX);
+        pair.lower().wr.append(&header(0x82, i64::MAX as u64));
+        pair.lower().wr.append(&[0; 10]);
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().len(), 10);
+    }
+
+    // Abort the input after `data`, with `set_close_on_input_eof`,
+    // returning the message buffer
+    fn abort_input(data: &[u8]) -> (PipeBufPair, WebsocketServer, PipeBuf) {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_close_on_input_eof(true);
+        ws.set_event_log(8);
+        pair.lower().wr.append(data);
+        pair.lower().wr.abort();
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        let close = ws.last_close().unwrap();
+        assert_eq!((close.code, close.by_peer), (Some(1006), true));
+        assert!(ws.events().any(|e| *e == WsEvent::InputAborted));
+        // No `Close` is sent, and the output is aborted
+        let lower = pair.lower();
+        assert!(lower.rd.is_empty());
+        assert!(lower.rd.has_pending_eof() && lower.rd.is_aborted());
+        (pair, ws, msg)
+    }
+
+    #[test]
+    fn input_aborted_mid_message() {
+        let (_pair, ws, mut msg) = abort_input(&frame(0x02, b"ab"));
+        assert_eq!(ws.partial_message(), None);
+        let rd = msg.rd();
+        assert_eq!(rd.data(), b"ab");
+        assert!(rd.has_pending_eof() && rd.is_aborted());
+    }
+
+    #[test]
+    fn input_aborted_between_messages() {
+        let (_pair, _ws, mut msg) = abort_input(&frame(0x82, b"ab"));
+        let rd = msg.rd();
+        assert_eq!(rd.data(), b"ab");
+        assert!(rd.has_pending_eof() && !rd.is_aborted());
+    }
+
+    #[test]
+    fn input_closed_between_messages() {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_close_on_input_eof(true);
+        pair.lower().wr.append(&frame(0x82, b"ab"));
+        pair.lower().wr.close();
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(ws.last_close().unwrap().code, Some(1001));
+        assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xe9]);
+        let lower = pair.lower();
+        assert!(lower.rd.has_pending_eof() && !lower.rd.is_aborted());
+    }
+
+    #[test]
+    fn from_http_scan_does_not_allocate() {
+        let mut pair = PipeBufPair::new();
+        pair.lower().wr.append(
+            b"GET /chat HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Origin: http://example.com\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        );
+        // Growing `pb.wr` for the reply is allowed
+        pair.upper().wr.space(1024);
+        // Header values must be slices of the request data
+        let input = pair.upper().rd.data().as_ptr_range();
+        let mut origin = false;
+        let before = allocations();
+        let ws = WebsocketServer::from_http_scan(pair.upper(), None, 1000, 125, |name, value| {
+            if name == "Origin" {
+                origin = value == b"http://example.com" && input.contains(&value.as_ptr());
+            }
+        });
+        assert_eq!(allocations(), before);
+        assert!(ws.unwrap().is_some());
+        assert!(origin);
+    }
+
+    #[test]
+    fn receive_frame_checks_headers() {
+        let receive = |data: &[u8]| {
+            let (mut pair, mut ws) = connect(1000);
+            pair.lower().wr.append(data);
+            let mut payload = PipeBuf::new();
+            let mut header = None;
+            let result = ws.receive_frame(pair.upper(), payload.wr(), &mut header);
+            (result, header)
+        };
+        assert_eq!(receive(&[0x82, 1, b'x']).0, Err(Error::Unmasked));
+        assert_eq!(
+            receive(&frame(0x80, b"x")).0,
+            Err(Error::UnexpectedContinuationFrame)
+        );
+        assert_eq!(receive(&frame(0x83, b"x")).0, Err(Error::InvalidOpCode));
+        assert_eq!(
+            receive(&frame(0x89, &[0; 126])).0,
+            Err(Error::InvalidFrameLength)
+        );
+        assert_eq!(
+            receive(&header(0x82, 1 << 63)).0,
+            Err(Error::InvalidFrameLength)
+        );
+
+        // Reserved bits are left to the caller
+        let (result, header) = receive(&frame(0xc1, b"x"));
+        assert_eq!(result, Ok(true));
+        assert_eq!(header.map(|h| h.rsv), Some(4));
+
+        // A new message may not start part-way through another
+        let (mut pair, mut ws) = connect(1000);
+        pair.lower().wr.append(&frame(0x01, b"a"));
+        pair.lower().wr.append(&frame(0x82, b"b"));
+        let mut payload = PipeBuf::new();
+        let mut header = None;
+        assert_eq!(
+            ws.receive_frame(pair.upper(), payload.wr(), &mut header),
+            Ok(true)
+        );
+        payload.reset();
+        assert_eq!(
+            ws.receive_frame(pair.upper(), payload.wr(), &mut header),
+            Err(Error::InvalidOpCode)
+        );
+    }
+
+    #[test]
+    fn forward_frames_checks_headers() {
+        let forward = |data: &[u8]| {
+            let (mut pair, mut ws) = connect(1000);
+            pair.lower().wr.append(data);
+            let mut out = PipeBuf::new();
+            let result = ws.forward_frames(pair.upper(), out.wr(), false);
+            (result, out.rd().data().to_vec())
+        };
+        let mut valid = frame(0x01, b"a");
+        valid.extend(frame(0x89, b"p"));
+        valid.extend(frame(0x80, b"b"));
+        assert_eq!(forward(&valid), (Ok(true), valid.clone()));
+
+        for data in [
+            vec![0x82, 1, b'x'],
+            frame(0x80, b"x"),
+            frame(0x83, b"x"),
+            frame(0x89, &[0; 126]),
+            header(0x82, 1 << 63),
+        ] {
+            let (result, out) = forward(&data);
+            assert!(result.is_err(), "{data:?}");
+            assert!(out.is_empty(), "{data:?}");
+        }
+
+        // The frames up to an invalid one are still forwarded
+        let mut data = frame(0x01, b"a");
+        data.extend(frame(0x82, b"b"));
+        assert_eq!(
+            forward(&data),
+            (Err(Error::InvalidOpCode), frame(0x01, b"a"))
+        );
+    }
+
+    #[test]
+    fn drain_aborts_partial_message() {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_drain_on_close(true);
+        pair.lower().wr.append(&frame(0x01, b"ab"));
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().data(), b"ab");
+        ws.close_normal(pair.upper()).unwrap();
+        take_output(&mut pair);
+
+        pair.lower().wr.append(&frame(0x00, b"cd"));
+        pair.lower().wr.append(&frame(0x80, b"ef"));
+        pair.lower().wr.append(&frame(0x81, b"gh"));
+        pair.lower().wr.append(&frame(0x88, &[3, 0xe8]));
+        assert!(ws.receive(pair.upper(), msg.wr(), &mut false).unwrap());
+        let rd = msg.rd();
+        assert_eq!(rd.data(), b"ab");
+        assert!(rd.has_pending_eof() && rd.is_aborted());
+        msg.reset();
+        while ws.receive(pair.upper(), msg.wr(), &mut false).unwrap() {}
+        assert!(msg.rd().is_empty() && !msg.rd().has_pending_eof());
+        assert_eq!(ws.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn close_deadline_timed_from_close() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        let millis = Arc::new(AtomicU64::new(1000));
+        let m = millis.clone();
+        let clock = move || Duration::from_millis(m.load(Ordering::Relaxed));
+        let limit = Duration::from_secs(3);
+
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_clock(Some(Arc::new(clock.clone())));
+        ws.close_normal(pair.upper()).unwrap();
+        take_output(&mut pair);
+        millis.store(3999, Ordering::Relaxed);
+        assert!(!ws.close_deadline(pair.upper(), &clock, limit));
+        millis.store(4000, Ordering::Relaxed);
+        assert!(ws.close_deadline(pair.upper(), &clock, limit));
+        assert!(pair.lower().rd.is_aborted());
+
+        // Without a registered clock, timing starts at the first call
+        let (mut pair, mut ws) = connect(1000);
+        ws.close_normal(pair.upper()).unwrap();
+        take_output(&mut pair);
+        assert!(!ws.close_deadline(pair.upper(), &clock, limit));
+        millis.store(6999, Ordering::Relaxed);
+        assert!(!ws.close_deadline(pair.upper(), &clock, limit));
+        millis.store(7000, Ordering::Relaxed);
+        assert!(ws.close_deadline(pair.upper(), &clock, limit));
+    }
+
+    #[test]
+    fn handshake_deadline_timed_from_set_clock() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        let millis = Arc::new(AtomicU64::new(1000));
+        let m = millis.clone();
+        let clock = move || Duration::from_millis(m.load(Ordering::Relaxed));
+        let limit = Duration::from_secs(3);
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+                        Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Version: 13\r\n\r\n";
+
+        let mut hs = ServerHandshake::new(None, 1000, 125);
+        hs.set_clock(Arc::new(clock.clone()));
+        let mut pair = PipeBufPair::new();
+        pair.lower().wr.append(&request[..20]);
+        assert!(hs.accept_scan(pair.upper(), |_, _| ()).unwrap().is_none());
+        millis.store(3999, Ordering::Relaxed);
+        assert_eq!(hs.handshake_deadline(pair.upper(), &clock, limit), Ok(()));
+        millis.store(4000, Ordering::Relaxed);
+        assert_eq!(
+            hs.handshake_deadline(pair.upper(), &clock, limit),
+            Err(HandshakeError::Timeout)
+        );
+        assert!(pair.lower().rd.has_pending_eof());
+
+        // The clock is passed on, to time the close deadline
+        let mut hs = ServerHandshake::new(None, 1000, 125);
+        hs.set_clock(Arc::new(clock.clone()));
+        let mut pair = PipeBufPair::new();
+        pair.lower().wr.append(request);
+        let mut ws = hs.accept_scan(pair.upper(), |_, _| ()).unwrap().unwrap();
+        ws.close_normal(pair.upper()).unwrap();
+        millis.store(7000, Ordering::Relaxed);
+        assert!(ws.close_deadline(pair.upper(), &clock, limit));
+    }
+
+    #[test]
+    fn ping_during_fragmented_send() {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_max_frame_len(Some(2));
+        ws.send(pair.upper(), TxMsgType::Text, false, b"a1b1")
+            .unwrap();
+        ws.send(pair.upper(), TxMsgType::Ping, true, b"p").unwrap();
+        assert_eq!(ws.send_ping_seq(pair.upper()), Ok(0));
+        ws.send(pair.upper(), TxMsgType::Text, true, b"a2").unwrap();
+        ws.send_text(pair.upper(), "c").unwrap();
+        assert_eq!(
+            take_output(&mut pair),
+            [
+                &[0x01, 2, b'a', b'1'][..],
+                &[0x00, 2, b'b', b'1'],
+                &[0x89, 1, b'p'],
+                &[0x89, 8, 0, 0, 0, 0, 0, 0, 0, 0],
+                &[0x80, 2, b'a', b'2'],
+                &[0x81, 1, b'c']
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn pong_during_fragmented_send() {
+        let (mut pair, mut ws) = connect(1000);
+        ws.send(pair.upper(), TxMsgType::Binary, false, b"a1")
+            .unwrap();
+        pair.lower().wr.append(&frame(0x89, b"p"));
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        ws.send(pair.upper(), TxMsgType::Binary, true, b"a2")
+            .unwrap();
+        assert_eq!(
+            take_output(&mut pair),
+            [
+                &[0x02, 2, b'a', b'1'][..],
+                &[0x8a, 1, b'p'],
+                &[0x80, 2, b'a', b'2']
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn split_sender_ping_during_fragmented_send() {
+        let (mut pair, ws) = connect(1000);
+        let (mut tx, _rx) = ws.split();
+        tx.send(pair.upper().wr, TxMsgType::Text, false, b"a1")
+            .unwrap();
+        tx.send(pair.upper().wr, TxMsgType::Ping, true, b"p")
+            .unwrap();
+        tx.send(pair.upper().wr, TxMsgType::Text, true, b"a2")
+            .unwrap();
+        assert_eq!(
+            take_output(&mut pair),
+            [
+                &[0x01, 2, b'a', b'1'][..],
+                &[0x89, 1, b'p'],
+                &[0x80, 2, b'a', b'2']
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn split_halves_share_close() {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_message_policy(MessagePolicy::BinaryOnly);
+        let (mut tx, mut rx) = ws.split();
+        let mut replies = PipeBuf::new();
+        let mut msg = PipeBuf::new();
+        pair.lower().wr.append(&frame(0x81, b"a"));
+        let upper = pair.upper();
+        rx.receive(upper.rd, replies.wr(), msg.wr(), &mut false)
+            .unwrap();
+        assert_eq!(
+            tx.send_text(pair.upper().wr, "b"),
+            Err(Error::WebSocketNotOpen)
+        );
+        tx.forward_replies(replies.rd(), pair.upper().wr);
+        assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xeb]);
+
+        // A `Close` sent by the sender is completed by the receiver
+        let (mut pair, ws) = connect(1000);
+        let (mut tx, mut rx) = ws.split();
+        let mut replies = PipeBuf::new();
+        tx.close(
+            pair.upper().wr,
+            WebSocketCloseStatusCode::NormalClosure,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            tx.send_text(pair.upper().wr, "b"),
+            Err(Error::WebSocketNotOpen)
+        );
+        assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xe8]);
+        pair.lower().wr.append(&frame(0x88, &[3, 0xe8]));
+        let upper = pair.upper();
+        rx.receive(upper.rd, replies.wr(), msg.wr(), &mut false)
+            .unwrap();
+        tx.forward_replies(replies.rd(), pair.upper().wr);
+        assert!(pair.lower().rd.has_pending_eof());
+    }
+
+    #[test]
+    fn recoverable_error_is_not_latched() {
+        assert!(Error::Unmasked.is_fatal() && Error::ConnectionFailed.is_fatal());
+        assert!(!Error::WebSocketNotOpen.is_fatal() && !Error::InvalidPayload.is_fatal());
+        let (mut pair, mut ws) = connect(1000);
+        ws.close_normal(pair.upper()).unwrap();
+        assert_eq!(
+            ws.send_text(pair.upper(), "a"),
+            Err(Error::WebSocketNotOpen)
+        );
+        pair.lower().wr.append(&frame(0x88, &[3, 0xe8]));
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(ws.failure(), None);
+        assert_eq!(ws.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn broadcast_of_close_or_pong_is_refused() {
+        for msg in [TxMsgType::CloseReply, TxMsgType::Pong] {
+            assert_eq!(Broadcaster::new(msg, b"").err(), Some(Error::InvalidOpCode));
+        }
+        assert_eq!(
+            Broadcaster::new(TxMsgType::Ping, &[0; 126]).err(),
+            Some(Error::InvalidFrameLength)
+        );
+        let ping = Broadcaster::new(TxMsgType::Ping, &[0; 125]).unwrap();
+        assert_eq!(&ping.frame()[..2], [0x89, 125]);
+    }
+
+    #[test]
+    fn client_request_injection_is_refused() {
+        let base = ClientConfig::new("example.com", "/chat");
+        let mut bad = Vec::new();
+        let mut c = base.clone();
+        c.host = "example.com\r\nX-Evil: 1".into();
+        bad.push(c);
+        let mut c = base.clone();
+        c.path = "/ HTTP/1.1\r\n".into();
+        bad.push(c);
+        let mut c = base.clone();
+        c.path = "/a b".into();
+        bad.push(c);
+        let mut c = base.clone();
+        c.origin = Some("x\n".into());
+        bad.push(c);
+        let mut c = base.clone();
+        c.subprotocols = vec!["chat, evil".into()];
+        bad.push(c);
+        let mut c = base.clone();
+        c.extensions = vec!["x\0".into()];
+        bad.push(c);
+        let mut c = base.clone();
+        c.extra_headers = vec![("X-A: b\r\nX-C".into(), "d".into())];
+        bad.push(c);
+        let mut c = base.clone();
+        c.extra_headers = vec![("X-A".into(), "b\r\n\r\nGET /".into())];
+        bad.push(c);
+        for config in bad {
+            let mut pb = PipeBuf::new();
+            let result = write_client_handshake(pb.wr(), &config);
+            assert_eq!(result.err(), Some(Error::InvalidHeader), "{config:?}");
+            assert!(pb.rd().is_empty());
+        }
+        let mut pb = PipeBuf::new();
+        write_client_handshake(pb.wr(), &base).unwrap();
+        assert!(pb
+            .rd()
+            .data()
+            .starts_with(b"GET /chat HTTP/1.1\r\nHost: example.com\r\n"));
+    }
+
+    #[test]
+    fn client_cookie_injection_is_refused() {
+        for (name, value) in [
+            ("a", "b; c=d"),
+            ("a", "b\r\nX-Evil: 1"),
+            ("a=b", "c"),
+            ("a b", "c"),
+            ("", "c"),
+            ("a", "b,c"),
+            ("a", "\"b"),
+        ] {
+            let mut config = ClientConfig::new("example.com", "/");
+            config.set_cookie(name, value);
+            let mut pb = PipeBuf::new();
+            let result = write_client_handshake(pb.wr(), &config);
+            assert_eq!(
+                result.err(),
+                Some(Error::InvalidHeader),
+                "{name:?}={value:?}"
+            );
+            assert!(pb.rd().is_empty());
+        }
+        let mut config = ClientConfig::new("example.com", "/");
+        config.set_cookie("id", "x1");
+        config.set_cookie("q", "\"a/b\"");
+        let mut pb = PipeBuf::new();
+        write_client_handshake(pb.wr(), &config).unwrap();
+        let request = String::from_utf8(pb.rd().data().to_vec()).unwrap();
+        assert!(request.contains("\r\nCookie: id=x1; q=\"a/b\"\r\n"));
+    }
+
+    #[test]
+    fn rate_limit_pause_waits_for_allowance() {
+        let now = Cell::new(0);
+        let clock = || Duration::from_millis(now.get());
+        let (mut pair, mut ws) = connect(1000);
+        let limit = RateLimit {
+            messages: 1,
+            bytes: 0,
+            window: Duration::from_secs(1),
+            action: RateLimitAction::Pause,
+        };
+        ws.set_rate_limit(Some(limit), &clock);
+        pair.lower().wr.append(&frame(0x82, b"a"));
+        pair.lower().wr.append(&frame(0x82, b"b"));
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().data(), b"a");
+        msg.reset();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert!(msg.rd().is_empty());
+        assert_eq!(pair.upper().rd.len(), frame(0x82, b"b").len());
+
+        now.set(1000);
+        ws.tick(&clock);
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().data(), b"b");
+        assert!(take_output(&mut pair).is_empty());
+        assert_eq!(ws.rate_limit(), Some(limit));
+    }
+
+    #[test]
+    fn rate_limit_close_keeps_limit() {
+        let clock = || Duration::ZERO;
+        let (mut pair, mut ws) = connect(1000);
+        let limit = RateLimit {
+            messages: 0,
+            bytes: 10,
+            window: Duration::from_secs(1),
+            action: RateLimitAction::Close,
+        };
+        ws.set_rate_limit(Some(limit), &clock);
+        pair.lower().wr.append(&frame(0x82, &[1; 8]));
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert!(msg.rd().is_aborted());
+        assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xf0]);
+        assert_eq!(ws.rate_limit(), Some(limit));
+        assert!(ws.rate_limit_tripped());
+
+        // The rest of the input is still read, and the limit kept
+        msg.reset();
+        pair.lower().wr.append(&frame(0x88, &[3, 0xf0]));
+        while ws.receive(pair.upper(), msg.wr(), &mut false).unwrap() {}
+        assert!(pair.upper().rd.is_empty());
+        assert_eq!(ws.state(), ConnectionState::Closed);
+        assert_eq!(ws.rate_limit(), Some(limit));
+    }
+
+    // Remove the mask from a frame sent by a client with a payload of
+    // no more than 125 bytes, checking that it has one
+    fn unmask_client_frame(out: &[u8]) -> (u8, Vec<u8>) {
+        assert_eq!(out[1] & 0x80, 0x80, "Client frame must be masked");
+        let len = (out[1] & 0x7F) as usize;
+        let key = &out[2..6];
+        assert_eq!(out.len(), 6 + len);
+        let payload = out[6..].iter().enumerate().map(|(i, b)| b ^ key[i % 4]);
+        (out[0], payload.collect())
+    }
+
+    #[test]
+    fn client_masks_frames_and_rejects_masked() {
+        let mut pair = PipeBufPair::new();
+        let mut client = WebsocketClient::new_entropy(1000, 125, |b| *b = [7; 16]);
+        client.send_text(pair.upper(), "hi").unwrap();
+        let first = take_output(&mut pair);
+        assert_eq!(unmask_client_frame(&first), (0x81, b"hi".to_vec()));
+        client.send_text(pair.upper(), "hi").unwrap();
+        let second = take_output(&mut pair);
+        assert_eq!(unmask_client_frame(&second), (0x81, b"hi".to_vec()));
+        assert_ne!(first[2..6], second[2..6], "Each frame needs a new key");
+
+        // Unmasked frames from the server are accepted, and the
+        // `Pong` reply is masked
+        pair.lower()
+            .wr
+            .append(&[0x89, 1, b'p', 0x82, 2, b'o', b'k']);
+        let mut msg = PipeBuf::new();
+        client.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().data(), b"ok");
+        assert_eq!(
+            unmask_client_frame(&take_output(&mut pair)),
+            (0x8A, b"p".to_vec())
+        );
+
+        // A masked frame is refused, with a 1002 close
+        msg.reset();
+        pair.lower().wr.append(&frame(0x82, b"x"));
+        let result = client.receive(pair.upper(), msg.wr(), &mut false);
+        assert_eq!(result, Err(Error::Masked));
+        assert_eq!(
+            unmask_client_frame(&take_output(&mut pair)),
+            (0x88, vec![3, 0xEA])
+        );
+        assert_eq!(client.state(), ConnectionState::Failed);
+    }
+
+    #[test]
+    fn client_and_server_as_endpoints() {
+        // Each endpoint gets a pipe-buffer pair with its transport on
+        // the lower side
+        let (server_pair, server) = connect(1000);
+        let mut pairs = [server_pair, PipeBufPair::new()];
+        let mut endpoints: Vec<Box<dyn WsEndpoint>> =
+            vec![Box::new(server), Box::new(WebsocketClient::new(1000, 125))];
+
+        endpoints[1]
+            .send(pairs[1].upper(), TxMsgType::Binary, true, b"data")
+            .unwrap();
+        let sent = take_output(&mut pairs[1]);
+        pairs[0].lower().wr.append(&sent);
+        let mut msg = PipeBuf::new();
+        endpoints[0]
+            .receive(pairs[0].upper(), msg.wr(), &mut false)
+            .unwrap();
+        assert_eq!(msg.rd().data(), b"data");
+
+        endpoints[0]
+            .close(
+                pairs[0].upper(),
+                WebSocketCloseStatusCode::NormalClosure,
+                None,
+            )
+            .unwrap();
+        let sent = take_output(&mut pairs[0]);
+        pairs[1].lower().wr.append(&sent);
+        endpoints[1]
+            .receive(pairs[1].upper(), PipeBuf::new().wr(), &mut false)
+            .unwrap();
+        let reply = take_output(&mut pairs[1]);
+        assert_eq!(unmask_client_frame(&reply), (0x88, vec![3, 0xE8]));
+        pairs[0].lower().wr.append(&reply);
+        endpoints[0]
+            .receive(pairs[0].upper(), PipeBuf::new().wr(), &mut false)
+            .unwrap();
+        for ep in &endpoints {
+            assert_eq!(ep.state(), ConnectionState::Closed);
+        }
+    }
+
+    // Extension which inverts the bits of messages sent with RSV1,
+    // and refuses any which decode to contain `!`
+    struct Invert {
+        rx_inverted: bool,
+    }
+
+    impl WsExtension for Invert {
+        fn rsv_bits(&self) -> u8 {
+            4
+        }
+
+        fn outgoing(&mut self, frame: &mut ExtFrame, data: &mut Vec<u8>) {
+            if frame.start {
+                frame.rsv = 4;
+            }
+            data.iter_mut().for_each(|b| *b = !*b);
+        }
+
+        fn incoming(&mut self, frame: &ExtFrame, data: &mut Vec<u8>) -> Result<(), Error> {
+            if frame.start {
+                self.rx_inverted = frame.rsv == 4;
+            }
+            if self.rx_inverted {
+                data.iter_mut().for_each(|b| *b = !*b);
+            }
+            match data.contains(&b'!') {
+                true => Err(Error::InvalidPayload),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn extension_sets_rsv_and_transforms_payloads() {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_extension(Some(Box::new(Invert { rx_inverted: false })));
+        ws.set_max_frame_len(Some(2));
+        ws.send_text(pair.upper(), "hi!").unwrap();
+        let expected = [&[0x41, 2, !b'h', !b'i'][..], &[0x80, 1, !b'!']].concat();
+        assert_eq!(take_output(&mut pair), expected);
+
+        // Only the first frame of the message has RSV1
+        pair.lower().wr.append(&frame(0x42, &[!b'o']));
+        pair.lower().wr.append(&frame(0x80, &[!b'k']));
+        let mut msg = PipeBuf::new();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().data(), b"ok");
+        pair.lower().wr.append(&frame(0x82, b"as is"));
+        msg.reset();
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(msg.rd().data(), b"as is");
+        assert_eq!(ws.violations(), 0);
+
+        // The extension's bit is still reserved on control frames
+        msg.reset();
+        pair.lower().wr.append(&frame(0xC9, b""));
+        ws.receive(pair.upper(), msg.wr(), &mut false).unwrap();
+        assert_eq!(ws.violations(), 1);
+        assert_eq!(take_output(&mut pair), [0x8A, 0]);
+
+        // Failure to decode closes with 1007
+        msg.reset();
+        pair.lower().wr.append(&frame(0xC2, &[!b'!']));
+        let result = ws.receive(pair.upper(), msg.wr(), &mut false);
+        assert_eq!(result, Err(Error::InvalidPayload));
+        assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xEF]);
+    }
+
+    #[test]
+    fn keepalive_pings_when_idle_and_times_out() {
+        let now = Cell::new(0);
+        let clock = || Duration::from_secs(now.get());
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_keepalive(Duration::from_secs(10), Duration::from_secs(5));
+        assert_eq!(ws.keepalive(pair.upper(), &clock), Ok(false));
+        assert_eq!(ws.next_ping_due(), Some(Duration::from_secs(10)));
+
+        now.set(10);
+        assert_eq!(ws.keepalive(pair.upper(), &clock), Ok(false));
+        assert_eq!(take_output(&mut pair), [&[0x89, 8][..], &[0; 8]].concat());
+        assert_eq!(ws.next_ping_due(), Some(Duration::from_secs(15)));
+
+        // The reply arrives, so the next ping is due an interval later
+        pair.lower().wr.append(&frame(0x8A, &[0; 8]));
+        ws.receive(pair.upper(), PipeBuf::new().wr(), &mut false)
+            .unwrap();
+        now.set(12);
+        assert_eq!(ws.keepalive(pair.upper(), &clock), Ok(false));
+        assert_eq!(ws.last_activity(), Some(Duration::from_secs(12)));
+        assert_eq!(ws.next_ping_due(), Some(Duration::from_secs(22)));
+
+        now.set(22);
+        assert_eq!(ws.keepalive(pair.upper(), &clock), Ok(false));
+        assert_eq!(
+            take_output(&mut pair),
+            [&[0x89, 8][..], &1_u64.to_be_bytes()].concat()
+        );
+        now.set(26);
+        assert_eq!(ws.keepalive(pair.upper(), &clock), Ok(false));
+        assert!(!pair.upper().wr.is_eof());
+        now.set(27);
+        assert_eq!(ws.keepalive(pair.upper(), &clock), Ok(true));
+        assert!(pair.lower().rd.is_aborted());
+    }
+
+    #[cfg(feature = "mux")]
+    #[test]
+    fn mux_limits_channels_and_buffered_data() {
+        let op = |op: u8, id: u32, data: &[u8]| [&[op][..], &id.to_be_bytes(), data].concat();
+        let mut mux = Mux::new();
+        mux.set_limits(2, 10);
+        assert_eq!(mux.handle(&op(0, 1, b"")), Ok(MuxEvent::Opened(1)));
+        assert_eq!(mux.handle(&op(0, 3, b"")), Ok(MuxEvent::Opened(3)));
+        assert_eq!(mux.handle(&op(0, 5, b"")), Err(Error::LimitExceeded));
+        let (mut pair, mut ws) = connect(1000);
+        assert_eq!(
+            mux.open(&mut ws, pair.upper(), 2),
+            Err(Error::LimitExceeded)
+        );
+        assert!(take_output(&mut pair).is_empty());
+
+        assert_eq!(mux.handle(&op(1, 1, b"123456")), Ok(MuxEvent::Data(1)));
+        assert_eq!(mux.handle(&op(1, 3, b"7890")), Ok(MuxEvent::Data(3)));
+        assert_eq!(mux.handle(&op(1, 3, b"x")), Err(Error::LimitExceeded));
+        assert_eq!(mux.buffered(), 10);
+
+        // Reading the data makes room for more
+        mux.channel(1).unwrap().rd().consume(6);
+        assert_eq!(mux.handle(&op(1, 3, b"x")), Ok(MuxEvent::Data(3)));
+        assert_eq!(mux.buffered(), 5);
+
+        // A channel closed by the peer counts until it is removed
+        assert_eq!(mux.handle(&op(2, 1, b"")), Ok(MuxEvent::Closed(1)));
+        assert_eq!(mux.handle(&op(0, 5, b"")), Err(Error::LimitExceeded));
+        mux.remove(1);
+        assert_eq!(mux.handle(&op(0, 5, b"")), Ok(MuxEvent::Opened(5)));
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn mqtt_refuses_text_of_any_length() {
+        for text in [&b""[..], b"x"] {
+            let (mut pair, mut ws) = connect(1000);
+            let mut mqtt = MqttStream::new();
+            let mut codec = PipeBufPair::new();
+            pair.lower().wr.append(&frame(0x82, b"\x10"));
+            pair.lower().wr.append(&frame(0x81, text));
+            pair.lower().wr.append(&frame(0x82, b"\x20"));
+            mqtt.process(&mut ws, pair.upper(), codec.upper()).unwrap();
+            assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xeb]);
+            assert_eq!(codec.lower().rd.data(), b"\x10\x20");
+        }
+    }
+
+    #[cfg(feature = "socks5")]
+    #[test]
+    fn socks5_no_auth_with_domain() {
+        let mut pair = PipeBufPair::new();
+        let mut socks = Socks5Connect::new("example.com", 443);
+        assert_eq!(socks.process(pair.upper()), Ok(false));
+        assert_eq!(take_output(&mut pair), [5, 1, 0]);
+        pair.lower().wr.append(&[5, 0]);
+        assert_eq!(socks.process(pair.upper()), Ok(false));
+        let expected = [&[5, 1, 0, 3, 11][..], b"example.com", &[1, 0xBB]].concat();
+        assert_eq!(take_output(&mut pair), expected);
+
+        // Data from the server after the reply is left for the caller
+        pair.lower().wr.append(&[5, 0, 0, 1, 10, 0, 0, 1, 0, 80]);
+        pair.lower().wr.append(b"HTTP");
+        assert_eq!(socks.process(pair.upper()), Ok(true));
+        assert_eq!(pair.upper().rd.data(), b"HTTP");
+        assert!(take_output(&mut pair).is_empty());
+    }
+
+    #[cfg(feature = "socks5")]
+    #[test]
+    fn socks5_user_pass_auth() {
+        let mut pair = PipeBufPair::new();
+        let mut socks = Socks5Connect::new("10.1.2.3", 8080);
+        socks.set_auth("user", "secret");
+        assert_eq!(socks.process(pair.upper()), Ok(false));
+        assert_eq!(take_output(&mut pair), [5, 2, 0, 2]);
+        pair.lower().wr.append(&[5, 2]);
+        assert_eq!(socks.process(pair.upper()), Ok(false));
+        let expected = [&[1, 4][..], b"user", &[6], b"secret"].concat();
+        assert_eq!(take_output(&mut pair), expected);
+        pair.lower().wr.append(&[1, 0]);
+        assert_eq!(socks.process(pair.upper()), Ok(false));
+        assert_eq!(
+            take_output(&mut pair),
+            [5, 1, 0, 1, 10, 1, 2, 3, 0x1F, 0x90]
+        );
+
+        // IPv6 bound address in the reply
+        let reply = [&[5, 0, 0, 4][..], &[0; 16], &[0, 80]].concat();
+        pair.lower().wr.append(&reply);
+        assert_eq!(socks.process(pair.upper()), Ok(true));
+        assert!(pair.upper().rd.is_empty());
+
+        let mut pair = PipeBufPair::new();
+        let mut socks = Socks5Connect::new("10.1.2.3", 8080);
+        socks.set_auth("user", "wrong");
+        socks.process(pair.upper()).unwrap();
+        pair.lower().wr.append(&[5, 2, 1, 1]);
+        assert_eq!(socks.process(pair.upper()), Err(Socks5Error::AuthFailed));
+    }
+
+    #[cfg(feature = "socks5")]
+    #[test]
+    fn socks5_refusals() {
+        let mut pair = PipeBufPair::new();
+        let mut socks = Socks5Connect::new("::1", 80);
+        socks.process(pair.upper()).unwrap();
+        take_output(&mut pair);
+        pair.lower().wr.append(&[5, 0]);
+        socks.process(pair.upper()).unwrap();
+        let localhost = std::net::Ipv6Addr::LOCALHOST.octets();
+        let expected = [&[5, 1, 0, 4][..], &localhost, &[0, 80]].concat();
+        assert_eq!(take_output(&mut pair), expected);
+        pair.lower().wr.append(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(socks.process(pair.upper()), Err(Socks5Error::Refused(5)));
+
+        let mut pair = PipeBufPair::new();
+        let mut socks = Socks5Connect::new("host", 80);
+        socks.process(pair.upper()).unwrap();
+        pair.lower().wr.append(&[5, 0xFF]);
+        let result = socks.process(pair.upper());
+        assert_eq!(result, Err(Socks5Error::NoAcceptableMethod));
+
+        let mut pair = PipeBufPair::new();
+        let mut socks = Socks5Connect::new("host", 80);
+        socks.process(pair.upper()).unwrap();
+        pair.lower().wr.append(&[4, 0]);
+        assert_eq!(socks.process(pair.upper()), Err(Socks5Error::Protocol));
+
+        let long = "x".repeat(256);
+        let mut pair = PipeBufPair::new();
+        let mut socks = Socks5Connect::new(&long, 80);
+        socks.process(pair.upper()).unwrap();
+        pair.lower().wr.append(&[5, 0]);
+        assert_eq!(socks.process(pair.upper()), Err(Socks5Error::InvalidHost));
+    }
+
+    #[cfg(feature = "socks5")]
+    #[test]
+    fn socks5_reply_split_across_reads() {
+        let mut pair = PipeBufPair::new();
+        let mut socks = Socks5Connect::new("example.com", 443);
+        socks.process(pair.upper()).unwrap();
+        pair.lower().wr.append(&[5]);
+        assert_eq!(socks.process(pair.upper()), Ok(false));
+        pair.lower().wr.append(&[0]);
+        assert_eq!(socks.process(pair.upper()), Ok(false));
+        take_output(&mut pair);
+
+        let reply = [&[5, 0, 0, 3, 3][..], b"abc", &[1, 0xBB]].concat();
+        for b in &reply[..reply.len() - 1] {
+            pair.lower().wr.append(&[*b]);
+            assert_eq!(socks.process(pair.upper()), Ok(false));
+        }
+        pair.lower().wr.append(&reply[reply.len() - 1..]);
+        assert_eq!(socks.process(pair.upper()), Ok(true));
+        assert!(pair.upper().rd.is_empty());
+        assert!(take_output(&mut pair).is_empty());
+    }
+
+    #[cfg(feature = "stomp")]
+    #[test]
+    fn stomp_header_escaping_roundtrip() {
+        let frame = StompFrame::new("SEND")
+            .header("a:b", "x\ny\\z\r")
+            .body("hi");
+        let encoded = frame.encode();
+        assert_eq!(encoded, b"SEND\na\\cb:x\\ny\\\\z\\r\n\nhi\0");
+        assert_eq!(parse_stomp(&encoded), Ok(vec![frame]));
+    }
+
+    #[cfg(feature = "stomp")]
+    #[test]
+    fn stomp_connect_headers_are_not_escaped() {
+        let frame = StompFrame::new("CONNECT").header("passcode", "a:b\\c");
+        let encoded = frame.encode();
+        assert_eq!(encoded, b"CONNECT\npasscode:a:b\\c\n\n\0");
+        assert_eq!(parse_stomp(&encoded), Ok(vec![frame]));
+        let parsed = parse_stomp(b"CONNECTED\nversion:1.2\\n\n\n\0").unwrap();
+        assert_eq!(parsed[0].get("version"), Some("1.2\\n"));
+    }
+
+    #[cfg(feature = "stomp")]
+    #[test]
+    fn stomp_body_with_nul_uses_content_length() {
+        let frame = StompFrame::new("SEND").body(&b"a\0b"[..]);
+        let encoded = frame.encode();
+        assert_eq!(encoded, b"SEND\ncontent-length:3\n\na\0b\0");
+        let parsed = parse_stomp(&[&encoded[..], &encoded].concat()).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].body, b"a\0b");
+        assert_eq!(parsed[1].get("content-length"), Some("3"));
+
+        // Without `content-length`, the body ends at the first NUL
+        let parsed = parse_stomp(b"SEND\n\na\0").unwrap();
+        assert_eq!(parsed[0].body, b"a");
+    }
+
+    #[cfg(feature = "stomp")]
+    #[test]
+    fn stomp_heart_beats_are_skipped() {
+        assert_eq!(parse_stomp(b"\n"), Ok(vec![]));
+        assert_eq!(parse_stomp(b"\r\n\n\r\n"), Ok(vec![]));
+        let parsed = parse_stomp(b"\nSEND\n\na\0\r\n\nSEND\n\nb\0\n").unwrap();
+        let bodies: Vec<_> = parsed.iter().map(|f| &f.body[..]).collect();
+        assert_eq!(bodies, [b"a", b"b"]);
+    }
+
+    #[cfg(feature = "stomp")]
+    #[test]
+    fn stomp_malformed_frames() {
+        let cases: [&[u8]; 7] = [
+            b"SEND",
+            b"SEND\nno-colon\n\n\0",
+            b"SEND\nbad:\\t\n\n\0",
+            b"SEND\nh:v\n\nbody",
+            b"SEND\ncontent-length:x\n\nbody\0",
+            b"SEND\ncontent-length:2\n\nbody\0",
+            b"SEND\nh:v\n",
+        ];
+        for message in cases {
+            let result = parse_stomp(message);
+            assert_eq!(result, Err(Error::InvalidPayload), "{message:?}");
+        }
+        assert_eq!(parse_stomp(b"SEND\nh:\xff\n\n\0"), Err(Error::Utf8Error));
+    }
+
+    #[cfg(feature = "jsonrpc")]
+    #[test]
+    fn jsonrpc_batch_parsing() {
+        let batch = br#"[
+            {"jsonrpc": "2.0", "id": 1, "method": "sum", "params": [1, 2]},
+            {"jsonrpc": "2.0", "method": "log"},
+            {"jsonrpc": "2.0", "id": "a", "result": 3},
+            {"jsonrpc": "2.0", "id": null, "error": {"code": -32600, "message": "m"}}
+        ]"#;
+        let expected = vec![
+            RpcMessage::Request {
+                id: 1.into(),
+                method: "sum".into(),
+                params: Some(serde_json::json!([1, 2])),
+            },
+            RpcMessage::Notification {
+                method: "log".into(),
+                params: None,
+            },
+            RpcMessage::Response {
+                id: "a".into(),
+                result: Ok(3.into()),
+            },
+            RpcMessage::Response {
+                id: serde_json::Value::Null,
+                result: Err(RpcError::new(RpcError::INVALID_REQUEST, "m")),
+            },
+        ];
+        assert_eq!(RpcMessage::parse_batch(batch), Ok(expected.clone()));
+        for msg in expected {
+            let json = serde_json::to_vec(&msg.to_value()).unwrap();
+            assert_eq!(RpcMessage::parse_batch(&json), Ok(vec![msg]));
+        }
+    }
+
+    #[cfg(feature = "jsonrpc")]
+    #[test]
+    fn jsonrpc_invalid_requests() {
+        let code = |message: &[u8]| RpcMessage::parse_batch(message).map_err(|e| e.code);
+        assert_eq!(code(b"{"), Err(RpcError::PARSE_ERROR));
+        let invalid: [&[u8]; 9] = [
+            b"[]",
+            b"1",
+            br#"{"id": 1, "method": "a"}"#,
+            br#"{"jsonrpc": "1.0", "id": 1, "method": "a"}"#,
+            br#"{"jsonrpc": "2.0", "id": {}, "method": "a"}"#,
+            br#"{"jsonrpc": "2.0", "id": 1, "method": 5}"#,
+            br#"{"jsonrpc": "2.0", "id": 1, "method": "a", "params": "x"}"#,
+            br#"{"jsonrpc": "2.0", "id": 1, "result": 1, "error": {}}"#,
+            br#"{"jsonrpc": "2.0", "result": 1}"#,
+        ];
+        for message in invalid {
+            let desc = String::from_utf8_lossy(message);
+            assert_eq!(code(message), Err(RpcError::INVALID_REQUEST), "{desc}");
+        }
+        // One bad member rejects the whole batch
+        let batch = br#"[{"jsonrpc": "2.0", "method": "a"}, 1]"#;
+        assert_eq!(code(batch), Err(RpcError::INVALID_REQUEST));
+    }
+
+    #[cfg(feature = "jsonrpc")]
+    #[test]
+    fn jsonrpc_client_matches_and_cancels() {
+        let (mut pair, mut ws) = connect(1000);
+        let mut client = RpcClient::new();
+        let first = client.call(&mut ws, pair.upper(), "sum", None).unwrap();
+        let second = client.call(&mut ws, pair.upper(), "log", None).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(client.pending(), 2);
+        let sent = take_output(&mut pair);
+        let (len, rest) = (sent[1] as usize, &sent[2..]);
+        let msg = RpcMessage::parse_batch(&rest[..len]).unwrap();
+        assert!(matches!(&msg[..], [RpcMessage::Request { method, .. }] if method == "sum"));
+
+        let response = |id: u64| RpcMessage::Response {
+            id: id.into(),
+            result: Ok(serde_json::Value::Bool(true)),
+        };
+        let reply = client.handle(response(first));
+        assert_eq!(reply.map(|r| (r.id, r.method)), Ok((first, "sum".into())));
+        assert_eq!(client.handle(response(first)), Err(response(first)));
+
+        assert!(client.cancel(second));
+        assert!(!client.cancel(second));
+        assert_eq!(client.handle(response(second)), Err(response(second)));
+        assert_eq!(client.pending(), 0);
+
+        let note = RpcMessage::Notification {
+            method: "log".into(),
+            params: None,
+        };
+        assert_eq!(client.handle(note.clone()), Err(note));
+    }
+
+    #[cfg(feature = "engineio")]
+    #[test]
+    fn engineio_decode_and_encode() {
+        let packets = [
+            (
+                "0{\"sid\":\"a\"}",
+                EioPacket::Open("{\"sid\":\"a\"}".into()),
+            ),
+            ("1", EioPacket::Close),
+            ("2probe", EioPacket::Ping("probe".into())),
+            ("2", EioPacket::Ping("".into())),
+            ("3probe", EioPacket::Pong("probe".into())),
+            ("4hello", EioPacket::Message("hello".into())),
+            ("4", EioPacket::Message("".into())),
+            ("5", EioPacket::Upgrade),
+            ("6", EioPacket::Noop),
+        ];
+        for (text, packet) in packets {
+            assert_eq!(EioPacket::decode(text.as_bytes(), true), Ok(packet.clone()));
+            assert_eq!(packet.encode().as_deref(), Some(text));
+        }
+        // Binary messages have no type prefix, even if empty
+        assert_eq!(EioPacket::decode(b"", false), Ok(EioPacket::Binary(vec![])));
+        assert_eq!(
+            EioPacket::decode(b"4x", false),
+            Ok(EioPacket::Binary(b"4x".to_vec()))
+        );
+        assert_eq!(EioPacket::Binary(vec![1]).encode(), None);
+
+        assert_eq!(EioPacket::decode(b"", true), Err(Error::InvalidPayload));
+        assert_eq!(EioPacket::decode(b"7", true), Err(Error::InvalidPayload));
+        assert_eq!(EioPacket::decode(b"x4", true), Err(Error::InvalidPayload));
+        assert_eq!(EioPacket::decode(b"4\xff", true), Err(Error::Utf8Error));
+    }
+
+    #[cfg(feature = "engineio")]
+    #[test]
+    fn engineio_send() {
+        let (mut pair, mut ws) = connect(1000);
+        EioPacket::Ping("".into())
+            .send(&mut ws, pair.upper())
+            .unwrap();
+        EioPacket::Binary(vec![7])
+            .send(&mut ws, pair.upper())
+            .unwrap();
+        assert_eq!(take_output(&mut pair), [0x81, 1, b'2', 0x82, 1, 7]);
+    }
+
+    #[test]
+    fn key_lengths_and_encodings() {
+        let check = |keys: &[&[u8]], quirks: Quirks| {
+            let headers: Vec<_> = keys
+                .iter()
+                .map(|value| httparse::Header {
+                    name: "sec-websocket-key",
+                    value,
+                })
+                .collect();
+            handshake::key_ok(&headers, quirks)
+        };
+        let strict = Quirks::default();
+        assert!(check(&[b"dGhlIHNhbXBsZSBub25jZQ=="], strict));
+        assert!(check(&[b"AAAAAAAAAAAAAAAAAAAAAA=="], strict));
+        assert!(check(&[b"/////////////////////w=="], strict));
+        let bad: [&[u8]; 10] = [
+            b"",
+            b"dGhlIHNhbXBsZSBub25jZQ=",
+            b"dGhlIHNhbXBsZSBub25jZQ===",
+            b"dGhlIHNhbXBsZSBub25jZQAA",
+            b"dGhlIHNhbXBsZSBub25jZSA=",
+            b"dGhlIHNhbXBsZSBub25jZQ",
+            // Last digit carries bits beyond the 16 bytes
+            b"dGhlIHNhbXBsZSBub25jZR==",
+            // Not in the standard base64 alphabet
+            b"dGhlIHNhbXBsZSBub25j-Q==",
+            b"dGhlIHNhbXBsZSBub25j Q==",
+            b"dGhlIHNhbXBsZSBub25j\xffQ==",
+        ];
+        for key in bad {
+            assert!(!check(&[key], strict), "{:?}", String::from_utf8_lossy(key));
+        }
+        assert!(!check(&[], strict));
+        let key = &b"dGhlIHNhbXBsZSBub25jZQ=="[..];
+        assert!(!check(&[key, key], strict));
+
+        let lenient = Quirks {
+            lenient_key: true,
+            ..Quirks::default()
+        };
+        assert!(check(&[b"short"], lenient));
+        assert!(check(&[b"dGhlIHNhbXBsZSBub25jZQAA"], lenient));
+        assert!(!check(&[b"dGhlIHNhbXBsZSBub25jZQ==="], lenient));
+        assert!(!check(&[key, key], lenient));
+
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZR==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        assert!(matches!(handshake(request), Err(HandshakeError::BadKey)));
+    }
+
+    // Write a client's upgrade request with a fixed key, returning the
+    // key and the accepting response from the server
+    fn client_request(config: &ClientConfig) -> (HandshakeKey, String) {
+        let mut request = PipeBuf::new();
+        let key = write_client_handshake_entropy(request.wr(), config, |b| *b = [1; 16]).unwrap();
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            key.expected_accept()
+        );
+        (key, response)
+    }
+
+    #[test]
+    fn client_handshake_split_across_reads() {
+        let config = ClientConfig::new("example.com", "/chat");
+        let (key, response) = client_request(&config);
+        let data = [response.as_bytes(), &[0x81, 0]].concat();
+        let mut rd = PipeBuf::new();
+        for b in &data[..response.len() - 1] {
+            rd.wr().append(&[*b]);
+            assert!(matches!(
+                read_client_handshake(rd.rd(), &config, &key),
+                Ok(None)
+            ));
+        }
+        rd.wr().append(&data[response.len() - 1..]);
+        let accepted = read_client_handshake(rd.rd(), &config, &key).unwrap();
+        assert_eq!(accepted, Some(UpgradeResponse::default()));
+        // The frames which follow are left for the connection
+        assert_eq!(rd.rd().data(), [0x81, 0]);
+    }
+
+    #[test]
+    fn client_handshake_wrong_accept() {
+        let config = ClientConfig::new("example.com", "/chat");
+        let (key, _) = client_request(&config);
+        let mut rd = PipeBuf::new();
+        rd.wr().append(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+        );
+        assert_eq!(
+            read_client_handshake(rd.rd(), &config, &key),
+            Err(ClientHandshakeError::Ws(Error::AcceptStringInvalid))
+        );
+        let mut rd = PipeBuf::new();
+        rd.wr()
+            .append(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n");
+        assert_eq!(
+            read_client_handshake(rd.rd(), &config, &key),
+            Err(ClientHandshakeError::Ws(Error::UpgradeInvalid))
+        );
+    }
+
+    #[test]
+    fn client_handshake_rejected() {
+        let config = ClientConfig::new("example.com", "/chat");
+        let (key, _) = client_request(&config);
+        let mut rd = PipeBuf::new();
+        rd.wr().append(
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\n\
+              Retry-After: 30\r\n\r\nbu",
+        );
+        // Waits for the body
+        assert_eq!(read_client_handshake(rd.rd(), &config, &key), Ok(None));
+        rd.wr().append(b"sy");
+        let Err(ClientHandshakeError::Rejected(rejected)) =
+            read_client_handshake(rd.rd(), &config, &key)
+        else {
+            panic!("Expected a rejection");
+        };
+        assert_eq!(rejected.status, 503);
+        assert_eq!(rejected.reason, "Service Unavailable");
+        assert_eq!(rejected.body, b"busy");
+        assert_eq!(rejected.retry_after(), Some(Duration::from_secs(30)));
+        assert!(rd.rd().is_empty());
+    }
+
+    #[test]
+    fn client_handshake_set_cookie_parsing() {
+        let mut config = ClientConfig::new("example.com", "/chat");
+        let (key, response) = client_request(&config);
+        let response = response.replace(
+            "\r\n\r\n",
+            "\r\nSet-Cookie: sid=abc; Path=/; HttpOnly\r\n\
+             set-cookie: quoted=\"v1\"\r\n\
+             Set-Cookie:  spaced = x ; Max-Age=60\r\n\
+             Set-Cookie: empty=\r\n\
+             Set-Cookie: bad=a b\r\n\
+             Set-Cookie: semi=\"a;b\"\r\n\
+             Set-Cookie: no-equals\r\n\
+             Set-Cookie: bad name=v\r\n\
+             X-Other: a=b\r\n\r\n",
+        );
+        let mut rd = PipeBuf::new();
+        let mut cookies = Vec::new();
+        let read = |rd: &mut PipeBuf, cookies: &mut Vec<(String, String)>| {
+            read_client_handshake_cookies(rd.rd(), &config, &key, |name, value| {
+                cookies.push((name.to_string(), value.to_string()))
+            })
+        };
+
+        // Nothing is passed on until the whole response is accepted
+        rd.wr().append(&response.as_bytes()[..response.len() - 2]);
+        assert_eq!(read(&mut rd, &mut cookies), Ok(None));
+        assert!(cookies.is_empty());
+        rd.wr().append(b"\r\n");
+        assert!(read(&mut rd, &mut cookies).unwrap().is_some());
+        let expected = [
+            ("sid", "abc"),
+            ("quoted", "\"v1\""),
+            ("spaced", "x"),
+            ("empty", ""),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(cookies, expected);
+
+        // They can be sent back on reconnection, replacing old values
+        config.set_cookie("sid", "old");
+        for (name, value) in &cookies {
+            config.set_cookie(name, value);
+        }
+        let mut request = PipeBuf::new();
+        write_client_handshake(request.wr(), &config).unwrap();
+        let request = String::from_utf8(request.rd().data().to_vec()).unwrap();
+        assert!(request.contains("\r\nCookie: sid=abc; quoted=\"v1\"; spaced=x; empty=\r\n"));
+
+        // Cookies from a rejection are not passed on
+        let mut rd = PipeBuf::new();
+        rd.wr()
+            .append(b"HTTP/1.1 401 Unauthorized\r\nSet-Cookie: a=b\r\nContent-Length: 0\r\n\r\n");
+        let mut cookies = Vec::new();
+        let result = read_client_handshake_cookies(rd.rd(), &config, &key, |name, value| {
+            cookies.push((name.to_string(), value.to_string()))
+        });
+        assert!(matches!(result, Err(ClientHandshakeError::Rejected(_))));
+        assert!(cookies.is_empty());
+    }
+
+    #[test]
+    fn budget_stops_receive_and_resumes_after_close() {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_budget(Some(Budget {
+            messages: 2,
+            bytes: 0,
+            code: WebSocketCloseStatusCode::PolicyViolation,
+        }));
+        let mut input = frame(0x81, b"one");
+        input.extend(frame(0x81, b"two"));
+        input.extend(frame(0x81, b"three"));
+        pair.lower().wr.append(&input);
+
+        // One message is delivered per call, up to the budget
+        let mut msg = PipeBuf::new();
+        for expected in [&b"one"[..], b"two"] {
+            assert!(ws.receive(pair.upper(), msg.wr(), &mut false).unwrap());
+            assert_eq!(msg.rd().data(), expected);
+            assert!(msg.rd().has_pending_eof());
+            msg.reset();
+            assert!(take_output(&mut pair).is_empty());
+        }
+
+        // The next call closes the connection and discards the third
+        // message without passing any of it on
+        while ws.receive(pair.upper(), msg.wr(), &mut false).unwrap() {}
+        assert!(msg.rd().data().is_empty());
+        assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xf0]);
+        assert!(pair.upper().rd.is_empty());
+        assert_eq!(ws.state(), ConnectionState::CloseSent);
+
+        // Input after the budget is used up is still parsed from the
+        // right point, so the peer's `Close` completes the handshake
+        msg.reset();
+        pair.lower().wr.append(&frame(0x88, &[3, 0xf0]));
+        while ws.receive(pair.upper(), msg.wr(), &mut false).unwrap() {}
+        assert!(pair.upper().rd.is_empty());
+        assert_eq!(ws.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn byte_budget_is_checked_between_messages() {
+        let (mut pair, mut ws) = connect(1000);
+        ws.set_budget(Some(Budget {
+            messages: 0,
+            bytes: 20,
+            code: WebSocketCloseStatusCode::EndpointUnavailable,
+        }));
+
+        // 14 bytes, leaving some of the budget
+        pair.lower().wr.append(&frame(0x82, &[1; 8]));
+        let mut msg = PipeBuf::new();
+        assert!(ws.receive(pair.upper(), msg.wr(), &mut false).unwrap());
+        assert_eq!(msg.rd().data(), [1; 8]);
+        msg.reset();
+
+        // The whole of this message is consumed, since the budget is
+        // only checked between chunks of input, taking it to 28 bytes
+        pair.lower().wr.append(&frame(0x82, &[2; 8]));
+        assert!(ws.receive(pair.upper(), msg.wr(), &mut false).unwrap());
+        assert_eq!(msg.rd().data(), [2; 8]);
+        msg.reset();
+        assert!(take_output(&mut pair).is_empty());
+
+        // The following message is over budget
+        pair.lower().wr.append(&frame(0x82, &[3; 8]));
+        while ws.receive(pair.upper(), msg.wr(), &mut false).unwrap() {}
+        assert!(msg.rd().data().is_empty());
+        assert!(pair.upper().rd.is_empty());
+        assert_eq!(take_output(&mut pair), [0x88, 2, 3, 0xe9]);
+    }
+
+    #[test]
+    fn size_estimates_match_bytes_written() {
+        let (mut pair, mut ws) = connect(1 << 20);
+        for (len, overhead) in [(0, 2), (125, 2), (126, 4), (65535, 4), (65536, 10)] {
+            assert_eq!(ws.frame_overhead(len), overhead);
+            ws.send_binary(pair.upper(), &vec![0; len]).unwrap();
+            let written = take_output(&mut pair).len();
+            assert_eq!(written, len + overhead, "len {len}");
+            assert!(ws.space_needed(len) >= written, "len {len}");
+        }
+
+        // Fragmented messages, including text split short of the
+        // limit to keep characters whole
+        ws.set_max_frame_len(Some(126));
+        for len in [0, 125, 126, 65535, 65536] {
+            ws.send_binary(pair.upper(), &vec![0; len]).unwrap();
+            let written = take_output(&mut pair).len();
+            assert!(ws.space_needed(len) >= written, "len {len}");
+            let text = "\u{20ac}".repeat(len / 3);
+            ws.send_text(pair.upper(), &text).unwrap();
+            let written = take_output(&mut pair).len();
+            assert!(ws.space_needed(text.len()) >= written, "text len {len}");
+        }
+    }
 }
@@ -0,0 +1,35 @@
+use crate::Error;
+
+/// Receiver of connection metrics
+///
+/// See [`WebsocketServer::set_metrics`].  The connection calls into
+/// this as things happen, which allows a deployment to bridge to
+/// Prometheus, StatsD or whatever else, without this crate depending
+/// on any metrics library.  Since one implementation will normally
+/// be shared by many connections, the methods take `&self`, so the
+/// implementation should use atomics or the metrics library's own
+/// handles.  All the methods do nothing by default.
+///
+/// [`WebsocketServer::set_metrics`]: crate::WebsocketServer::set_metrics
+pub trait WsMetrics: Send + Sync {
+    /// A complete data message of `len` bytes was received
+    fn message_received(&self, _is_text: bool, _len: u64) {}
+
+    /// A data message of `len` bytes was sent.  For a fragmented
+    /// message, this is called when the final fragment is sent.
+    fn message_sent(&self, _is_text: bool, _len: u64) {}
+
+    /// `len` bytes were consumed from the input stream
+    fn bytes_received(&self, _len: u64) {}
+
+    /// `len` bytes were written to the output stream
+    fn bytes_sent(&self, _len: u64) {}
+
+    /// Receiving failed with the given error
+    fn error(&self, _err: &Error) {}
+
+    /// The close handshake was started, either by the peer or by us,
+    /// with the given status code, or `None` if the peer's `Close`
+    /// had no payload
+    fn closed(&self, _by_peer: bool, _code: Option<u16>) {}
+}
@@ -0,0 +1,141 @@
+use crate::{
+    ConnectionState, Error, HandshakeError, MessagePolicy, TransportState, WebsocketServer,
+};
+use embedded_websocket as ws;
+use pipebuf::{PBufRdWr, PipeBuf};
+use ws::WebSocketSubProtocol;
+
+/// Subprotocol name required for MQTT over websocket
+pub const MQTT_SUBPROTOCOL: &str = "mqtt";
+
+/// Adapter presenting a websocket as an MQTT byte stream
+///
+/// MQTT over websocket carries the MQTT byte stream in binary
+/// messages, but MQTT control packets don't have to line up with
+/// message boundaries.  A packet may span several messages, and one
+/// message may contain several packets.  So this adapter ignores
+/// message boundaries and passes all the incoming data on as one
+/// contiguous stream, suitable for feeding to an MQTT codec.
+/// Outgoing data from the codec is sent as binary messages.
+///
+/// Use [`MqttStream::from_http`] to accept the connection, and then
+/// call [`MqttStream::process`] whenever there is activity on either
+/// side.
+pub struct MqttStream {
+    message: PipeBuf,
+    is_text: bool,
+}
+
+impl Default for MqttStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MqttStream {
+    /// Create the adapter for a websocket which has been accepted
+    /// with the `mqtt` subprotocol
+    pub fn new() -> Self {
+        Self {
+            message: PipeBuf::new(),
+            is_text: false,
+        }
+    }
+
+    /// Accept an incoming websocket connection, replying with the
+    /// `mqtt` subprotocol.  If the client didn't offer `mqtt`, the
+    /// connection is accepted without a subprotocol, which may be
+    /// detected with [`WebsocketServer::subprotocol`].  See
+    /// [`WebsocketServer::from_http`] for details of arguments and
+    /// returns.
+    pub fn from_http(
+        pb: PBufRdWr,
+        max_msg_len: usize,
+        max_aux_len: usize,
+    ) -> Result<Option<WebsocketServer>, HandshakeError> {
+        let subprotocol = WebSocketSubProtocol::from(MQTT_SUBPROTOCOL);
+        WebsocketServer::from_http(pb, Some(&subprotocol), max_msg_len, max_aux_len)
+    }
+
+    /// Pass data between the websocket stream `pb` and the MQTT
+    /// codec stream `codec`.  Any data in `codec.rd` is sent as a
+    /// binary message, and EOF on `codec.rd` starts the close
+    /// handshake.  Data from incoming messages is appended to
+    /// `codec.wr` as it arrives, with a push after each chunk.
+    ///
+    /// Once the peer has closed the websocket, or the input stream
+    /// has ended, `codec.wr` is closed.  If the input ends part-way
+    /// through a message, then `codec.wr` is aborted instead.
+    ///
+    /// Returns `Ok(true)` if there was activity, `Ok(false)` if
+    /// nothing could be done right now, or `Err(_)` in case of
+    /// protocol or limit errors from [`WebsocketServer::receive`].
+    /// Since MQTT doesn't permit text messages, this sets the
+    /// [`MessagePolicy::BinaryOnly`] policy on `ws`, so a text message
+    /// of any length closes the websocket with code 1003 (unsupported
+    /// data), and its data is discarded.
+    pub fn process(
+        &mut self,
+        ws: &mut WebsocketServer,
+        mut pb: PBufRdWr,
+        mut codec: PBufRdWr,
+    ) -> Result<bool, Error> {
+        ws.set_message_policy(MessagePolicy::BinaryOnly);
+        let mut activity = false;
+
+        if !codec.rd.is_empty() && ws.state() == ConnectionState::Open {
+            ws.send_binary(pb.reborrow(), codec.rd.data())?;
+            let len = codec.rd.len();
+            codec.rd.consume(len);
+            activity = true;
+        }
+        if codec.rd.has_pending_eof() && codec.rd.is_empty() {
+            codec.rd.consume_eof();
+            if ws.state() == ConnectionState::Open {
+                ws.close_normal(pb.reborrow())?;
+            }
+            activity = true;
+        }
+
+        let mut aborted = false;
+        loop {
+            let act = ws.receive(pb.reborrow(), self.message.wr(), &mut self.is_text)?;
+            let mut rd = self.message.rd();
+            if !rd.is_empty() {
+                if !codec.wr.is_eof() {
+                    codec.wr.append(rd.data());
+                    codec.wr.push();
+                }
+                let len = rd.len();
+                rd.consume(len);
+                activity = true;
+            }
+            if rd.has_pending_eof() {
+                aborted = rd.is_aborted();
+                self.message.reset();
+            }
+            if !act {
+                break;
+            }
+            activity = true;
+        }
+
+        let ended = matches!(
+            ws.transport_state(pb.reborrow()),
+            TransportState::InputEnded | TransportState::Closed
+        );
+        let peer_closed = matches!(
+            ws.state(),
+            ConnectionState::CloseReceived | ConnectionState::Closed
+        );
+        if !codec.wr.is_eof() && (aborted || ended || peer_closed) {
+            if aborted {
+                codec.wr.abort();
+            } else {
+                codec.wr.close();
+            }
+            activity = true;
+        }
+        Ok(activity)
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{Error, WebsocketServer};
+use pipebuf::{PBufRdWr, PBufState, PipeBuf};
+use std::collections::HashMap;
+
+/// Subprotocol name to negotiate for a multiplexed connection
+pub const MUX_SUBPROTOCOL: &str = "x-pipebuf-mux";
+
+const OP_OPEN: u8 = 0;
+const OP_DATA: u8 = 1;
+const OP_CLOSE: u8 = 2;
+
+/// Default limit on the number of channels
+pub const MUX_MAX_CHANNELS: usize = 1024;
+
+/// Default limit on the unread bytes in all channels together
+pub const MUX_MAX_BUFFERED: usize = 1 << 20;
+
+/// Event resulting from a message handled by [`Mux::handle`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MuxEvent {
+    /// The peer opened a new channel
+    Opened(u32),
+    /// Data was added to the channel's pipe-buffer
+    Data(u32),
+    /// The peer closed the channel.  EOF has been indicated on the
+    /// channel's pipe-buffer.  Once the remaining data has been
+    /// processed, the channel should be discarded with
+    /// [`Mux::remove`].
+    Closed(u32),
+}
+
+/// Multiplexing of many logical channels over one websocket
+///
+/// This is an experimental layer for applications such as dashboards
+/// or RPC systems which need many independent streams between the
+/// same two endpoints.  Both ends must use this layer, which should
+/// be agreed using the [`MUX_SUBPROTOCOL`] subprotocol.
+///
+/// Each websocket message is a binary message carrying one operation
+/// on one channel: a single byte operation code (0 to open, 1 for
+/// data, 2 to close), then the channel ID as a 32-bit big-endian
+/// number, then for data, the payload.  Either end may open a
+/// channel.  To avoid clashes, by convention the server uses even
+/// IDs and the client odd IDs.
+///
+/// Incoming data for each channel is appended to its own
+/// [`PipeBuf`], which works as a byte stream.  Complete incoming
+/// messages from [`WebsocketServer::receive`] must be passed to
+/// [`Mux::handle`].
+///
+/// Since the peer may open channels and send data faster than it is
+/// processed, the number of channels and the amount of unread data
+/// in their pipe-buffers are limited, by default to
+/// [`MUX_MAX_CHANNELS`] and [`MUX_MAX_BUFFERED`].  See
+/// [`Mux::set_limits`].
+pub struct Mux {
+    channels: HashMap<u32, PipeBuf>,
+    max_channels: usize,
+    max_buffered: usize,
+}
+
+impl Default for Mux {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mux {
+    /// Create a multiplexer with no channels open, and the default
+    /// limits
+    pub fn new() -> Self {
+        Self {
+            channels: HashMap::new(),
+            max_channels: MUX_MAX_CHANNELS,
+            max_buffered: MUX_MAX_BUFFERED,
+        }
+    }
+
+    /// Set the maximum number of channels, and the maximum total of
+    /// unread bytes in the pipe-buffers of all the channels.  A
+    /// message from the peer which would open a channel or buffer
+    /// data beyond these limits is refused by [`Mux::handle`] with
+    /// `Error::LimitExceeded`, and opening a channel locally beyond
+    /// the limit fails in the same way.  Channels closed by the peer
+    /// count until they are discarded with [`Mux::remove`].
+    pub fn set_limits(&mut self, max_channels: usize, max_buffered: usize) {
+        self.max_channels = max_channels;
+        self.max_buffered = max_buffered;
+    }
+
+    /// Open a new channel, informing the peer
+    pub fn open(&mut self, ws: &mut WebsocketServer, pb: PBufRdWr, id: u32) -> Result<(), Error> {
+        if self.channels.contains_key(&id) {
+            return Err(Error::WebsocketAlreadyOpen);
+        }
+        if self.channels.len() >= self.max_channels {
+            return Err(Error::LimitExceeded);
+        }
+        send_op(ws, pb, OP_OPEN, id, &[])?;
+        self.channels.insert(id, PipeBuf::new());
+        Ok(())
+    }
+
+    /// Send data on an open channel
+    pub fn send(
+        &mut self,
+        ws: &mut WebsocketServer,
+        pb: PBufRdWr,
+        id: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if !self.channels.contains_key(&id) {
+            return Err(Error::WebSocketNotOpen);
+        }
+        send_op(ws, pb, OP_DATA, id, data)
+    }
+
+    /// Close a channel, informing the peer, and return its
+    /// pipe-buffer, which may still contain unprocessed data
+    pub fn close(
+        &mut self,
+        ws: &mut WebsocketServer,
+        pb: PBufRdWr,
+        id: u32,
+    ) -> Result<PipeBuf, Error> {
+        if !self.channels.contains_key(&id) {
+            return Err(Error::WebSocketNotOpen);
+        }
+        send_op(ws, pb, OP_CLOSE, id, &[])?;
+        Ok(self.channels.remove(&id).expect("Channel exists"))
+    }
+
+    /// Handle a complete binary message received from the peer.
+    /// Returns `Error::InvalidPayload` if the message is not valid for
+    /// this multiplexing protocol, `Error::InvalidChannel` if it
+    /// refers to a channel in the wrong state, or
+    /// `Error::LimitExceeded` if it would exceed the limits set with
+    /// [`Mux::set_limits`].  Nothing is changed if an error is
+    /// returned.
+    pub fn handle(&mut self, message: &[u8]) -> Result<MuxEvent, Error> {
+        if message.len() < 5 {
+            return Err(Error::InvalidPayload);
+        }
+        let id = u32::from_be_bytes([message[1], message[2], message[3], message[4]]);
+        let payload = &message[5..];
+        match message[0] {
+            OP_OPEN if !self.channels.contains_key(&id) && payload.is_empty() => {
+                if self.channels.len() >= self.max_channels {
+                    return Err(Error::LimitExceeded);
+                }
+                self.channels.insert(id, PipeBuf::new());
+                Ok(MuxEvent::Opened(id))
+            }
+            OP_DATA if self.buffered() + payload.len() > self.max_buffered => {
+                Err(Error::LimitExceeded)
+            }
+            OP_DATA => match self.channels.get_mut(&id) {
+                Some(chan) if is_open(chan) => {
+                    let mut wr = chan.wr();
+                    wr.append(payload);
+                    wr.push();
+                    Ok(MuxEvent::Data(id))
+                }
+                _ => Err(Error::InvalidChannel),
+            },
+            OP_CLOSE => match self.channels.get_mut(&id) {
+                Some(chan) if is_open(chan) => {
+                    chan.wr().close();
+                    Ok(MuxEvent::Closed(id))
+                }
+                _ => Err(Error::InvalidChannel),
+            },
+            _ => Err(Error::InvalidPayload),
+        }
+    }
+
+    /// Get the pipe-buffer for a channel, to read incoming data
+    pub fn channel(&mut self, id: u32) -> Option<&mut PipeBuf> {
+        self.channels.get_mut(&id)
+    }
+
+    /// Discard a channel which has been closed by the peer, returning
+    /// its pipe-buffer
+    pub fn remove(&mut self, id: u32) -> Option<PipeBuf> {
+        self.channels.remove(&id)
+    }
+
+    /// Get the total of unread bytes in the pipe-buffers of all the
+    /// channels
+    pub fn buffered(&mut self) -> usize {
+        self.channels.values_mut().map(|chan| chan.rd().len()).sum()
+    }
+
+    /// Iterate over the IDs of the channels currently known
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.channels.keys().copied()
+    }
+}
+
+fn send_op(
+    ws: &mut WebsocketServer,
+    pb: PBufRdWr,
+    op: u8,
+    id: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    let mut msg = Vec::with_capacity(5 + data.len());
+    msg.push(op);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(data);
+    ws.send_binary(pb, &msg)
+}
+
+fn is_open(chan: &PipeBuf) -> bool {
+    matches!(chan.state(), PBufState::Open | PBufState::Push)
+}
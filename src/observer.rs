@@ -0,0 +1,40 @@
+use crate::Error;
+
+/// Observer of the lifecycle of a connection
+///
+/// See [`WebsocketServer::set_observer`] and
+/// [`ServerHandshake::set_observer`].  This allows logging or
+/// auditing to be attached to a connection in one place, rather than
+/// being sprinkled through the application wherever it calls into
+/// the connection.  All the methods do nothing by default.
+///
+/// [`WebsocketServer::set_observer`]: crate::WebsocketServer::set_observer
+/// [`ServerHandshake::set_observer`]: crate::ServerHandshake::set_observer
+pub trait WsObserver: Send {
+    /// The upgrade was accepted, with the given subprotocol.  This
+    /// is only called when the observer was set on a
+    /// `ServerHandshake`.
+    fn handshake_complete(&mut self, _subprotocol: Option<&str>) {}
+
+    /// The header of the first frame of an incoming data message was
+    /// received.  This is called before any of the message data is
+    /// committed to the `message` buffer, so that the caller may
+    /// allocate space or decide where to route the message.  `len`
+    /// is the declared length of the message if it is unfragmented,
+    /// or `None` if more fragments will follow.
+    fn message_start(&mut self, _is_text: bool, _len: Option<u64>) {}
+
+    /// An incoming data message of `len` bytes is complete
+    fn message_end(&mut self, _is_text: bool, _len: u64) {}
+
+    /// A complete incoming control frame was received, i.e. `Close`
+    /// (8), `Ping` (9) or `Pong` (10).  This is called before any
+    /// reply is sent.
+    fn control_frame(&mut self, _opcode: u8, _payload: &[u8]) {}
+
+    /// Receiving failed with the given error
+    fn error(&mut self, _err: &Error) {}
+
+    /// The close handshake was started, either by the peer or by us
+    fn closed(&mut self, _by_peer: bool, _code: Option<u16>, _reason: &str) {}
+}
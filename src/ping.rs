@@ -0,0 +1,46 @@
+/// Statistics from sequence-numbered pings
+///
+/// See [`WebsocketServer::send_ping_seq`].  Comparing the sequence
+/// numbers echoed in the `Pong` replies against those sent shows how
+/// many pings went unanswered, which is a measure of link quality
+/// over flaky transports.
+///
+/// [`WebsocketServer::send_ping_seq`]: crate::WebsocketServer::send_ping_seq
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PingStats {
+    /// Sequence-numbered pings sent
+    pub sent: u64,
+    /// `Pong` replies received echoing a new sequence number
+    pub answered: u64,
+    /// Sequence numbers skipped over by the replies, i.e. pings which
+    /// were lost or never answered.  Pings still awaiting a reply are
+    /// not counted.
+    pub missed: u64,
+    /// `Pong` replies echoing a sequence number which had already
+    /// been answered or skipped over, i.e. duplicated or reordered
+    /// replies
+    pub duplicates: u64,
+}
+
+impl PingStats {
+    /// Update the statistics for an incoming `Pong`.  `next` is the
+    /// sequence number of the next reply expected.  Replies which
+    /// don't carry one of our sequence numbers are ignored.
+    pub(crate) fn pong(&mut self, next: &mut u64, payload: &[u8]) {
+        let Ok(bytes) = <[u8; 8]>::try_from(payload) else {
+            return;
+        };
+        let seq = u64::from_be_bytes(bytes);
+        if seq >= self.sent {
+            return;
+        }
+        if seq < *next {
+            self.duplicates += 1;
+        } else {
+            self.missed += seq - *next;
+            self.answered += 1;
+            *next = seq + 1;
+        }
+    }
+}
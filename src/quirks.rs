@@ -0,0 +1,43 @@
+/// Tolerance of known faults in real-world peers
+///
+/// Each field allows something which RFC 6455 forbids, but which some
+/// deployed clients are known to do.  Gathering these together makes
+/// the workarounds in use on a connection explicit, and allows them
+/// to be compared or logged as a whole.  By default none of them are
+/// tolerated.
+///
+/// Things which the RFCs allow, but which a careless implementation
+/// might not, are always accepted and don't need a quirk: header
+/// names and the `websocket` token of `Upgrade` in any case, `Pong`s
+/// which don't answer a `Ping`, and `Close` frames with no payload.
+///
+/// See [`WebsocketServer::set_quirks`] and
+/// [`ServerHandshake::set_quirks`].
+///
+/// [`WebsocketServer::set_quirks`]: crate::WebsocketServer::set_quirks
+/// [`ServerHandshake::set_quirks`]: crate::ServerHandshake::set_quirks
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quirks {
+    /// Accept a `Sec-WebSocket-Key` which is not the base64 encoding
+    /// of 16 bytes, see [`ServerHandshake::set_lenient_key`].
+    /// Handshake only.
+    ///
+    /// [`ServerHandshake::set_lenient_key`]: crate::ServerHandshake::set_lenient_key
+    pub lenient_key: bool,
+    /// Accept unmasked frames from the client, see
+    /// [`WebsocketServer::set_accept_unmasked`]
+    ///
+    /// [`WebsocketServer::set_accept_unmasked`]: crate::WebsocketServer::set_accept_unmasked
+    pub unmasked: bool,
+    /// Accept a `Ping`, `Pong` or `Close` with the FIN bit clear,
+    /// treating it as complete.  Control frames may not be
+    /// fragmented, but some clients fail to set FIN on them.
+    /// Otherwise it is handled the same way, but counted as a
+    /// `Violation::FragmentedControl`, so that it closes the
+    /// connection if the limit set with
+    /// [`WebsocketServer::set_violation_tolerance`] is exceeded.
+    ///
+    /// [`WebsocketServer::set_violation_tolerance`]: crate::WebsocketServer::set_violation_tolerance
+    pub fragmented_control: bool,
+}
@@ -0,0 +1,139 @@
+use embedded_websocket::WebSocketCloseStatusCode;
+use std::time::Duration;
+
+/// Limit on the rate of incoming messages and data
+///
+/// See [`WebsocketServer::set_rate_limit`].  This works as a token
+/// bucket, so short bursts up to the full allowance for a window are
+/// permitted, but over a longer period the rate is limited to the
+/// given number of messages or bytes per window.
+///
+/// [`WebsocketServer::set_rate_limit`]: crate::WebsocketServer::set_rate_limit
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RateLimit {
+    /// Maximum number of incoming messages per window, or 0 for no
+    /// limit
+    pub messages: u64,
+    /// Maximum number of bytes consumed from the input stream per
+    /// window, or 0 for no limit
+    pub bytes: u64,
+    /// Length of the window
+    pub window: Duration,
+    /// What to do when the limit is reached
+    pub action: RateLimitAction,
+}
+
+/// Action to take when a [`RateLimit`] is reached
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RateLimitAction {
+    /// Stop consuming input until more allowance is available.  This
+    /// applies backpressure to the client.
+    Pause,
+    /// Close the connection with code 1008 (policy violation).  The
+    /// data of the message in progress is discarded, and if some of
+    /// it has already been received, the `message` buffer is aborted.
+    Close,
+}
+
+/// Limit on the total incoming messages and data over the lifetime
+/// of a connection
+///
+/// See [`WebsocketServer::set_budget`].  This allows a public
+/// endpoint to bound the resources any one connection may consume,
+/// whatever its rate.
+///
+/// [`WebsocketServer::set_budget`]: crate::WebsocketServer::set_budget
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Budget {
+    /// Maximum number of incoming data messages, or 0 for no limit
+    pub messages: u64,
+    /// Maximum number of bytes consumed from the input stream, or 0
+    /// for no limit
+    pub bytes: u64,
+    /// Code to close the connection with when the budget is used up,
+    /// typically `PolicyViolation` (1008) or `EndpointUnavailable`
+    /// (1001, going away)
+    pub code: WebSocketCloseStatusCode,
+}
+
+pub(crate) struct RateLimiter {
+    pub limit: RateLimit,
+    // The `Close` action has been taken, after which the limit is
+    // no longer enforced, but input is still charged against it
+    pub tripped: bool,
+    // Times are as given by the connection's `WsClock`
+    now: Duration,
+    // "Theoretical arrival time" for each bucket, i.e. the time at
+    // which the bucket would be empty again
+    msg_tat: Duration,
+    byte_tat: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, now: Duration) -> Self {
+        Self {
+            limit,
+            tripped: false,
+            now,
+            msg_tat: now,
+            byte_tat: now,
+        }
+    }
+
+    pub fn set_time(&mut self, now: Duration) {
+        self.now = self.now.max(now);
+    }
+
+    /// Test whether another message may be started
+    pub fn message_allowed(&self) -> bool {
+        allowance(
+            self.msg_tat,
+            self.now,
+            self.limit.window,
+            self.limit.messages,
+        ) > 0
+    }
+
+    /// Get the number of bytes that may be consumed right now
+    pub fn bytes_allowed(&self) -> u64 {
+        allowance(self.byte_tat, self.now, self.limit.window, self.limit.bytes)
+    }
+
+    pub fn charge_message(&mut self) {
+        charge(
+            &mut self.msg_tat,
+            self.now,
+            self.limit.window,
+            self.limit.messages,
+            1,
+        );
+    }
+
+    pub fn charge_bytes(&mut self, count: u64) {
+        charge(
+            &mut self.byte_tat,
+            self.now,
+            self.limit.window,
+            self.limit.bytes,
+            count,
+        );
+    }
+}
+
+fn allowance(tat: Duration, now: Duration, window: Duration, limit: u64) -> u64 {
+    if limit == 0 {
+        return u64::MAX;
+    }
+    let free = window.saturating_sub(tat.saturating_sub(now));
+    let allowed = free.as_nanos() * limit as u128 / window.as_nanos().max(1);
+    allowed.min(u64::MAX as u128) as u64
+}
+
+fn charge(tat: &mut Duration, now: Duration, window: Duration, limit: u64, count: u64) {
+    if limit != 0 {
+        let cost = window.as_nanos() * count as u128 / limit as u128;
+        *tat = (*tat).max(now) + Duration::from_nanos(cost.min(u64::MAX as u128) as u64);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{Broadcaster, Error, WebsocketServer, WsConnection, WsConnections};
+use embedded_websocket as ws;
+use pipebuf::PBufRdWr;
+use std::hash::Hash;
+use ws::WebSocketSendMessageType;
+
+/// Sending half of a websocket connection as a sink of messages
+///
+/// This allows generic code to send messages without knowing what it
+/// is sending to.  [`WsSink::start_send`] writes a message to the
+/// outgoing stream, but doesn't indicate a push, so several messages
+/// may be queued up and then go out together when
+/// [`WsSink::flush`] is called.  There is no need to wait for the
+/// sink to become ready, because pipe-buffers grow as required.
+/// Use [`WebsocketServer::set_watermarks`] to detect peers which
+/// are not keeping up.
+///
+/// This is implemented for a [`WebsocketServer`] together with its
+/// outgoing stream as [`WsServerSink`], for [`WsConnection`], and for
+/// [`WsConnections`], where each message is broadcast to all the
+/// connections.
+pub trait WsSink {
+    /// Write a message to the outgoing stream.  For an unfragmented
+    /// message, `eom` should be `true`.  For a fragmented message,
+    /// it should be `true` only for the final fragment.
+    fn start_send(
+        &mut self,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error>;
+
+    /// Indicate a push on the outgoing stream, so that the transport
+    /// sends the queued messages without delay
+    fn flush(&mut self);
+
+    /// Send an unfragmented text message, and flush
+    fn send_text(&mut self, data: &str) -> Result<(), Error> {
+        self.start_send(WebSocketSendMessageType::Text, true, data.as_bytes())?;
+        self.flush();
+        Ok(())
+    }
+
+    /// Send an unfragmented binary message, and flush
+    fn send_binary(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.start_send(WebSocketSendMessageType::Binary, true, data)?;
+        self.flush();
+        Ok(())
+    }
+}
+
+/// [`WsSink`] for a [`WebsocketServer`] and its outgoing stream
+///
+/// Obtained from [`WebsocketServer::sink`].
+pub struct WsServerSink<'a> {
+    ws: &'a mut WebsocketServer,
+    pb: PBufRdWr<'a>,
+}
+
+impl WebsocketServer {
+    /// Get a [`WsSink`] which sends on the given stream
+    pub fn sink<'a>(&'a mut self, pb: PBufRdWr<'a>) -> WsServerSink<'a> {
+        WsServerSink { ws: self, pb }
+    }
+}
+
+impl WsSink for WsServerSink<'_> {
+    fn start_send(
+        &mut self,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.ws.write_message(self.pb.wr.reborrow(), msg, eom, data)
+    }
+
+    fn flush(&mut self) {
+        if !self.pb.wr.is_eof() {
+            self.pb.wr.push();
+        }
+    }
+}
+
+impl WsSink for WsConnection {
+    fn start_send(
+        &mut self,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.ws
+            .write_message(self.transport.upper().wr, msg, eom, data)
+    }
+
+    fn flush(&mut self) {
+        let mut wr = self.transport.upper().wr;
+        if !wr.is_eof() {
+            wr.push();
+        }
+    }
+}
+
+/// Each message is encoded once with [`Broadcaster`] and written to
+/// every connection which can still be sent to.  Connections which
+/// are closing are skipped.  Fragmented messages can't be broadcast,
+/// so `eom` must be `true`, otherwise `Error::FragmentUnsupported` is
+/// returned.  Only text, binary and `Ping` messages may be sent, see
+/// [`Broadcaster::new`].
+impl<K: Eq + Hash> WsSink for WsConnections<K> {
+    fn start_send(
+        &mut self,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if !eom {
+            return Err(Error::FragmentUnsupported);
+        }
+        let msg = Broadcaster::new(msg, data)?;
+        for (_, conn) in self.iter_mut() {
+            let _ = msg.write(&mut conn.ws, conn.transport.upper().wr);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        for (_, conn) in self.iter_mut() {
+            conn.flush();
+        }
+    }
+}
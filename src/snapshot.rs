@@ -0,0 +1,232 @@
+use crate::close::{close_code, close_status};
+use crate::events::EventLog;
+use crate::ratelimit::RateLimiter;
+use crate::{
+    Budget, CloseInfo, Error, MessagePolicy, PartialMessage, PingStats, Quirks, RateLimit,
+    WebsocketServer, WsClock, MAX_PINGS_OUT,
+};
+use embedded_websocket as ws;
+use serde::{Deserialize, Serialize};
+use ws::{WebSocketServer, WebSocketState, WebSocketSubProtocol};
+
+/// Largest event log capacity accepted by
+/// [`WebsocketServer::from_snapshot`]
+const MAX_EVENT_LOG: usize = 4096;
+
+/// Snapshot of the protocol state of a [`WebsocketServer`]
+///
+/// This allows a live connection to be handed over to another
+/// process, for example to restart a server without dropping
+/// connections.  The transport itself must be passed across
+/// separately, along with any unconsumed data in the pipe-buffers
+/// and any partial message already delivered to the caller's message
+/// buffer.
+///
+/// All the settings made with the `set_*` calls are carried across,
+/// along with the counters and ping statistics, except for those
+/// which hold caller code or objects: the frame hook, metrics, clock
+/// and observer must be set again on the restored connection.  If a
+/// `Close` had been sent, the close deadline is measured afresh from
+/// the restore.  The
+/// event log keeps its capacity but starts empty, and the restored
+/// rate limit starts with its full allowance.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WsSnapshot {
+    state: u8,
+    max_msg_len: usize,
+    max_aux_len: usize,
+    tx_fragmented: bool,
+    tx_msg_len: u64,
+    rx_fragment: Option<bool>,
+    rx_message: Option<(bool, u64)>,
+    rx_discard: bool,
+    rx_offset: u64,
+    rx_frames: u64,
+    rx_messages: u64,
+    rx_eof: bool,
+    tx_bytes: u64,
+    tx_frames: u64,
+    output_ended: bool,
+    last_close: Option<(bool, Option<u16>, String, bool)>,
+    subprotocol: Option<String>,
+    policy: MessagePolicy,
+    rate_limit: Option<RateLimit>,
+    rate_limit_tripped: bool,
+    budget: Option<(u64, u64, u16)>,
+    violations: u32,
+    violation_tolerance: Option<u32>,
+    quirks: Quirks,
+    truncate_close_reason: bool,
+    max_frame_len: Option<usize>,
+    push_fragments: bool,
+    push_replies: bool,
+    read_quota: Option<usize>,
+    watermarks: Option<(usize, usize)>,
+    backlogged: bool,
+    drain_on_close: bool,
+    close_on_input_eof: bool,
+    event_log: usize,
+    ping_stats: PingStats,
+    pong_next: u64,
+    pings_out: Vec<Vec<u8>>,
+    unmatched_pongs: u64,
+    rx_crc: Option<u32>,
+    message_crc: Option<u32>,
+}
+
+impl WebsocketServer {
+    /// Take a snapshot of the connection state.  Returns `None` if
+    /// the connection is part-way through an incoming frame, in which
+    /// case more data must be processed with
+    /// [`WebsocketServer::receive`] before trying again, or if the
+    /// connection has failed.
+    pub fn snapshot(&self) -> Option<WsSnapshot> {
+        if self.rx_remaining != 0 || self.failure.is_some() {
+            return None;
+        }
+        Some(WsSnapshot {
+            state: self.ws.state as u8,
+            max_msg_len: self.max_msg_len,
+            max_aux_len: self.max_aux_len,
+            tx_fragmented: self.tx_fragmented,
+            tx_msg_len: self.tx_msg_len,
+            rx_fragment: self.rx_fragment,
+            rx_message: self.rx_message.map(|m| (m.is_text, m.len)),
+            rx_discard: self.rx_discard,
+            rx_offset: self.rx_offset,
+            rx_frames: self.rx_frames,
+            rx_messages: self.rx_messages,
+            rx_eof: self.rx_eof,
+            tx_bytes: self.tx_bytes,
+            tx_frames: self.tx_frames,
+            output_ended: self.output_ended,
+            last_close: self
+                .last_close
+                .as_ref()
+                .map(|c| (c.by_peer, c.code, c.reason.clone(), c.completed)),
+            subprotocol: self.subprotocol().map(str::to_string),
+            policy: self.policy,
+            rate_limit: self.rate_limit(),
+            rate_limit_tripped: self.rate_limit_tripped(),
+            budget: self
+                .budget
+                .map(|b| (b.messages, b.bytes, close_code(b.code))),
+            violations: self.violations,
+            violation_tolerance: self.violation_tolerance,
+            quirks: self.quirks,
+            truncate_close_reason: self.truncate_close_reason,
+            max_frame_len: self.max_frame_len,
+            push_fragments: self.push_fragments,
+            push_replies: self.push_replies,
+            read_quota: self.read_quota,
+            watermarks: self.watermarks,
+            backlogged: self.backlogged,
+            drain_on_close: self.drain_on_close,
+            close_on_input_eof: self.close_on_input_eof,
+            event_log: self.events.as_ref().map_or(0, EventLog::capacity),
+            ping_stats: self.ping_stats,
+            pong_next: self.pong_next,
+            pings_out: self.pings_out.iter().cloned().collect(),
+            unmatched_pongs: self.unmatched_pongs,
+            rx_crc: self.rx_crc,
+            message_crc: self.message_crc,
+        })
+    }
+
+    /// Recreate a connection from a snapshot taken with
+    /// [`WebsocketServer::snapshot`].  The clock is used to restart
+    /// the rate limit, if one was set, as for
+    /// [`WebsocketServer::set_rate_limit`].
+    ///
+    /// Since a snapshot may have been stored or passed between
+    /// processes, it is checked before use.  Returns
+    /// `Err(Error::InvalidSnapshot)` if it has an unknown connection
+    /// state or a subprotocol too long for `embedded_websocket` to
+    /// hold.
+    /// Settings are restored through the same limits as the `set_*`
+    /// calls apply, the event log capacity is limited to 4096
+    /// events, and only the 16 most recent outstanding pings are
+    /// kept, so that a corrupt snapshot can't stall the connection or
+    /// make it allocate without limit.
+    pub fn from_snapshot(snap: &WsSnapshot, clock: &impl WsClock) -> Result<Self, Error> {
+        let state = match snap.state {
+            0 => WebSocketState::None,
+            1 => WebSocketState::Connecting,
+            2 => WebSocketState::Open,
+            3 => WebSocketState::CloseSent,
+            4 => WebSocketState::CloseReceived,
+            5 => WebSocketState::Closed,
+            6 => WebSocketState::Aborted,
+            _ => return Err(Error::InvalidSnapshot),
+        };
+        let subprotocol = match snap.subprotocol.as_deref() {
+            Some(p) if p.len() > 24 => return Err(Error::InvalidSnapshot),
+            p => p.map(WebSocketSubProtocol::from),
+        };
+        let mut this = Self::from_wss(
+            WebSocketServer::new_server(),
+            snap.max_msg_len,
+            snap.max_aux_len,
+        );
+        if state == WebSocketState::CloseSent {
+            this.close_sent_at = Some(clock.now());
+        }
+        this.tx_fragmented = snap.tx_fragmented;
+        this.tx_msg_len = snap.tx_msg_len;
+        this.rx_fragment = snap.rx_fragment;
+        this.rx_discard = snap.rx_discard;
+        this.rx_message = snap
+            .rx_message
+            .map(|(is_text, len)| PartialMessage { is_text, len });
+        this.rx_offset = snap.rx_offset;
+        this.rx_frames = snap.rx_frames;
+        this.rx_messages = snap.rx_messages;
+        this.rx_eof = snap.rx_eof;
+        this.tx_bytes = snap.tx_bytes;
+        this.tx_frames = snap.tx_frames;
+        this.output_ended = snap.output_ended;
+        this.last_close = snap
+            .last_close
+            .clone()
+            .map(|(by_peer, code, reason, completed)| CloseInfo {
+                by_peer,
+                code,
+                reason,
+                completed,
+            });
+        this.subprotocol = subprotocol;
+        this.policy = snap.policy;
+        this.rate_limit = snap.rate_limit.map(|limit| {
+            let mut rl = RateLimiter::new(limit, clock.now());
+            rl.tripped = snap.rate_limit_tripped;
+            rl
+        });
+        this.budget = snap.budget.map(|(messages, bytes, code)| Budget {
+            messages,
+            bytes,
+            code: close_status(code),
+        });
+        this.violations = snap.violations;
+        this.violation_tolerance = snap.violation_tolerance;
+        this.quirks = snap.quirks;
+        this.truncate_close_reason = snap.truncate_close_reason;
+        this.set_max_frame_len(snap.max_frame_len);
+        this.push_fragments = snap.push_fragments;
+        this.push_replies = snap.push_replies;
+        this.set_read_quota(snap.read_quota);
+        this.set_watermarks(snap.watermarks);
+        this.backlogged = snap.backlogged;
+        this.drain_on_close = snap.drain_on_close;
+        this.close_on_input_eof = snap.close_on_input_eof;
+        this.set_event_log(snap.event_log.min(MAX_EVENT_LOG));
+        this.ping_stats = snap.ping_stats;
+        this.pong_next = snap.pong_next;
+        let skip = snap.pings_out.len().saturating_sub(MAX_PINGS_OUT);
+        this.pings_out = snap.pings_out[skip..].iter().cloned().collect();
+        this.unmatched_pongs = snap.unmatched_pongs;
+        this.rx_crc = snap.rx_crc;
+        this.message_crc = snap.message_crc;
+        this.ws.state = state;
+        Ok(this)
+    }
+}
@@ -0,0 +1,168 @@
+use pipebuf::PBufRdWr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Failure of a SOCKS5 connection attempt
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Socks5Error {
+    /// The proxy sent something which is not valid SOCKS5
+    Protocol,
+    /// The proxy accepted none of the offered authentication methods
+    NoAcceptableMethod,
+    /// The proxy rejected the username and password
+    AuthFailed,
+    /// The target host name is longer than 255 bytes
+    InvalidHost,
+    /// The proxy refused the `CONNECT`, with the given reply code,
+    /// e.g. 5 for connection refused
+    Refused(u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Stage {
+    Start,
+    Method,
+    Auth,
+    Reply,
+    Done,
+}
+
+/// Client side of a SOCKS5 `CONNECT` (RFC 1928)
+///
+/// This allows a client to reach a websocket server through a SOCKS5
+/// proxy, such as Tor or an SSH dynamic forward.  After connecting
+/// the transport to the proxy, call [`Socks5Connect::process`] until
+/// it returns `Ok(true)`.  The stream then leads to the target
+/// server, and the websocket upgrade request may be sent.  Any data
+/// the server sends after the proxy's reply is left unconsumed in
+/// `pb.rd`.
+///
+/// The upgrade request may then be written with
+/// [`write_client_handshake`] and the response read with
+/// [`read_client_handshake`], after which the connection is handled
+/// by a [`WebsocketClient`].
+///
+/// [`write_client_handshake`]: crate::write_client_handshake
+/// [`read_client_handshake`]: crate::read_client_handshake
+/// [`WebsocketClient`]: crate::WebsocketClient
+pub struct Socks5Connect {
+    host: String,
+    port: u16,
+    auth: Option<(String, String)>,
+    stage: Stage,
+}
+
+impl Socks5Connect {
+    /// Prepare to connect to the given target through the proxy.
+    /// `host` may be an IPv4 or IPv6 address, or a host name, which
+    /// the proxy will resolve.  Passing the name to the proxy avoids
+    /// leaking DNS lookups, which matters when using Tor.
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            auth: None,
+            stage: Stage::Start,
+        }
+    }
+
+    /// Offer username and password authentication (RFC 1929) as
+    /// well as no authentication.  Must be called before the first
+    /// call to [`Socks5Connect::process`].
+    pub fn set_auth(&mut self, username: &str, password: &str) {
+        self.auth = Some((username.to_string(), password.to_string()));
+    }
+
+    /// Advance the exchange with the proxy as far as possible.
+    /// Returns `Ok(false)` if more data is required from the proxy,
+    /// `Ok(true)` once the connection to the target has been
+    /// established, or `Err(_)` if the attempt failed, in which case
+    /// the transport should be closed.
+    pub fn process(&mut self, mut pb: PBufRdWr) -> Result<bool, Socks5Error> {
+        loop {
+            match self.stage {
+                Stage::Start => {
+                    if self.auth.is_some() {
+                        pb.wr.append(&[5, 2, 0, 2]);
+                    } else {
+                        pb.wr.append(&[5, 1, 0]);
+                    }
+                    pb.wr.push();
+                    self.stage = Stage::Method;
+                }
+                Stage::Method => {
+                    let [ver, method] = match *pb.rd.data() {
+                        [ver, method, ..] => [ver, method],
+                        _ => return Ok(false),
+                    };
+                    pb.rd.consume(2);
+                    match (ver, method, &self.auth) {
+                        (5, 0, _) => self.send_connect(pb.reborrow())?,
+                        (5, 2, Some((user, pass))) => {
+                            if user.len() > 255 || pass.len() > 255 {
+                                return Err(Socks5Error::AuthFailed);
+                            }
+                            pb.wr.append(&[1, user.len() as u8]);
+                            pb.wr.append(user.as_bytes());
+                            pb.wr.append(&[pass.len() as u8]);
+                            pb.wr.append(pass.as_bytes());
+                            pb.wr.push();
+                            self.stage = Stage::Auth;
+                        }
+                        (5, 0xFF, _) => return Err(Socks5Error::NoAcceptableMethod),
+                        _ => return Err(Socks5Error::Protocol),
+                    }
+                }
+                Stage::Auth => {
+                    let status = match *pb.rd.data() {
+                        [_, status, ..] => status,
+                        _ => return Ok(false),
+                    };
+                    pb.rd.consume(2);
+                    if status != 0 {
+                        return Err(Socks5Error::AuthFailed);
+                    }
+                    self.send_connect(pb.reborrow())?;
+                }
+                Stage::Reply => {
+                    let data = pb.rd.data();
+                    let len = match *data {
+                        [5, _, _, 1, ..] => 10,
+                        [5, _, _, 4, ..] => 22,
+                        [5, _, _, 3, len, ..] => 7 + len as usize,
+                        [] | [5] | [5, _] | [5, _, _] | [5, _, _, 3] => return Ok(false),
+                        _ => return Err(Socks5Error::Protocol),
+                    };
+                    if data[1] != 0 {
+                        return Err(Socks5Error::Refused(data[1]));
+                    }
+                    if data.len() < len {
+                        return Ok(false);
+                    }
+                    pb.rd.consume(len);
+                    self.stage = Stage::Done;
+                }
+                Stage::Done => return Ok(true),
+            }
+        }
+    }
+
+    fn send_connect(&mut self, mut pb: PBufRdWr) -> Result<(), Socks5Error> {
+        pb.wr.append(&[5, 1, 0]);
+        if let Ok(addr) = self.host.parse::<Ipv4Addr>() {
+            pb.wr.append(&[1]);
+            pb.wr.append(&addr.octets());
+        } else if let Ok(addr) = self.host.parse::<Ipv6Addr>() {
+            pb.wr.append(&[4]);
+            pb.wr.append(&addr.octets());
+        } else if self.host.len() <= 255 {
+            pb.wr.append(&[3, self.host.len() as u8]);
+            pb.wr.append(self.host.as_bytes());
+        } else {
+            return Err(Socks5Error::InvalidHost);
+        }
+        pb.wr.append(&self.port.to_be_bytes());
+        pb.wr.push();
+        self.stage = Stage::Reply;
+        Ok(())
+    }
+}
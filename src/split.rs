@@ -0,0 +1,99 @@
+use crate::{Error, WebsocketServer};
+use embedded_websocket::{WebSocketCloseStatusCode, WebSocketSendMessageType};
+use pipebuf::{PBufRd, PBufRdWr, PBufWr};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub(crate) fn split(ws: WebsocketServer) -> (WsSender, WsReceiver) {
+    let ws = Rc::new(RefCell::new(ws));
+    (WsSender { ws: ws.clone() }, WsReceiver { ws })
+}
+
+/// Sending half of a websocket connection
+///
+/// Obtained from [`WebsocketServer::split`].  Since the receiving
+/// half must still be able to reply to `Ping` and `Close` messages,
+/// it writes its replies to a separate "replies" pipe-buffer, which
+/// must be passed on to the transport using
+/// [`WsSender::forward_replies`].  Replies are always written as
+/// whole frames, so this may be done at any point between sends.
+///
+/// The two halves share the one connection state, so they see the
+/// same close handshake, failure and settings.  Once either half has
+/// sent a `Close`, for example when the receiving half closes the
+/// connection because of a protocol violation, further sends fail
+/// with `Error::WebSocketNotOpen`.  The halves are not `Send`, so
+/// must both stay on the same thread.
+pub struct WsSender {
+    ws: Rc<RefCell<WebsocketServer>>,
+}
+
+impl WsSender {
+    /// Send an unfragmented websocket text message
+    pub fn send_text(&mut self, wr: PBufWr, data: &str) -> Result<(), Error> {
+        self.send(wr, WebSocketSendMessageType::Text, true, data.as_bytes())
+    }
+
+    /// Send an unfragmented websocket binary message
+    pub fn send_binary(&mut self, wr: PBufWr, data: &[u8]) -> Result<(), Error> {
+        self.send(wr, WebSocketSendMessageType::Binary, true, data)
+    }
+
+    /// Send an arbitrary websocket message.  See
+    /// [`WebsocketServer::send`].
+    pub fn send(
+        &mut self,
+        wr: PBufWr,
+        msg: WebSocketSendMessageType,
+        eom: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.ws.borrow_mut().send_wr(wr, msg, eom, data)
+    }
+
+    /// Initiate the close handshake by sending a `Close` message.
+    /// See [`WebsocketServer::close`].  The peer's reply arrives
+    /// through the [`WsReceiver`], which then closes its replies
+    /// pipe-buffer, and so `wr` once the replies are forwarded.
+    pub fn close(
+        &mut self,
+        wr: PBufWr,
+        code: WebSocketCloseStatusCode,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        self.ws.borrow_mut().close_wr(wr, code, reason)
+    }
+
+    /// Pass on any replies generated by the [`WsReceiver`] to the
+    /// transport.  If the receiving half has completed the close
+    /// handshake, this also closes `wr`.
+    pub fn forward_replies(&mut self, mut replies: PBufRd, wr: PBufWr) {
+        replies.forward(wr);
+    }
+}
+
+/// Receiving half of a websocket connection
+///
+/// Obtained from [`WebsocketServer::split`].  `Pong` and
+/// `CloseReply` messages are written to the `replies` pipe-buffer
+/// instead of directly to the transport, and that buffer is closed
+/// when the close handshake completes.  See [`WsSender`].
+pub struct WsReceiver {
+    ws: Rc<RefCell<WebsocketServer>>,
+}
+
+impl WsReceiver {
+    /// Process as much data as possible from the stream.  See
+    /// [`WebsocketServer::receive`] for details.  Replies are written
+    /// to `replies` rather than to the transport.
+    pub fn receive(
+        &mut self,
+        rd: PBufRd,
+        replies: PBufWr,
+        message: PBufWr,
+        is_text: &mut bool,
+    ) -> Result<bool, Error> {
+        let pb = PBufRdWr { rd, wr: replies };
+        self.ws.borrow_mut().receive(pb, message, is_text)
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{Error, WebsocketServer};
+use pipebuf::PBufRdWr;
+
+/// Subprotocol name for STOMP 1.2 over websocket
+pub const STOMP_SUBPROTOCOL: &str = "v12.stomp";
+
+/// A STOMP 1.2 frame
+///
+/// Over websocket, each message normally carries one STOMP frame,
+/// but may carry several, along with heart-beat end-of-lines between
+/// them.  Use [`parse_stomp`] to split a complete incoming message
+/// into frames, and [`StompFrame::send`] to send a frame as a
+/// message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StompFrame {
+    /// Command, e.g. `CONNECT`, `SEND` or `MESSAGE`
+    pub command: String,
+    /// Headers in order, unescaped.  Where a header is repeated, the
+    /// first one is the one that counts.
+    pub headers: Vec<(String, String)>,
+    /// Body of the frame, which may be empty
+    pub body: Vec<u8>,
+}
+
+impl StompFrame {
+    /// Create a frame with no headers and an empty body
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Add a header, consuming and returning the frame
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set the body, consuming and returning the frame
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Get the value of a header.  If it was repeated, the first
+    /// value is returned, as required by STOMP 1.2.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Encode the frame, including its terminating NUL.  If the body
+    /// contains a NUL and there is no `content-length` header, one is
+    /// added, since otherwise the peer couldn't find the end of the
+    /// body.
+    pub fn encode(&self) -> Vec<u8> {
+        let escape = self.escapes_headers();
+        let mut out = Vec::with_capacity(self.body.len() + 64);
+        out.extend_from_slice(self.command.as_bytes());
+        out.push(b'\n');
+        for (name, value) in &self.headers {
+            push_header_part(&mut out, name, escape);
+            out.push(b':');
+            push_header_part(&mut out, value, escape);
+            out.push(b'\n');
+        }
+        if self.body.contains(&0) && self.get("content-length").is_none() {
+            out.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.body);
+        out.push(0);
+        out
+    }
+
+    /// Send the frame as a single websocket message.  This is sent
+    /// as a text message if the encoded frame is valid UTF-8,
+    /// otherwise as a binary message.
+    pub fn send(&self, ws: &mut WebsocketServer, pb: PBufRdWr) -> Result<(), Error> {
+        let data = self.encode();
+        match std::str::from_utf8(&data) {
+            Ok(text) => ws.send_text(pb, text),
+            Err(_) => ws.send_binary(pb, &data),
+        }
+    }
+
+    // STOMP 1.2 doesn't escape headers of CONNECT and CONNECTED
+    // frames, for backwards compatibility with STOMP 1.0
+    fn escapes_headers(&self) -> bool {
+        !matches!(self.command.as_str(), "CONNECT" | "CONNECTED")
+    }
+
+    // Parse one frame from the start of `data`, returning the frame
+    // and the number of bytes used, including the terminating NUL
+    fn parse(data: &[u8]) -> Result<(Self, usize), Error> {
+        let mut pos = 0;
+        let command = next_line(data, &mut pos)?;
+        if command.is_empty() {
+            return Err(Error::InvalidPayload);
+        }
+        let mut frame = Self::new(std::str::from_utf8(command)?);
+        let escape = frame.escapes_headers();
+        loop {
+            let line = next_line(data, &mut pos)?;
+            if line.is_empty() {
+                break;
+            }
+            let colon = line
+                .iter()
+                .position(|b| *b == b':')
+                .ok_or(Error::InvalidPayload)?;
+            let name = header_part(&line[..colon], escape)?;
+            let value = header_part(&line[colon + 1..], escape)?;
+            frame.headers.push((name, value));
+        }
+        let body_len = match frame.get("content-length") {
+            Some(len) => len.parse::<usize>().map_err(|_| Error::InvalidPayload)?,
+            None => data[pos..]
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or(Error::InvalidPayload)?,
+        };
+        // `content-length` comes from the peer, so may be anything
+        let end = pos.checked_add(body_len).ok_or(Error::InvalidPayload)?;
+        if data.get(end) != Some(&0) {
+            return Err(Error::InvalidPayload);
+        }
+        frame.body = data[pos..end].to_vec();
+        Ok((frame, end + 1))
+    }
+}
+
+/// Split a complete incoming websocket message into STOMP frames.
+/// Heart-beats, i.e. end-of-lines outside of frames, are skipped, so
+/// a message containing only a heart-beat gives an empty list.
+/// Returns `Error::InvalidPayload` if the message is not valid STOMP
+/// 1.2 framing, or `Error::Utf8Error` if a command or header is not
+/// valid UTF-8.
+pub fn parse_stomp(message: &[u8]) -> Result<Vec<StompFrame>, Error> {
+    let mut frames = Vec::new();
+    let mut rest = message;
+    loop {
+        while let [b'\r' | b'\n', tail @ ..] = rest {
+            rest = tail;
+        }
+        if rest.is_empty() {
+            return Ok(frames);
+        }
+        let (frame, len) = StompFrame::parse(rest)?;
+        frames.push(frame);
+        rest = &rest[len..];
+    }
+}
+
+// Get the next line, stripping the optional CR before the LF
+fn next_line<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8], Error> {
+    let start = *pos;
+    let len = data[start..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or(Error::InvalidPayload)?;
+    *pos = start + len + 1;
+    let line = &data[start..start + len];
+    Ok(line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+fn header_part(data: &[u8], escape: bool) -> Result<String, Error> {
+    let text = std::str::from_utf8(data)?;
+    if !escape {
+        return Ok(text.to_string());
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('r') => '\r',
+            Some('n') => '\n',
+            Some('c') => ':',
+            Some('\\') => '\\',
+            _ => return Err(Error::InvalidPayload),
+        });
+    }
+    Ok(out)
+}
+
+fn push_header_part(out: &mut Vec<u8>, text: &str, escape: bool) {
+    if !escape {
+        out.extend_from_slice(text.as_bytes());
+        return;
+    }
+    for b in text.bytes() {
+        match b {
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b':' => out.extend_from_slice(b"\\c"),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            _ => out.push(b),
+        }
+    }
+}
@@ -0,0 +1,38 @@
+/// Minor conformance violation by the peer
+///
+/// By default these are ignored, for compatibility with sloppy
+/// clients.  See [`WebsocketServer::set_violation_tolerance`] to
+/// count them and close the connection once there are too many.
+///
+/// [`WebsocketServer::set_violation_tolerance`]: crate::WebsocketServer::set_violation_tolerance
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// A frame had RSV bits set, although no extension has been
+    /// negotiated
+    ReservedBits,
+    /// A `Close` frame had a 1-byte payload, which is too short to
+    /// hold a status code.  The reply is always code 1002.
+    ClosePayload,
+    /// A `Close` frame had a status code which may not be sent, e.g.
+    /// 1005, or one outside of the ranges defined by RFC 6455.  The
+    /// reply is always code 1002.
+    CloseCode(u16),
+    /// A control frame had the FIN bit clear.  Only counted if not
+    /// allowed by [`Quirks::fragmented_control`].
+    ///
+    /// [`Quirks::fragmented_control`]: crate::Quirks::fragmented_control
+    FragmentedControl,
+}
+
+/// Check the payload of an incoming `Close` frame, returning the
+/// violation if it is invalid according to RFC 6455
+pub(crate) fn check_close(payload: &[u8]) -> Option<Violation> {
+    match *payload {
+        [] => None,
+        [_] => Some(Violation::ClosePayload),
+        [hi, lo, ..] => match u16::from_be_bytes([hi, lo]) {
+            1000..=1003 | 1007..=1014 | 3000..=4999 => None,
+            code => Some(Violation::CloseCode(code)),
+        },
+    }
+}